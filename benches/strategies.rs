@@ -0,0 +1,106 @@
+//! Compare the different ways of computing a content hash over the same synthetic data.
+//!
+//! Run with `cargo bench`, adding `--features mmap` to include the memory-mapped file reader.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use dropbox_content_hash::{blocks, multibuffer, parallel, ContentHasher, BLOCK_SIZE};
+use std::fs::File;
+use std::path::PathBuf;
+
+const THREADS: &[usize] = &[1, 2, 4, 8];
+
+/// 16 blocks and a bit, so every strategy has some whole blocks and a partial one at the end.
+fn data() -> Vec<u8> {
+    (0 .. 16 * BLOCK_SIZE + 12345).map(|i| (i % 251) as u8).collect()
+}
+
+fn serial(c: &mut Criterion) {
+    let data = data();
+    let mut group = c.benchmark_group("serial");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    group.bench_function("read_stream", |b| {
+        b.iter(|| ContentHasher::from_stream(&data[..]).unwrap().finish())
+    });
+    // Small updates exercise the splitting of input across block boundaries.
+    for &chunk_size in &[1000, 64 * 1024] {
+        group.bench_with_input(BenchmarkId::new("update", chunk_size), &chunk_size, |b, &n| {
+            b.iter(|| {
+                let mut ctx = ContentHasher::new();
+                for chunk in data.chunks(n) {
+                    ctx.update(chunk);
+                }
+                ctx.finish()
+            })
+        });
+    }
+    group.bench_function("multibuffer", |b| {
+        b.iter(|| multibuffer::content_hash_from_stream(&data[..]).unwrap())
+    });
+    group.finish();
+}
+
+/// Hashing a group of blocks together, against one at a time. Which is faster depends on the CPU:
+/// with AVX2 the group wins, unless the CPU also has the SHA extensions, which hash one block at
+/// a time faster still; `multibuffer::is_faster` goes by this.
+fn multibuffer_blocks(c: &mut Criterion) {
+    let data = data();
+    let mut blocks = data.chunks_exact(BLOCK_SIZE);
+    let blocks = [(); multibuffer::LANES].map(|()| blocks.next().unwrap());
+    let mut group = c.benchmark_group("multibuffer_blocks");
+    group.throughput(Throughput::Bytes((multibuffer::LANES * BLOCK_SIZE) as u64));
+    group.sample_size(10);
+    group.bench_function("one_at_a_time", |b| {
+        b.iter(|| blocks.map(blocks::hash_block))
+    });
+    group.bench_function("together", |b| b.iter(|| multibuffer::hash_blocks(blocks)));
+    group.finish();
+}
+
+fn parallel_stream(c: &mut Criterion) {
+    let data = data();
+    let mut group = c.benchmark_group("parallel_stream");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    for &threads in THREADS {
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter(|| parallel::content_hash_from_stream(&data[..], n).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn parallel_file(c: &mut Criterion) {
+    let data = data();
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("bench-data");
+    std::fs::write(&path, &data).unwrap();
+    let file = File::open(&path).unwrap();
+
+    let backends = [
+        ("pread", parallel::FileBackend::Pread),
+        #[cfg(feature = "mmap")]
+        ("mmap", parallel::FileBackend::Mmap),
+        #[cfg(windows)]
+        ("overlapped", parallel::FileBackend::Overlapped { queue_depth: 8 }),
+    ];
+
+    let mut group = c.benchmark_group("parallel_file");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+    for (name, backend) in backends {
+        for &threads in THREADS {
+            group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &n| {
+                b.iter(|| {
+                    parallel::content_hash_from_file_with_backend(&file, n, backend).unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+
+    drop(file);
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, serial, multibuffer_blocks, parallel_stream, parallel_file);
+criterion_main!(benches);
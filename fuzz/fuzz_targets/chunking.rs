@@ -0,0 +1,86 @@
+//! Feed random data through `ContentHasher` in random pieces, with a random block size, and check
+//! that however it's split up, the result is the same as hashing each block with `sha2`.
+
+#![no_main]
+
+use dropbox_content_hash::{ContentHasher, BLOCK_SIZE};
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    block_size: u8,
+    data: Vec<u8>,
+    /// The sizes of the pieces to give to `update`, and of the reads `read_stream` gets, with
+    /// whatever's left over as the last piece.
+    pieces: Vec<u8>,
+}
+
+/// The content hash, done the simplest way.
+fn reference(data: &[u8], block_size: usize) -> [u8; 32] {
+    let mut overall = Sha256::new();
+    for block in data.chunks(block_size) {
+        overall.update(Sha256::digest(block));
+    }
+    overall.finalize().into()
+}
+
+/// Splits `data` into pieces of the given sizes, then the rest; a size of 0 gives an empty piece.
+fn pieces<'a>(mut data: &'a [u8], sizes: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let mut sizes = sizes.iter();
+    std::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        let n = sizes.next().map_or(data.len(), |&n| usize::from(n).min(data.len()));
+        let (piece, rest) = data.split_at(n);
+        data = rest;
+        Some(piece)
+    })
+}
+
+/// A reader which returns the pieces one at a time, so `read_stream` gets short reads.
+struct ShortReads<'a, I> {
+    pieces: I,
+    /// What's left of a piece too big for the last read.
+    rest: &'a [u8],
+}
+
+impl<'a, I: Iterator<Item = &'a [u8]>> Read for ShortReads<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rest.is_empty() {
+            match self.pieces.next() {
+                // An empty piece would look like the end of the stream, so it's an interruption
+                // here.
+                Some([]) => return Err(io::ErrorKind::Interrupted.into()),
+                Some(piece) => self.rest = piece,
+                None => return Ok(0),
+            }
+        }
+        let n = self.rest.len().min(buf.len());
+        buf[.. n].copy_from_slice(&self.rest[.. n]);
+        self.rest = &self.rest[n ..];
+        Ok(n)
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let block_size = usize::from(input.block_size.max(1));
+    let expected = reference(&input.data, block_size);
+
+    let mut ctx = ContentHasher::builder().block_size(block_size).build();
+    for piece in pieces(&input.data, &input.pieces) {
+        ctx.update(piece);
+    }
+    assert_eq!(expected, ctx.finish(), "update");
+
+    let mut ctx = ContentHasher::builder().block_size(block_size).build();
+    let reader = ShortReads { pieces: pieces(&input.data, &input.pieces), rest: &[] };
+    ctx.read_stream_with_buffer(reader, &mut [0; 100]).unwrap();
+    assert_eq!(expected, ctx.finish(), "read_stream");
+
+    let ctx = ContentHasher::from_stream(&input.data[..]).unwrap();
+    assert_eq!(reference(&input.data, BLOCK_SIZE), ctx.finish(), "from_stream");
+});
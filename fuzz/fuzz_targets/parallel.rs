@@ -0,0 +1,57 @@
+//! Feed random data, a few blocks long, through the serial and parallel hashers in random pieces,
+//! and check that they all agree.
+
+#![no_main]
+
+use dropbox_content_hash::{parallel, ContentHasher, BLOCK_SIZE};
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use libfuzzer_sys::fuzz_target;
+
+/// Inputs much over a few blocks would only make each run slower.
+const MAX_LEN: usize = 3 * BLOCK_SIZE + 100;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    /// The data, as runs of a byte repeated, so short inputs can make data spanning blocks.
+    runs: Vec<(u8, u32)>,
+    /// The sizes of the pieces to give to `update`, with whatever's left over as the last piece.
+    pieces: Vec<u32>,
+    threads: u8,
+    queue_depth: u8,
+}
+
+fuzz_target!(|input: Input| {
+    let mut data = vec![];
+    for &(byte, len) in &input.runs {
+        let len = (len as usize % BLOCK_SIZE).min(MAX_LEN - data.len());
+        data.resize(data.len() + len, byte);
+    }
+    let mut sizes = input.pieces.iter().map(|&n| n as usize % (2 * BLOCK_SIZE));
+    let mut pieces = vec![];
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let n = sizes.next().unwrap_or(rest.len()).min(rest.len());
+        let (piece, after) = rest.split_at(n);
+        pieces.push(piece);
+        rest = after;
+    }
+    let options = parallel::Options::new(usize::from(input.threads % 4 + 1))
+        .queue_depth(usize::from(input.queue_depth % 8 + 1));
+
+    let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+
+    let mut ctx = ContentHasher::new();
+    for piece in &pieces {
+        ctx.update(piece);
+    }
+    assert_eq!(expected, ctx.finish(), "update");
+
+    let hash = parallel::content_hash_from_stream_with_options(&data[..], &options).unwrap();
+    assert_eq!(expected, hash, "parallel::content_hash_from_stream");
+
+    let mut ctx = parallel::ParallelContentHasher::with_options(&options);
+    for piece in &pieces {
+        ctx.update(piece);
+    }
+    assert_eq!(expected, ctx.finish().unwrap(), "ParallelContentHasher");
+});
@@ -0,0 +1,177 @@
+//! Lists of block hashes, which can be kept around to recompute a file's content hash after parts
+//! of it change, without reading the whole file again.
+
+use crate::{block_index, block_range, num_blocks, BLOCK_SIZE, HASH_OUTPUT_SIZE};
+use crate::read::read_full;
+use crate::sha256::{digest, Context, SHA256};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// The hash of a single block.
+pub type BlockHash = [u8; HASH_OUTPUT_SIZE];
+
+/// The hashes of every block of a file, and the file's length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHashList {
+    len: u64,
+    hashes: Vec<BlockHash>,
+}
+
+impl BlockHashList {
+    /// Make a list from the hashes of every block of a file of the given length, or return `None`
+    /// if the number of hashes is wrong for that length.
+    pub fn from_hashes(len: u64, hashes: Vec<BlockHash>) -> Option<Self> {
+        if hashes.len() as u64 == num_blocks(len) {
+            Some(Self { len, hashes })
+        } else {
+            None
+        }
+    }
+
+    /// Read and hash every block of the given stream.
+    pub fn from_stream(mut source: impl Read) -> io::Result<Self> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut list = Self { len: 0, hashes: vec![] };
+        loop {
+            let n = read_full(&mut source, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            list.hashes.push(hash_block(&buf[.. n]));
+            list.len += n as u64;
+            if n < BLOCK_SIZE {
+                break;
+            }
+        }
+        Ok(list)
+    }
+
+    /// The length of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The hash of each block, in order.
+    pub fn hashes(&self) -> &[BlockHash] {
+        &self.hashes
+    }
+
+    /// Compute the content hash of the file.
+    pub fn content_hash(&self) -> [u8; HASH_OUTPUT_SIZE] {
+        let mut ctx = Context::new(&SHA256);
+        for hash in &self.hashes {
+            ctx.update(hash);
+        }
+        let mut out = [0u8; HASH_OUTPUT_SIZE];
+        out.copy_from_slice(ctx.finish().as_ref());
+        out
+    }
+
+    /// Bring the list up to date with a modified file, re-reading only the blocks which overlap
+    /// the given ranges of changed bytes.
+    ///
+    /// `new_len` is the file's length now. If it's different from before, the blocks from the
+    /// shorter of the two lengths to the end of the file are re-read as well, so appended data
+    /// doesn't need to be listed in `changed`.
+    ///
+    /// If this returns an error, the list is left in an unspecified state, and should be rebuilt
+    /// from scratch.
+    pub fn update(
+        &mut self,
+        mut source: impl Read + Seek,
+        new_len: u64,
+        changed: &[Range<u64>],
+    ) -> io::Result<()> {
+        let num = num_blocks(new_len);
+        let mut dirty = vec![false; num as usize];
+        for range in changed {
+            let end = range.end.min(new_len);
+            if range.start < end {
+                for block in block_index(range.start) ..= block_index(end - 1) {
+                    dirty[block as usize] = true;
+                }
+            }
+        }
+        if new_len != self.len {
+            for block in block_index(self.len.min(new_len)) .. num {
+                dirty[block as usize] = true;
+            }
+        }
+
+        self.hashes.resize(num as usize, [0; HASH_OUTPUT_SIZE]);
+        self.len = new_len;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        for (block, _) in dirty.iter().enumerate().filter(|(_, dirty)| **dirty) {
+            let range = block_range(block as u64);
+            let len = (range.end.min(new_len) - range.start) as usize;
+            source.seek(SeekFrom::Start(range.start))?;
+            source.read_exact(&mut buf[.. len])?;
+            self.hashes[block] = hash_block(&buf[.. len]);
+        }
+        Ok(())
+    }
+}
+
+/// The SHA-256 hash of one block, as it goes into a content hash.
+pub fn hash_block(block: &[u8]) -> BlockHash {
+    let mut out = [0u8; HASH_OUTPUT_SIZE];
+    out.copy_from_slice(digest(&SHA256, block).as_ref());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentHasher;
+    use std::io::Cursor;
+
+    fn expected(data: &[u8]) -> [u8; HASH_OUTPUT_SIZE] {
+        ContentHasher::from_stream(data).unwrap().finish()
+    }
+
+    #[test]
+    fn from_stream() {
+        for &len in &[0, 5, BLOCK_SIZE, 2 * BLOCK_SIZE + 1] {
+            let data = (0 .. len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let list = BlockHashList::from_stream(&data[..]).unwrap();
+            assert_eq!(len as u64, list.len());
+            assert_eq!(num_blocks(len as u64) as usize, list.hashes().len());
+            assert_eq!(expected(&data), list.content_hash(), "len={}", len);
+            assert_eq!(Some(&list),
+                BlockHashList::from_hashes(list.len(), list.hashes().to_vec()).as_ref());
+        }
+        assert_eq!(None, BlockHashList::from_hashes(1, vec![]));
+    }
+
+    #[test]
+    fn update() {
+        let mut data = (0 .. 3 * BLOCK_SIZE + 10).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut list = BlockHashList::from_stream(&data[..]).unwrap();
+
+        // Change bytes spanning the first two blocks, and one in the last block.
+        data[BLOCK_SIZE - 1] ^= 1;
+        data[BLOCK_SIZE] ^= 1;
+        data[3 * BLOCK_SIZE + 5] ^= 1;
+        let b = BLOCK_SIZE as u64;
+        let changed = [b - 1 .. b + 1, 3 * b + 5 .. 3 * b + 6];
+        list.update(Cursor::new(&data), data.len() as u64, &changed).unwrap();
+        assert_eq!(expected(&data), list.content_hash());
+
+        // Append.
+        data.extend_from_slice(&[7; BLOCK_SIZE]);
+        list.update(Cursor::new(&data), data.len() as u64, &[]).unwrap();
+        assert_eq!(expected(&data), list.content_hash());
+
+        // Truncate to a block boundary, and to the middle of a block.
+        for &len in &[2 * BLOCK_SIZE, BLOCK_SIZE / 2, 0] {
+            data.truncate(len);
+            list.update(Cursor::new(&data), len as u64, &[]).unwrap();
+            assert_eq!(expected(&data), list.content_hash(), "len={}", len);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! A persistent cache of content hashes and block hash lists, stored in an SQLite database, so
+//! files which haven't changed don't need to be hashed again.
+//!
+//! Entries are keyed by the file's canonical path, and are only returned while the file's size
+//! and modification time are the same as when the entry was added.
+
+use crate::blocks::{BlockHash, BlockHashList};
+use crate::file::{self, content_hash_file};
+use crate::{parallel, HASH_OUTPUT_SIZE};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// An error from the cache.
+#[derive(Debug)]
+pub enum Error {
+    /// The database couldn't be read or written.
+    Db(rusqlite::Error),
+
+    /// The file couldn't be looked up.
+    Io(io::Error),
+
+    /// The file couldn't be hashed.
+    Hash(parallel::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Db(e) => write!(f, "hash cache database error: {}", e),
+            Error::Io(e) => e.fmt(f),
+            Error::Hash(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Db(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Hash(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Db(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<parallel::Error> for Error {
+    fn from(e: parallel::Error) -> Error {
+        Error::Hash(e)
+    }
+}
+
+/// What the cache knows about a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The file's content hash.
+    pub content_hash: [u8; HASH_OUTPUT_SIZE],
+
+    /// The hashes of the file's blocks, if they were stored.
+    pub blocks: Option<BlockHashList>,
+}
+
+/// A cache of content hashes.
+pub struct Cache {
+    db: Connection,
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("path", &self.db.path())
+            .finish()
+    }
+}
+
+impl Cache {
+    /// Open the cache stored in the given database file, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Make a cache which is only kept in memory.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> Result<Self, Error> {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                path BLOB PRIMARY KEY NOT NULL,
+                size INTEGER NOT NULL,
+                mtime_secs INTEGER NOT NULL,
+                mtime_nanos INTEGER NOT NULL,
+                content_hash BLOB NOT NULL,
+                blocks BLOB
+            )")?;
+        Ok(Self { db })
+    }
+
+    /// Look up a file, returning its entry if there is one and the file hasn't changed since.
+    pub fn lookup(&self, path: impl AsRef<Path>) -> Result<Option<Entry>, Error> {
+        let path = fs::canonicalize(path)?;
+        let key = Key::new(&path, &path.metadata()?)?;
+        let row = self.db
+            .query_row(
+                "SELECT content_hash, blocks FROM files
+                    WHERE path = ?1 AND size = ?2 AND mtime_secs = ?3 AND mtime_nanos = ?4",
+                params![key.path, key.size, key.mtime_secs, key.mtime_nanos],
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?)))
+            .optional()?;
+        let (content_hash, blocks) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        // Treat malformed rows as missing; they'll be replaced on the next insert.
+        let content_hash = match content_hash.try_into() {
+            Ok(hash) => hash,
+            Err(_) => return Ok(None),
+        };
+        let blocks = match blocks {
+            Some(blocks) => match decode_blocks(key.size as u64, &blocks) {
+                Some(list) => Some(list),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        Ok(Some(Entry { content_hash, blocks }))
+    }
+
+    /// Add or replace the entry for a file.
+    ///
+    /// The metadata should be read before hashing the file, so that if the file is modified while
+    /// it's being hashed, the entry won't be returned by [`lookup`](Self::lookup).
+    pub fn insert(
+        &self,
+        path: impl AsRef<Path>,
+        meta: &Metadata,
+        entry: &Entry,
+    ) -> Result<(), Error> {
+        let path = fs::canonicalize(path)?;
+        let key = Key::new(&path, meta)?;
+        let blocks = entry.blocks.as_ref().map(|list| list.hashes().concat());
+        self.db.execute(
+            "INSERT OR REPLACE INTO files
+                (path, size, mtime_secs, mtime_nanos, content_hash, blocks)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![key.path, key.size, key.mtime_secs, key.mtime_nanos,
+                &entry.content_hash[..], blocks])?;
+        Ok(())
+    }
+
+    /// Remove the entry for a file, if there is one. The file itself doesn't need to exist any
+    /// more, as long as the directory it was in does.
+    pub fn remove(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let path = match fs::canonicalize(path) {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let parent = match path.parent() {
+                    Some(parent) if parent != Path::new("") => parent,
+                    _ => Path::new("."),
+                };
+                match path.file_name() {
+                    Some(name) => fs::canonicalize(parent)?.join(name),
+                    None => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.db.execute("DELETE FROM files WHERE path = ?1", params![path_key(&path)?])?;
+        Ok(())
+    }
+
+    /// Remove the entries for every file which no longer exists or has changed since it was
+    /// added, returning how many were removed.
+    pub fn prune(&self) -> Result<usize, Error> {
+        let mut stale = vec![];
+        {
+            let mut stmt = self.db.prepare(
+                "SELECT path, size, mtime_secs, mtime_nanos FROM files")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let stored = Key {
+                    path: row.get(0)?,
+                    size: row.get(1)?,
+                    mtime_secs: row.get(2)?,
+                    mtime_nanos: row.get(3)?,
+                };
+                // A path which can't be decoded can't be one of this system's files, so it's as
+                // good as gone.
+                let current = key_path(&stored.path)
+                    .and_then(|path| path.metadata().and_then(|meta| Key::new(&path, &meta)));
+                if current.ok().as_ref() != Some(&stored) {
+                    stale.push(stored.path);
+                }
+            }
+        }
+        for path in &stale {
+            self.db.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+        }
+        Ok(stale.len())
+    }
+
+    /// Get a file's content hash from the cache if it hasn't changed, or else compute it and add
+    /// it to the cache.
+    pub fn content_hash_file(
+        &self,
+        path: impl AsRef<Path>,
+        options: &file::Options,
+    ) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+        let path = path.as_ref();
+        if let Some(entry) = self.lookup(path)? {
+            return Ok(entry.content_hash);
+        }
+        let meta = path.metadata()?;
+        let (content_hash, _stats) = content_hash_file(path, options)?;
+        self.insert(path, &meta, &Entry { content_hash, blocks: None })?;
+        Ok(content_hash)
+    }
+}
+
+/// The columns identifying a version of a file.
+#[derive(Debug, PartialEq, Eq)]
+struct Key {
+    path: Vec<u8>,
+    size: i64,
+    mtime_secs: i64,
+    mtime_nanos: i64,
+}
+
+impl Key {
+    fn new(path: &Path, meta: &Metadata) -> io::Result<Self> {
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Self {
+            path: path_key(path)?,
+            size: meta.len() as i64,
+            mtime_secs: mtime.as_secs() as i64,
+            mtime_nanos: i64::from(mtime.subsec_nanos()),
+        })
+    }
+}
+
+/// Paths are stored as their bytes on Unix, where any bytes make a path.
+#[cfg(unix)]
+fn path_key(path: &Path) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(unix)]
+fn key_path(key: &[u8]) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(key.to_vec())))
+}
+
+/// Paths are stored as UTF-8 elsewhere, so paths which aren't valid Unicode can't be cached.
+#[cfg(not(unix))]
+fn path_key(path: &Path) -> io::Result<Vec<u8>> {
+    match path.to_str() {
+        Some(path) => Ok(path.as_bytes().to_vec()),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{:?} can't be cached because it isn't valid Unicode", path))),
+    }
+}
+
+#[cfg(not(unix))]
+fn key_path(key: &[u8]) -> io::Result<PathBuf> {
+    std::str::from_utf8(key)
+        .map(PathBuf::from)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+            format!("a path in the cache isn't valid UTF-8: {}", e)))
+}
+
+fn decode_blocks(len: u64, bytes: &[u8]) -> Option<BlockHashList> {
+    if !bytes.len().is_multiple_of(HASH_OUTPUT_SIZE) {
+        return None;
+    }
+    let hashes = bytes.chunks(HASH_OUTPUT_SIZE)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect::<Vec<BlockHash>>();
+    BlockHashList::from_hashes(len, hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentHasher;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn lookup_insert_prune() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        let data = vec![9u8; crate::BLOCK_SIZE + 1];
+        fs::write(&path, &data).unwrap();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+
+        let cache = Cache::in_memory().unwrap();
+        assert_eq!(None, cache.lookup(&path).unwrap());
+        assert_eq!(expected, cache.content_hash_file(&path, &file::Options::new()).unwrap());
+        let entry = cache.lookup(&path).unwrap().unwrap();
+        assert_eq!(expected, entry.content_hash);
+        assert_eq!(None, entry.blocks);
+
+        let blocks = BlockHashList::from_stream(&data[..]).unwrap();
+        let entry = Entry { content_hash: expected, blocks: Some(blocks) };
+        cache.insert(&path, &path.metadata().unwrap(), &entry).unwrap();
+        assert_eq!(Some(&entry), cache.lookup(&path).unwrap().as_ref());
+        assert_eq!(0, cache.prune().unwrap());
+
+        // A modified file isn't returned, and gets pruned.
+        let mtime = path.metadata().unwrap().modified().unwrap();
+        fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(mtime + Duration::from_secs(1)).unwrap();
+        assert_eq!(None, cache.lookup(&path).unwrap());
+        assert_eq!(1, cache.prune().unwrap());
+
+        cache.content_hash_file(&path, &file::Options::new()).unwrap();
+        cache.remove(&path).unwrap();
+        assert_eq!(None, cache.lookup(&path).unwrap());
+
+        // Files can be removed from the cache after they're deleted.
+        cache.content_hash_file(&path, &file::Options::new()).unwrap();
+        fs::remove_file(&path).unwrap();
+        cache.remove(&path).unwrap();
+        assert_eq!(0, cache.prune().unwrap());
+    }
+
+    #[test]
+    fn path_keys() {
+        let path = Path::new("/some/dir/file.txt");
+        assert_eq!(path, key_path(&path_key(path).unwrap()).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let path = Path::new(std::ffi::OsStr::from_bytes(b"/not/utf-8/\xff\xfe"));
+            assert_eq!(path, key_path(&path_key(path).unwrap()).unwrap());
+        }
+        #[cfg(not(unix))]
+        assert_eq!(io::ErrorKind::InvalidData, key_path(b"\xff\xfe").unwrap_err().kind());
+
+        // Rows written by something else, which might not decode, are pruned.
+        let cache = Cache::in_memory().unwrap();
+        cache.db.execute(
+            "INSERT INTO files (path, size, mtime_secs, mtime_nanos, content_hash, blocks)
+                VALUES (?1, 0, 0, 0, ?2, NULL)",
+            params![&b"\xff\xfe/nonexistent"[..], &[0u8; HASH_OUTPUT_SIZE][..]]).unwrap();
+        assert_eq!(1, cache.prune().unwrap());
+    }
+}
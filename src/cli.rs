@@ -0,0 +1,184 @@
+//! Parts of the command-line tool.
+
+use dropbox_content_hash::blocks::BlockHash;
+use dropbox_content_hash::HASH_OUTPUT_SIZE;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub mod archive;
+pub mod blocks;
+pub mod check;
+pub mod checkpoint;
+#[cfg(feature = "cloud")]
+pub mod cloud;
+pub mod compare;
+pub mod config;
+pub mod diff;
+pub mod digests;
+#[cfg(feature = "dropbox")]
+pub mod dropbox;
+pub mod dupes;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(all(feature = "cache", feature = "watch"))]
+pub mod index;
+pub mod jobs;
+pub mod output;
+pub mod progress;
+pub mod retry;
+#[cfg(feature = "serve")]
+pub mod serve;
+#[cfg(feature = "sign")]
+pub mod sign;
+pub mod sparse;
+pub mod stats;
+pub mod throttle;
+pub mod walk;
+#[cfg(feature = "watch")]
+pub mod watch;
+
+/// The result of hashing one file.
+pub struct Hashed {
+    /// The content hash.
+    pub hash: [u8; HASH_OUTPUT_SIZE],
+    /// The number of bytes hashed.
+    pub size: u64,
+    /// The block hashes, if they were asked for.
+    pub blocks: Option<Vec<BlockHash>>,
+    /// The other digests asked for with `--also`.
+    pub also: Vec<(digests::Algorithm, Vec<u8>)>,
+    /// The modification time of the file before it was read, if it's a file.
+    pub mtime: Option<SystemTime>,
+}
+
+/// Parse a size in bytes, optionally followed by a unit: "K", "M", "G", or "T" (which are all
+/// powers of 1024, and may be followed by "iB"), or "B".
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let shift = match unit.trim_start().trim_end_matches("iB") {
+        "" | "B" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("{:?} is not a size, such as 4096 or 4MiB", s)),
+    };
+    let number = number.parse::<u64>().map_err(|e| format!("{:?}: {}", s, e))?;
+    number.checked_mul(1 << shift).ok_or_else(|| format!("{:?} is too big", s))
+}
+
+/// Parse a duration: a number of seconds, or a number followed by "ms", "s", "m", or "h".
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let digits = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let scale = match unit.trim_start() {
+        "ms" => 0.001,
+        "" | "s" => 1.,
+        "m" => 60.,
+        "h" => 3600.,
+        _ => return Err(format!("{:?} is not a duration, such as 500ms or 5s", s)),
+    };
+    let number = number.parse::<f64>().map_err(|e| format!("{:?}: {}", s, e))?;
+    Duration::try_from_secs_f64(number * scale).map_err(|e| format!("{:?}: {}", s, e))
+}
+
+/// Parse a number of threads: a number, "auto" for the number of logical CPUs, or a percentage of
+/// them, such as "50%", which is rounded down but is always at least 1.
+pub fn parse_threads(s: &str) -> Result<usize, String> {
+    let available = dropbox_content_hash::parallel::available_threads();
+    if s == "auto" {
+        return Ok(available);
+    }
+    if let Some(percent) = s.strip_suffix('%') {
+        let percent = percent.parse::<usize>()
+            .map_err(|e| format!("{:?} is not a percentage: {}", s, e))?;
+        return Ok((available.saturating_mul(percent) / 100).max(1));
+    }
+    s.parse().map_err(|e| format!("{:?} is not a number of threads: {}", s, e))
+}
+
+/// Whether a path given on the command line is actually an HTTP or HTTPS URL.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|s| s.starts_with("https://") || s.starts_with("http://"))
+}
+
+/// Whether a path given on the command line is actually the URL of an object in cloud storage:
+/// Amazon S3 (`s3://`), Google Cloud Storage (`gs://`), or Azure Blob Storage (`az://`).
+pub fn is_object_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|s| ["s3://", "gs://", "az://"].iter().any(|p| s.starts_with(p)))
+}
+
+/// Read a list of paths from a file (or standard input, for "-"), separated by newlines, or by
+/// NUL bytes if `nul` is true. Empty entries are skipped.
+pub fn read_path_list(
+    list: &Path,
+    nul: bool,
+) -> io::Result<impl Iterator<Item = io::Result<PathBuf>>> {
+    let reader: Box<dyn BufRead> = if list == Path::new("-") {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(list)?))
+    };
+    Ok(reader.split(if nul { 0 } else { b'\n' })
+        .filter(|entry| entry.as_ref().map_or(true, |bytes| !bytes.is_empty()))
+        .map(|entry| entry.map(|bytes| PathBuf::from(os_string(bytes)))))
+}
+
+#[cfg(unix)]
+fn os_string(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(Ok(4096), parse_size("4096"));
+        assert_eq!(Ok(4096), parse_size("4096B"));
+        assert_eq!(Ok(4 << 20), parse_size("4MiB"));
+        assert_eq!(Ok(4 << 20), parse_size("4 M"));
+        assert_eq!(Ok(1 << 10), parse_size("1k"));
+        assert_eq!(Ok(2 << 40), parse_size("2TiB"));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("4 megs").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("100000000T").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(Ok(Duration::from_secs(5)), parse_duration("5"));
+        assert_eq!(Ok(Duration::from_secs(5)), parse_duration("5s"));
+        assert_eq!(Ok(Duration::from_millis(1500)), parse_duration("1.5s"));
+        assert_eq!(Ok(Duration::from_millis(250)), parse_duration("250ms"));
+        assert_eq!(Ok(Duration::from_secs(120)), parse_duration("2 m"));
+        assert_eq!(Ok(Duration::from_secs(3600)), parse_duration("1h"));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("5 days").is_err());
+    }
+
+    #[test]
+    fn threads() {
+        let available = dropbox_content_hash::parallel::available_threads();
+        assert_eq!(Ok(3), parse_threads("3"));
+        assert_eq!(Ok(available), parse_threads("auto"));
+        assert_eq!(Ok(available), parse_threads("100%"));
+        assert_eq!(Ok(2 * available), parse_threads("200%"));
+        assert_eq!(Ok(1), parse_threads("0%"));
+        assert!(parse_threads("half").is_err());
+        assert!(parse_threads("-5%").is_err());
+    }
+}
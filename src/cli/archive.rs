@@ -0,0 +1,140 @@
+//! Reading the files inside archives without extracting them, for `--tar` and `--zip`.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+#[cfg(any(feature = "tar", feature = "zip"))]
+use dropbox_content_hash::trace::debug;
+
+/// Call `visit` with the path, size, and contents of each regular file in a tar archive, in the
+/// order they're stored, stopping at the first error reading the archive.
+#[cfg(feature = "tar")]
+pub fn tar_entries(
+    archive: impl Read,
+    mut visit: impl FnMut(&Path, u64, &mut dyn Read),
+) -> io::Result<()> {
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !entry.header().entry_type().is_file() {
+            debug!(path = %path.display(), "skipping archive entry which isn't a regular file");
+            continue;
+        }
+        let size = entry.size();
+        visit(&path, size, &mut entry);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "tar"))]
+pub fn tar_entries(
+    _archive: impl Read,
+    _visit: impl FnMut(&Path, u64, &mut dyn Read),
+) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        "reading tar archives is not available in this build"))
+}
+
+/// Call `visit` with the path of each regular file in a zip archive, in the order they're listed,
+/// and its size and contents, or the error opening it, such as for files compressed with a method
+/// other than "stored" or "deflate". Stops at the first error reading the archive itself.
+#[cfg(feature = "zip")]
+pub fn zip_entries(
+    archive: File,
+    mut visit: impl FnMut(&Path, io::Result<(u64, &mut dyn Read)>),
+) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(archive)?;
+    for index in 0 .. archive.len() {
+        let path = match archive.name_for_index(index) {
+            Some(name) => Path::new(name).to_owned(),
+            None => continue,
+        };
+        match archive.by_index(index) {
+            Ok(entry) if !entry.is_file() => {
+                debug!(path = %path.display(), "skipping archive entry which isn't a regular file");
+            }
+            Ok(mut entry) => {
+                let size = entry.size();
+                visit(&path, Ok((size, &mut entry)));
+            }
+            Err(zip::result::ZipError::Io(e)) => return Err(e),
+            Err(e) => visit(&path, Err(e.into())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "zip"))]
+pub fn zip_entries(
+    _archive: File,
+    _visit: impl FnMut(&Path, io::Result<(u64, &mut dyn Read)>),
+) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        "reading zip archives is not available in this build"))
+}
+
+#[cfg(all(test, any(feature = "tar", feature = "zip")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "tar")]
+    fn tar() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder.append_data(&mut header, "dir/", io::empty()).unwrap();
+        for (path, data) in [("dir/a", &b"hello"[..]), ("b", &[7u8; 10000][..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+
+        let mut entries = vec![];
+        tar_entries(&archive[..], |path, size, contents| {
+            // Only read part of the second one; the rest should be skipped.
+            let mut data = vec![];
+            contents.take(5).read_to_end(&mut data).unwrap();
+            entries.push((path.to_owned(), size, data));
+        }).unwrap();
+        assert_eq!(vec![
+            (Path::new("dir/a").to_owned(), 5, b"hello".to_vec()),
+            (Path::new("b").to_owned(), 10000, vec![7; 5]),
+        ], entries);
+    }
+
+    #[test]
+    #[cfg(feature = "zip")]
+    fn zip() {
+        use std::io::{Seek, SeekFrom, Write};
+        use zip::write::SimpleFileOptions;
+        use zip::CompressionMethod;
+
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = zip::ZipWriter::new(&mut file);
+        writer.add_directory("dir/", SimpleFileOptions::default()).unwrap();
+        writer.start_file("dir/a", SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.start_file("b", SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)).unwrap();
+        writer.write_all(&[7; 10000]).unwrap();
+        writer.finish().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut entries = vec![];
+        zip_entries(file, |path, entry| {
+            let (size, contents) = entry.unwrap();
+            let mut data = vec![];
+            contents.read_to_end(&mut data).unwrap();
+            entries.push((path.to_owned(), size, data));
+        }).unwrap();
+        assert_eq!(vec![
+            (Path::new("dir/a").to_owned(), 5, b"hello".to_vec()),
+            (Path::new("b").to_owned(), 10000, vec![7; 10000]),
+        ], entries);
+    }
+}
@@ -0,0 +1,278 @@
+//! Block hash manifests, for `--blocks-out` and `--verify-blocks`.
+
+use super::check::Verbosity;
+use super::output::json_string;
+use super::progress::Progress;
+use super::Hashed;
+use dropbox_content_hash::blocks::{BlockHash, BlockHashList};
+use dropbox_content_hash::{block_range, file, hex_string, parse_hex};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The formats of block hash manifests, as given to `--blocks-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A "# PATH" line for each file, followed by an "INDEX OFFSET HASH" line for each block.
+    Text,
+    /// A JSON object for each file, one per line, giving its path, size, and an array of objects
+    /// with each block's index, offset, and hash.
+    Json,
+}
+
+impl Format {
+    /// The names accepted by [`FromStr`].
+    pub const NAMES: &'static [&'static str] = &["text", "json"];
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown block manifest format {:?}", s)),
+        }
+    }
+}
+
+/// Writes the block hashes of each file to a manifest.
+pub struct BlockWriter<W = BufWriter<File>> {
+    out: W,
+    format: Format,
+}
+
+impl BlockWriter {
+    /// Create (or truncate) the manifest file.
+    pub fn create(path: &Path, format: Format) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?), format))
+    }
+}
+
+impl<W: Write> BlockWriter<W> {
+    /// Write the manifest to the given writer.
+    pub fn new(out: W, format: Format) -> Self {
+        Self { out, format }
+    }
+
+    /// Write the block hashes of a file. Nothing is written if they weren't collected.
+    pub fn write(&mut self, path: &Path, hashed: &Hashed) -> io::Result<()> {
+        match &hashed.blocks {
+            Some(blocks) => self.write_blocks(path, hashed.size, blocks),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the given block hashes of a file of the given size.
+    pub fn write_blocks(&mut self, path: &Path, size: u64, blocks: &[BlockHash]) -> io::Result<()> {
+        match self.format {
+            Format::Text => {
+                writeln!(self.out, "# {}", path.display())?;
+                for (index, hash) in blocks.iter().enumerate() {
+                    let offset = block_range(index as u64).start;
+                    writeln!(self.out, "{} {} {}", index, offset, hex_string(hash))?;
+                }
+            }
+            Format::Json => {
+                let blocks = blocks.iter().enumerate()
+                    .map(|(index, hash)| format!("{{\"index\":{},\"offset\":{},\"hash\":\"{}\"}}",
+                        index, block_range(index as u64).start, hex_string(hash)))
+                    .collect::<Vec<_>>();
+                writeln!(self.out, "{{\"path\":{},\"size\":{},\"blocks\":[{}]}}",
+                    json_string(&path.to_string_lossy()), size, blocks.join(","))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the manifest to the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Unwrap the writer, without flushing it.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// The block hashes listed for a file in a manifest.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The file's path.
+    pub path: PathBuf,
+    /// The hash of each block, in order.
+    pub blocks: Vec<BlockHash>,
+}
+
+/// Read a manifest in either format (or from standard input, for "-").
+pub fn read_manifest(manifest: &Path) -> io::Result<Vec<ManifestEntry>> {
+    if manifest == Path::new("-") {
+        parse_manifest(io::stdin().lock())
+    } else {
+        parse_manifest(BufReader::new(File::open(manifest)?))
+    }
+}
+
+/// Read a manifest in either format from a reader.
+pub fn parse_manifest(reader: impl BufRead) -> io::Result<Vec<ManifestEntry>> {
+    let mut entries = vec![];
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        parse_line(&line, &mut entries).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {} is improperly formatted", number + 1)))?;
+    }
+    Ok(entries)
+}
+
+/// Parse a line of a manifest, adding to the entries.
+fn parse_line(line: &str, entries: &mut Vec<ManifestEntry>) -> Option<()> {
+    if line.is_empty() {
+        return Some(());
+    }
+    if let Some(path) = line.strip_prefix("# ") {
+        entries.push(ManifestEntry { path: PathBuf::from(path), blocks: vec![] });
+        return Some(());
+    }
+    if line.starts_with('{') {
+        let object = serde_json::from_str::<Value>(line).ok()?;
+        let mut entry = ManifestEntry {
+            path: PathBuf::from(object["path"].as_str()?),
+            blocks: vec![],
+        };
+        for block in object["blocks"].as_array()? {
+            let index = block["index"].as_u64()?;
+            add_block(&mut entry, index, block["offset"].as_u64()?, block["hash"].as_str()?)?;
+        }
+        entries.push(entry);
+        return Some(());
+    }
+    let mut fields = line.split(' ');
+    let index = fields.next()?.parse().ok()?;
+    let offset = fields.next()?.parse().ok()?;
+    let hash = fields.next()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    add_block(entries.last_mut()?, index, offset, hash)
+}
+
+/// Add a block to the entry, checking that it's the next one and has the right offset.
+fn add_block(entry: &mut ManifestEntry, index: u64, offset: u64, hash: &str) -> Option<()> {
+    if index != entry.blocks.len() as u64 || offset != block_range(index).start {
+        return None;
+    }
+    entry.blocks.push(parse_hex(hash).ok()?);
+    Some(())
+}
+
+/// Check every file listed in the manifest block by block, printing "PATH: OK" for each one that
+/// matches, or a line for each block which doesn't. Returns the exit code: 0 if every file
+/// matched, 1 if not.
+pub fn verify(manifest: &Path, progress: &Progress, verbosity: Verbosity) -> io::Result<i32> {
+    let entries = read_manifest(manifest)?;
+    let mut failed = 0;
+    for entry in &entries {
+        let path = entry.path.display();
+        let actual = File::open(&entry.path).and_then(|file| {
+            let len = file::len(&file).ok();
+            let bar = progress.file(&entry.path, len);
+            BlockHashList::from_stream(bar.reader(file))
+        });
+        let actual = match actual {
+            Ok(list) => list,
+            Err(e) => {
+                if verbosity.failures() {
+                    eprintln!("{}: {}", path, e);
+                    println!("{}: FAILED open or read", path);
+                }
+                failed += 1;
+                continue;
+            }
+        };
+        let diffs = diff_blocks(&entry.blocks, actual.hashes());
+        if diffs.is_empty() {
+            if verbosity.ok() {
+                println!("{}: OK", path);
+            }
+            continue;
+        }
+        failed += 1;
+        if verbosity.failures() {
+            for (index, diff) in diffs {
+                let offset = block_range(index).start;
+                println!("{}: block {} at offset {} {}", path, index, offset, diff);
+            }
+        }
+    }
+
+    if failed != 0 {
+        if verbosity.failures() {
+            eprintln!("WARNING: {} of {} listed {} NOT match", failed, entries.len(),
+                if entries.len() == 1 { "file did" } else { "files did" });
+        }
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+/// Find the blocks which differ, returning their indexes and how they differ.
+fn diff_blocks(expected: &[BlockHash], actual: &[BlockHash]) -> Vec<(u64, &'static str)> {
+    (0 .. expected.len().max(actual.len()))
+        .filter_map(|index| {
+            let diff = match (expected.get(index), actual.get(index)) {
+                (Some(expected), Some(actual)) if expected == actual => return None,
+                (Some(_), Some(_)) => "FAILED",
+                (Some(_), None) => "missing (the file is shorter)",
+                (None, _) => "not in the manifest (the file is longer)",
+            };
+            Some((index as u64, diff))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dropbox_content_hash::BLOCK_SIZE;
+
+    #[test]
+    fn parse() {
+        let a = "ab".repeat(32);
+        let b = "cd".repeat(32);
+        let mut entries = vec![];
+        for line in &[
+            "# one".to_owned(),
+            format!("0 0 {}", a),
+            format!("1 {} {}", BLOCK_SIZE, b),
+            format!("{{\"path\":\"two\",\"size\":1,\"blocks\":\
+                [{{\"index\":0,\"offset\":0,\"hash\":\"{}\"}}]}}", b),
+            "# three".to_owned(),
+        ] {
+            assert_eq!(Some(()), parse_line(line, &mut entries), "{}", line);
+        }
+        assert_eq!(vec![
+            ManifestEntry { path: "one".into(), blocks: vec![[0xab; 32], [0xcd; 32]] },
+            ManifestEntry { path: "two".into(), blocks: vec![[0xcd; 32]] },
+            ManifestEntry { path: "three".into(), blocks: vec![] },
+        ], entries);
+
+        // Blocks must be in order, with the right offsets, and belong to a file.
+        assert_eq!(None, parse_line(&format!("1 {} {}", 2 * BLOCK_SIZE, a), &mut entries));
+        assert_eq!(None, parse_line(&format!("0 1 {}", a), &mut entries));
+        assert_eq!(None, parse_line(&format!("0 0 {}", a), &mut vec![]));
+    }
+
+    #[test]
+    fn diff() {
+        let (a, b) = ([1; 32], [2; 32]);
+        assert!(diff_blocks(&[a, b], &[a, b]).is_empty());
+        assert_eq!(vec![(1, "FAILED")], diff_blocks(&[a, b], &[a, a]));
+        assert_eq!(vec![(1, "missing (the file is shorter)")], diff_blocks(&[a, b], &[a]));
+        assert_eq!(vec![(1, "not in the manifest (the file is longer)")],
+            diff_blocks(&[a], &[a, b]));
+    }
+}
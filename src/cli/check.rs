@@ -0,0 +1,409 @@
+//! Verify files against a manifest of their content hashes, like `sha256sum --check`.
+
+use dropbox_content_hash::{parse_hex, HASH_OUTPUT_SIZE};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the algorithm in BSD-style tagged lines.
+pub const TAG: &str = "DropboxContentHash";
+
+/// How much the checking modes print, as chosen by `--quiet` and `--status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// A line for every file, and warnings.
+    Normal,
+    /// Nothing for files which match.
+    Quiet,
+    /// Nothing at all; the result is only in the exit code.
+    Status,
+}
+
+impl Verbosity {
+    /// Whether to print a line for a file which matched.
+    pub fn ok(self) -> bool {
+        self == Verbosity::Normal
+    }
+
+    /// Whether to print failures and warnings.
+    pub fn failures(self) -> bool {
+        self != Verbosity::Status
+    }
+}
+
+/// Parse a manifest line of the form `HASH  PATH`, or the BSD-style `DropboxContentHash (PATH) =
+/// HASH`. A `*` in place of the second space, which sha256sum writes for files hashed in binary
+/// mode, is also accepted, as is a `\` at the start of the line, which means the path is escaped
+/// as by [`escape`].
+pub fn parse_line(line: &str) -> Option<([u8; HASH_OUTPUT_SIZE], PathBuf)> {
+    match line.strip_prefix('\\') {
+        Some(escaped) => {
+            let (hash, path) = split_line(escaped)?;
+            Some((hash, PathBuf::from(unescape(path)?)))
+        }
+        None => split_line(line).map(|(hash, path)| (hash, PathBuf::from(path))),
+    }
+}
+
+fn split_line(line: &str) -> Option<([u8; HASH_OUTPUT_SIZE], &str)> {
+    if let Some(tagged) = line.strip_prefix(TAG).and_then(|rest| rest.strip_prefix(" (")) {
+        let (path, hex) = tagged.rsplit_once(") = ")?;
+        if path.is_empty() {
+            return None;
+        }
+        return Some((parse_hex(hex).ok()?, path));
+    }
+
+    let hex = line.get(.. 2 * HASH_OUTPUT_SIZE)?;
+    let rest = &line[hex.len() ..];
+    let path = rest.strip_prefix("  ").or_else(|| rest.strip_prefix(" *"))?;
+    if path.is_empty() {
+        return None;
+    }
+    Some((parse_hex(hex).ok()?, path))
+}
+
+/// Escape a path the way coreutils does in its checksum lines, so any name fits on one line:
+/// backslashes, newlines, and carriage returns are written as `\\`, `\n`, and `\r`. Returns
+/// whether anything was escaped, in which case the line should start with a `\`.
+pub fn escape(path: &Path) -> (bool, String) {
+    let path = path.to_string_lossy();
+    if !path.contains(&['\\', '\n', '\r'][..]) {
+        return (false, path.into_owned());
+    }
+    let escaped = path.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+    (true, escaped)
+}
+
+/// Undo [`escape`], or return `None` if there's a backslash that isn't part of an escape.
+fn unescape(path: &str) -> Option<String> {
+    let mut out = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        out.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(out)
+}
+
+/// The first line of a manifest written with `--manifest`, whose lines also give the size and
+/// modification time of each file: `HASH  SIZE  MTIME  PATH`, with `-` for an unknown time.
+pub const MANIFEST_HEADER: &str = "# dropbox-content-hash manifest: hash, size, mtime, path";
+
+/// A line of a manifest written with `--manifest`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Record {
+    pub hash: [u8; HASH_OUTPUT_SIZE],
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    pub path: PathBuf,
+}
+
+/// Parse a line of a manifest written with `--manifest`, which is escaped like the lines
+/// [`parse_line`] reads.
+pub fn parse_record(line: &str) -> Option<Record> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (hex, rest) = line.split_once("  ")?;
+    let (size, rest) = rest.split_once("  ")?;
+    let (mtime, path) = rest.split_once("  ")?;
+    if path.is_empty() {
+        return None;
+    }
+    Some(Record {
+        hash: parse_hex(hex).ok()?,
+        size: size.parse().ok()?,
+        mtime: if mtime == "-" { None } else { Some(parse_mtime(mtime)?) },
+        path: PathBuf::from(if escaped { unescape(path)? } else { path.to_owned() }),
+    })
+}
+
+/// Parse a line of a manifest, which starts with [`MANIFEST_HEADER`] if `with_metadata`.
+pub fn parse_any_line(
+    line: &str,
+    with_metadata: bool,
+) -> Option<([u8; HASH_OUTPUT_SIZE], PathBuf)> {
+    if with_metadata {
+        parse_record(line).map(|record| (record.hash, record.path))
+    } else {
+        parse_line(line)
+    }
+}
+
+/// A modification time as written in manifests: seconds since the Unix epoch, and nanoseconds.
+pub fn format_mtime(mtime: SystemTime) -> String {
+    match mtime.duration_since(UNIX_EPOCH) {
+        Ok(since) => format!("{}.{:09}", since.as_secs(), since.subsec_nanos()),
+        Err(e) => format!("-{}.{:09}", e.duration().as_secs(), e.duration().subsec_nanos()),
+    }
+}
+
+/// Parse a modification time written by [`format_mtime`].
+pub fn parse_mtime(s: &str) -> Option<SystemTime> {
+    let (before, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let (secs, nanos) = s.split_once('.')?;
+    if nanos.len() != 9 || !secs.bytes().chain(nanos.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let since = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    if before {
+        UNIX_EPOCH.checked_sub(since)
+    } else {
+        UNIX_EPOCH.checked_add(since)
+    }
+}
+
+/// Whether a line is one `rclone hashsum` writes for a file it has no hash for: `ERROR` or
+/// `UNSUPPORTED`, lined up with the hashes, in place of one.
+fn is_rclone_placeholder(line: &str) -> bool {
+    let rest = line.trim_start_matches(' ');
+    rest.len() < line.len()
+        && ["ERROR  ", "UNSUPPORTED  "].iter().any(|word| rest.starts_with(word))
+}
+
+/// How to read a manifest and what counts as failing, corresponding to sha256sum's options.
+#[derive(Debug, Clone, Copy)]
+pub struct Options<'a> {
+    /// The directory paths in the manifest are relative to, as they are in the output of `rclone
+    /// hashsum`, instead of the current directory.
+    pub root: Option<&'a Path>,
+    /// Lines in the manifest end with a NUL byte instead of a newline.
+    pub zero: bool,
+    pub verbosity: Verbosity,
+    /// Skip files which don't exist, instead of failing.
+    pub ignore_missing: bool,
+    /// Fail if any line is improperly formatted.
+    pub strict: bool,
+    /// Warn about each improperly formatted line, not just how many there were.
+    pub warn: bool,
+}
+
+/// Open a manifest, or standard input for "-".
+pub fn open(manifest: &Path) -> io::Result<Box<dyn BufRead>> {
+    Ok(if manifest == Path::new("-") {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(manifest)?))
+    })
+}
+
+/// Check every file listed in a manifest, read from `reader`, using the given function to hash
+/// them, printing the results. Returns the exit code: 0 if every file matched, 1 if not.
+pub fn run(
+    manifest: &Path,
+    reader: impl BufRead,
+    options: &Options<'_>,
+    mut hash: impl FnMut(&Path) -> Result<[u8; HASH_OUTPUT_SIZE], String>,
+) -> io::Result<i32> {
+    let verbosity = options.verbosity;
+
+    let mut ok = 0;
+    let mut malformed = 0;
+    let mut unhashed = 0;
+    let mut missing = 0;
+    let mut unreadable = 0;
+    let mut mismatched = 0;
+    let mut with_metadata = false;
+    let lines = reader.split(if options.zero { b'\0' } else { b'\n' });
+    for (number, line) in (1 ..).zip(lines) {
+        let mut line = String::from_utf8(line?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !options.zero && line.ends_with('\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        if number == 1 && line == MANIFEST_HEADER {
+            with_metadata = true;
+            continue;
+        }
+        let (expected, path) = match parse_any_line(&line, with_metadata) {
+            Some(parsed) => parsed,
+            None if is_rclone_placeholder(&line) => {
+                unhashed += 1;
+                continue;
+            }
+            None => {
+                if options.warn && verbosity.failures() {
+                    eprintln!("{}: {}: improperly formatted content hash line",
+                        manifest.display(), number);
+                }
+                malformed += 1;
+                continue;
+            }
+        };
+        let full_path = match options.root {
+            Some(root) => root.join(&path),
+            None => path.clone(),
+        };
+        if options.ignore_missing && !full_path.exists() {
+            missing += 1;
+            continue;
+        }
+        let hashed = hash(&full_path);
+        let (escaped, name) = escape(&path);
+        let name = if escaped { format!("\\{}", name) } else { name };
+        match hashed {
+            Ok(actual) if actual == expected => {
+                if verbosity.ok() {
+                    println!("{}: OK", name);
+                }
+                ok += 1;
+            }
+            Ok(_) => {
+                if verbosity.failures() {
+                    println!("{}: FAILED", name);
+                }
+                mismatched += 1;
+            }
+            Err(e) => {
+                if verbosity.failures() {
+                    eprintln!("{}", e);
+                    println!("{}: FAILED open or read", name);
+                }
+                unreadable += 1;
+            }
+        }
+    }
+
+    if verbosity.failures() {
+        if malformed != 0 {
+            eprintln!("WARNING: {} {} improperly formatted",
+                malformed, plural(malformed, "line is", "lines are"));
+        }
+        if unhashed != 0 {
+            eprintln!("WARNING: {} listed {} no hash",
+                unhashed, plural(unhashed, "file has", "files have"));
+        }
+        if unreadable != 0 {
+            eprintln!("WARNING: {} listed {} not be read",
+                unreadable, plural(unreadable, "file could", "files could"));
+        }
+        if mismatched != 0 {
+            eprintln!("WARNING: {} computed {} NOT match",
+                mismatched, plural(mismatched, "hash did", "hashes did"));
+        }
+    }
+    if ok == 0 && mismatched == 0 && unreadable == 0 {
+        if verbosity.failures() {
+            if missing == 0 {
+                eprintln!("{}: no properly formatted content hash lines found",
+                    manifest.display());
+            } else {
+                eprintln!("{}: no file was verified", manifest.display());
+            }
+        }
+        return Ok(1);
+    }
+    let failed = mismatched != 0 || unreadable != 0 || (options.strict && malformed != 0);
+    Ok(if failed { 1 } else { 0 })
+}
+
+fn plural(n: usize, one: &'static str, many: &'static str) -> &'static str {
+    if n == 1 { one } else { many }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn lines() {
+        let hex = "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50";
+        let (hash, path) = parse_line(&format!("{}  some file", hex)).unwrap();
+        assert_eq!(0x95, hash[0]);
+        assert_eq!(0x50, hash[31]);
+        assert_eq!(Path::new("some file"), path);
+        assert_eq!(Path::new("x"), parse_line(&format!("{} *x", hex)).unwrap().1);
+        assert_eq!(None, parse_line(&format!("{} x", hex)));
+        assert_eq!(None, parse_line(&format!("{}  ", hex)));
+        assert_eq!(None, parse_line(&format!("{}  x", &hex[1 ..])));
+        assert_eq!(None, parse_line("hello"));
+
+        let (tagged_hash, path) = parse_line(&format!("{} (a) = b) = {}", TAG, hex)).unwrap();
+        assert_eq!(hash, tagged_hash);
+        assert_eq!(Path::new("a) = b"), path);
+        assert_eq!(None, parse_line(&format!("{} () = {}", TAG, hex)));
+        assert_eq!(None, parse_line(&format!("SHA256 (a) = {}", hex)));
+
+        let (_, path) = parse_line(&format!("\\{}  a\\\\b\\nc\\rd", hex)).unwrap();
+        assert_eq!(Path::new("a\\b\nc\rd"), path);
+        assert_eq!((true, "a\\\\b\\nc\\rd".to_owned()), escape(&path));
+        assert_eq!((false, "a b".to_owned()), escape(Path::new("a b")));
+        assert_eq!(path, parse_line(&format!("\\{} (a\\\\b\\nc\\rd) = {}", TAG, hex)).unwrap().1);
+        assert_eq!(None, parse_line(&format!("\\{}  a\\b", hex)));
+        assert_eq!(Path::new("a\\b"), parse_line(&format!("{}  a\\b", hex)).unwrap().1);
+
+        let record = parse_record(&format!("{}  12  1700000000.000000042  a  b", hex)).unwrap();
+        assert_eq!(Record {
+            hash,
+            size: 12,
+            mtime: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 42)),
+            path: PathBuf::from("a  b"),
+        }, record);
+        let record = parse_record(&format!("\\{}  0  -  a\\nb", hex)).unwrap();
+        assert_eq!((None, Path::new("a\nb")), (record.mtime, record.path.as_path()));
+        assert_eq!(None, parse_record(&format!("{}  some file", hex)));
+        assert_eq!(None, parse_record(&format!("{}  1  1.5  a", hex)));
+
+        assert!(is_rclone_placeholder(&format!("{:>64}  a/b", "ERROR")));
+        assert!(is_rclone_placeholder(&format!("{:>64}  a/b", "UNSUPPORTED")));
+        assert!(!is_rclone_placeholder("ERROR  a/b"));
+        assert!(!is_rclone_placeholder(&format!("{:>64}  a/b", "OTHER")));
+    }
+
+    #[test]
+    fn mtimes() {
+        let since = Duration::new(5, 6);
+        for mtime in [UNIX_EPOCH, UNIX_EPOCH + since, UNIX_EPOCH - since] {
+            assert_eq!(Some(mtime), parse_mtime(&format_mtime(mtime)));
+        }
+        assert_eq!("-5.000000006", format_mtime(UNIX_EPOCH - since));
+        assert_eq!(None, parse_mtime("5.6"));
+        assert_eq!(None, parse_mtime("+5.000000006"));
+    }
+
+    #[test]
+    fn options() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("present"), b"").unwrap();
+        let manifest = dir.join("manifest");
+        let hex = "00".repeat(HASH_OUTPUT_SIZE);
+        std::fs::write(&manifest, format!("{0}  present\nnonsense\n{0}  missing\n", hex))
+            .unwrap();
+
+        let options = Options {
+            root: Some(dir),
+            zero: false,
+            verbosity: Verbosity::Status,
+            ignore_missing: false,
+            strict: false,
+            warn: false,
+        };
+        let hash = |path: &Path| match path.exists() {
+            true => Ok([0; HASH_OUTPUT_SIZE]),
+            false => Err("missing".to_owned()),
+        };
+        assert_eq!(1, run(&manifest, open(&manifest).unwrap(), &options, hash).unwrap());
+        let options = Options { ignore_missing: true, ..options };
+        assert_eq!(0, run(&manifest, open(&manifest).unwrap(), &options, hash).unwrap());
+        let options = Options { strict: true, ..options };
+        assert_eq!(1, run(&manifest, open(&manifest).unwrap(), &options, hash).unwrap());
+    }
+}
@@ -0,0 +1,162 @@
+//! Saving the progress of hashing a file, so it can be resumed later, for `--checkpoint`.
+//!
+//! A checkpoint is a line saying which file it's of, by its size, modification time, and (on
+//! Unix) device and inode numbers, followed by a block hash manifest in the text format, listing
+//! the whole blocks of the file which have been hashed so far.
+
+use super::blocks::{parse_manifest, BlockWriter, Format};
+use super::output::AtomicFile;
+use dropbox_content_hash::blocks::BlockHash;
+use dropbox_content_hash::BLOCK_SIZE;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// How often the checkpoint is saved while hashing.
+const INTERVAL: Duration = Duration::from_secs(30);
+
+/// The checkpoint of one file.
+pub struct Checkpoint<'a> {
+    path: &'a Path,
+    file: &'a Path,
+    identity: String,
+    saved: Instant,
+}
+
+impl<'a> Checkpoint<'a> {
+    /// A checkpoint kept at `path`, of the hash of `file`, whose metadata is `meta`.
+    pub fn new(path: &'a Path, file: &'a Path, meta: &Metadata) -> Self {
+        Self { path, file, identity: identity(meta), saved: Instant::now() }
+    }
+
+    /// Read the hashes of the blocks hashed so far, if the checkpoint exists. It's an error if
+    /// it's the checkpoint of a different file, or if the file has changed since it was saved.
+    pub fn load(&self) -> io::Result<Vec<BlockHash>> {
+        let mut reader = match File::open(self.path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut identity = String::new();
+        reader.read_line(&mut identity)?;
+        let mut entries = parse_manifest(reader)?;
+        match entries.pop() {
+            Some(entry) if entries.is_empty() && entry.path == self.file => {
+                if identity.trim_end() != self.identity {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("{:?} has changed since the checkpoint was saved", self.file)));
+                }
+                Ok(entry.blocks)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("it isn't a checkpoint of {:?}", self.file))),
+        }
+    }
+
+    /// Save the checkpoint if it hasn't been saved for a while.
+    pub fn save_if_due(&mut self, blocks: &[BlockHash]) -> io::Result<()> {
+        if self.saved.elapsed() >= INTERVAL {
+            self.save(blocks)?;
+        }
+        Ok(())
+    }
+
+    /// Save the checkpoint, replacing the old one all at once, so it's never left half-written.
+    pub fn save(&mut self, blocks: &[BlockHash]) -> io::Result<()> {
+        let mut out = AtomicFile::create(self.path, false)?;
+        writeln!(out, "{}", self.identity)?;
+        let mut writer = BlockWriter::new(out, Format::Text);
+        writer.write_blocks(self.file, (blocks.len() * BLOCK_SIZE) as u64, blocks)?;
+        writer.into_inner().commit()?;
+        self.saved = Instant::now();
+        Ok(())
+    }
+
+    /// Remove the checkpoint, once the hash is finished.
+    pub fn remove(self) -> io::Result<()> {
+        match fs::remove_file(self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The line identifying the file a checkpoint is of, which changes if the file is modified or
+/// replaced.
+fn identity(meta: &Metadata) -> String {
+    let mut identity = format!("checkpoint size={}", meta.len());
+    let mtime = meta.modified().ok().and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok());
+    if let Some(mtime) = mtime {
+        identity += &format!(" mtime={}.{:09}", mtime.as_secs(), mtime.subsec_nanos());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        identity += &format!(" dev={} ino={}", meta.dev(), meta.ino());
+    }
+    identity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("checkpoint");
+        let file = dir.path().join("file");
+        fs::write(&file, b"some data").unwrap();
+        let meta = file.metadata().unwrap();
+        let blocks = vec![[1; 32], [2; 32]];
+
+        let mut checkpoint = Checkpoint::new(&path, &file, &meta);
+        assert_eq!(Vec::<BlockHash>::new(), checkpoint.load().unwrap());
+        checkpoint.save(&blocks).unwrap();
+        assert_eq!(blocks, checkpoint.load().unwrap());
+        assert!(Checkpoint::new(&path, Path::new("/other/file"), &meta).load().is_err());
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn changed_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("checkpoint");
+        let file = dir.path().join("file");
+        fs::write(&file, b"some data").unwrap();
+        let meta = file.metadata().unwrap();
+        Checkpoint::new(&path, &file, &meta).save(&[[1; 32]]).unwrap();
+        let load = |meta: &Metadata| Checkpoint::new(&path, &file, meta).load();
+        assert_eq!(vec![[1; 32]], load(&meta).unwrap());
+
+        // A different size.
+        fs::write(&file, b"some more data").unwrap();
+        let changed = file.metadata().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, load(&changed).unwrap_err().kind());
+
+        // The same size, but modified at a different time.
+        fs::write(&file, b"some DATA").unwrap();
+        File::options().write(true).open(&file).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        let changed = file.metadata().unwrap();
+        assert_eq!(meta.len(), changed.len());
+        assert_eq!(io::ErrorKind::InvalidData, load(&changed).unwrap_err().kind());
+
+        // The same size and time, but a different file put in its place.
+        #[cfg(unix)]
+        {
+            let other = dir.path().join("other");
+            fs::write(&other, b"some data").unwrap();
+            File::options().write(true).open(&other).unwrap()
+                .set_modified(meta.modified().unwrap()).unwrap();
+            fs::rename(&other, &file).unwrap();
+            let changed = file.metadata().unwrap();
+            assert_eq!(meta.modified().unwrap(), changed.modified().unwrap());
+            assert_eq!(io::ErrorKind::InvalidData, load(&changed).unwrap_err().kind());
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Reading objects from cloud storage, for `s3://`, `gs://`, and `az://` URLs.
+
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use object_store::aws::AmazonS3Builder;
+use object_store::azure::MicrosoftAzureBuilder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use std::io::{self, Read};
+use tokio::runtime::{self, Runtime};
+
+/// Reads an object's contents as they're downloaded.
+pub struct ObjectReader {
+    runtime: Runtime,
+    stream: BoxStream<'static, object_store::Result<Bytes>>,
+    /// What's left of the last piece downloaded.
+    chunk: Bytes,
+}
+
+/// Start downloading an object, such as "s3://bucket/path/to/file", returning a reader for its
+/// contents and its size. Credentials and other settings are taken from the environment, such as
+/// `AWS_ACCESS_KEY_ID`, `GOOGLE_SERVICE_ACCOUNT`, or `AZURE_STORAGE_ACCOUNT_NAME`.
+pub fn open(url: &str) -> Result<(ObjectReader, u64), String> {
+    let failed = |e: &dyn std::fmt::Display| format!("{}: {}", url, e);
+    let (scheme, rest) = url.split_once("://").ok_or_else(|| failed(&"not a URL"))?;
+    let (container, key) = rest.split_once('/')
+        .filter(|(container, key)| !container.is_empty() && !key.is_empty())
+        .ok_or_else(|| failed(&"expected a bucket or container name followed by a path"))?;
+    let root = format!("{}://{}", scheme, container);
+    let store: Box<dyn ObjectStore> = match scheme {
+        "s3" => Box::new(AmazonS3Builder::from_env().with_url(root).build()
+            .map_err(|e| failed(&e))?),
+        "gs" => Box::new(GoogleCloudStorageBuilder::from_env().with_url(root).build()
+            .map_err(|e| failed(&e))?),
+        "az" => Box::new(MicrosoftAzureBuilder::from_env().with_url(root).build()
+            .map_err(|e| failed(&e))?),
+        _ => return Err(failed(&"unsupported kind of URL")),
+    };
+    let path = ObjectPath::parse(key).map_err(|e| failed(&e))?;
+
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| failed(&e))?;
+    let result = runtime.block_on(store.get(&path)).map_err(|e| failed(&e))?;
+    let size = result.meta.size;
+    let reader = ObjectReader { runtime, stream: result.into_stream(), chunk: Bytes::new() };
+    Ok((reader, size))
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.runtime.block_on(self.stream.next()) {
+                Some(chunk) => self.chunk = chunk.map_err(io::Error::other)?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[.. n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
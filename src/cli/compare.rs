@@ -0,0 +1,143 @@
+//! Comparing the content hashes of two files, for the `compare` subcommand.
+
+use super::jobs::map_ordered;
+use super::progress::Progress;
+use super::Hashed;
+use dropbox_content_hash::blocks::{self, BlockHash, BlockHashList};
+use dropbox_content_hash::{block_range, file, hex_string, BLOCK_SIZE};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Hash both files using the given function, on separate threads if `jobs` is more than 1, and
+/// print their hashes and whether they match. Returns the exit code: 0 if they match, 1 if not,
+/// or 2 if either couldn't be hashed.
+pub fn run(
+    a: &Path,
+    b: &Path,
+    jobs: usize,
+    hash: impl Fn(&Path) -> Result<Hashed, String> + Sync,
+) -> i32 {
+    let mut results = vec![];
+    map_ordered(
+        jobs.min(2),
+        |send| {
+            send(a.to_owned());
+            send(b.to_owned());
+        },
+        |path: PathBuf| {
+            let result = hash(&path);
+            (path, result)
+        },
+        |result| results.push(result));
+
+    let mut hashes = vec![];
+    for (path, result) in results {
+        match result {
+            Ok(hashed) => {
+                println!("{}  {}", hex_string(&hashed.hash), path.display());
+                hashes.push(hashed.hash);
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    match &hashes[..] {
+        [a, b] if a == b => {
+            println!("Content hashes match");
+            0
+        }
+        [_, _] => {
+            println!("Content hashes differ");
+            1
+        }
+        _ => 2,
+    }
+}
+
+/// Read both files a block at a time, stopping at the first block which differs and printing its
+/// number and offset, along with the offset of the first byte that differs. If the files are the
+/// same, print their hashes as [`run`] does. Returns the exit code: 0 if they match, 1 if not, or
+/// 2 if either couldn't be read.
+///
+/// Both files are read at once if `jobs` is more than 1.
+pub fn first_diff(a: &Path, b: &Path, jobs: usize, progress: &Progress) -> i32 {
+    match find_first_diff(a, b, jobs, progress) {
+        Ok(Diff::Same(list)) => {
+            let hash = hex_string(&list.content_hash());
+            println!("{}  {}", hash, a.display());
+            println!("{}  {}", hash, b.display());
+            println!("Content hashes match");
+            0
+        }
+        Ok(Diff::Block { index, byte }) => {
+            println!("Files differ starting in block {} (at byte offset {}); the first \
+                differing byte is at offset {}",
+                index, block_range(index).start, byte);
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+enum Diff {
+    /// The files are the same.
+    Same(BlockHashList),
+    /// The files differ in the block with the given index, first at the given byte offset.
+    Block { index: u64, byte: u64 },
+}
+
+fn find_first_diff(a: &Path, b: &Path, jobs: usize, progress: &Progress) -> Result<Diff, String> {
+    let open = |path: &Path| File::open(path)
+        .map_err(|e| format!("Failed to open {:?}: {}", path, e));
+    let (mut file_a, mut file_b) = (open(a)?, open(b)?);
+    let len = file::len(&file_a).ok();
+    let bar = progress.file(a, len);
+    let tracker = bar.tracker();
+
+    let mut hashes = vec![];
+    let mut buf_a = Vec::with_capacity(BLOCK_SIZE);
+    let mut buf_b = Vec::with_capacity(BLOCK_SIZE);
+    let mut index = 0;
+    loop {
+        let read = |path: &Path, file: &mut File, buf: &mut Vec<u8>| {
+            read_block(file, buf).map_err(|e| format!("Failed to read {:?}: {}", path, e))
+        };
+        let (hash_a, hash_b) = if jobs > 1 {
+            thread::scope(|scope| {
+                let other = scope.spawn(|| read(b, &mut file_b, &mut buf_b));
+                let hash_a = read(a, &mut file_a, &mut buf_a);
+                (hash_a, other.join().unwrap())
+            })
+        } else {
+            (read(a, &mut file_a, &mut buf_a), read(b, &mut file_b, &mut buf_b))
+        };
+        let (hash_a, hash_b) = (hash_a?, hash_b?);
+
+        let start = block_range(index).start;
+        if hash_a != hash_b {
+            let same = buf_a.iter().zip(&buf_b).take_while(|(a, b)| a == b).count();
+            return Ok(Diff::Block { index, byte: start + same as u64 });
+        }
+        if !buf_a.is_empty() {
+            hashes.push(hash_a);
+        }
+        let done = start + buf_a.len() as u64;
+        tracker.set_position(done);
+        if buf_a.len() < BLOCK_SIZE {
+            return Ok(Diff::Same(BlockHashList::from_hashes(done, hashes).unwrap()));
+        }
+        index += 1;
+    }
+}
+
+/// Read the next block into the buffer, returning its hash. The buffer is left empty at the end
+/// of the file.
+fn read_block(file: &mut File, buf: &mut Vec<u8>) -> io::Result<BlockHash> {
+    buf.clear();
+    file.take(BLOCK_SIZE as u64).read_to_end(buf)?;
+    Ok(blocks::hash_block(buf))
+}
@@ -0,0 +1,114 @@
+//! Defaults for command-line options, read from a TOML file, for `--config`.
+//!
+//! The file looks like:
+//!
+//! ```toml
+//! threads = "auto"
+//! progress = "never"
+//! format = "jsonl"
+//! exclude = [".git", "*.tmp"]
+//! ```
+
+use super::{output, parse_threads, progress};
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// The name of the file looked for in the user's configuration directory.
+const FILE_NAME: &str = "dropbox-content-hash.toml";
+
+/// The settings in a configuration file. Anything not set is left as it is.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// The default for `--threads`: a number, or a string such as "auto" or "50%".
+    pub threads: Option<usize>,
+    /// The default for `--progress`.
+    pub progress: Option<progress::Mode>,
+    /// The default for `--format`.
+    pub format: Option<output::Format>,
+    /// The patterns to `--exclude` if none are given on the command line.
+    pub exclude: Vec<String>,
+}
+
+/// Where the configuration file is read from by default:
+/// `$XDG_CONFIG_HOME/dropbox-content-hash.toml`, or `~/.config/dropbox-content-hash.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join(FILE_NAME))
+}
+
+impl Config {
+    /// Read a configuration file. If `optional` is true, a file which doesn't exist is the same
+    /// as an empty one.
+    pub fn read(path: &Path, optional: bool) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if optional && e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read {:?}: {}", path, e)),
+        }
+    }
+
+    /// Parse the contents of a configuration file.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let table = text.parse::<Table>().map_err(|e| e.message().to_owned())?;
+        let mut config = Self::default();
+        for (key, value) in &table {
+            let wrong_type = || format!("{:?} has the wrong type", key);
+            match key.as_str() {
+                "threads" => config.threads = Some(match value {
+                    Value::Integer(n) => (*n).try_into().map_err(|_| wrong_type())?,
+                    Value::String(s) => parse_threads(s)?,
+                    _ => return Err(wrong_type()),
+                }),
+                "progress" => config.progress = Some(value.as_str().ok_or_else(wrong_type)?
+                    .parse()?),
+                "format" => config.format = Some(value.as_str().ok_or_else(wrong_type)?.parse()?),
+                "exclude" => {
+                    config.exclude = value.as_array().ok_or_else(wrong_type)?
+                        .iter()
+                        .map(|pattern| pattern.as_str().map(str::to_owned).ok_or_else(wrong_type))
+                        .collect::<Result<_, _>>()?;
+                }
+                _ => return Err(format!("unknown setting {:?}", key)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse(r#"
+            threads = 3
+            progress = "never"
+            format = "jsonl"
+            exclude = [".git", "*.tmp"]
+        "#).unwrap();
+        assert_eq!(Config {
+            threads: Some(3),
+            progress: Some(progress::Mode::Never),
+            format: Some(output::Format::Jsonl),
+            exclude: vec![".git".to_owned(), "*.tmp".to_owned()],
+        }, config);
+
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+        assert_eq!(Some(parse_threads("auto").unwrap()),
+            Config::parse("threads = \"auto\"").unwrap().threads);
+        assert!(Config::parse("threads = -1").is_err());
+        assert!(Config::parse("format = 5").is_err());
+        assert!(Config::parse("format = \"yaml\"").is_err());
+        assert!(Config::parse("exclude = [1]").is_err());
+        assert!(Config::parse("colour = \"red\"").is_err());
+        assert!(Config::parse("threads = ").is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Comparing two manifests, for the `diff` subcommand.
+
+use super::check::{parse_any_line, MANIFEST_HEADER};
+use dropbox_content_hash::HASH_OUTPUT_SIZE;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+type Manifest = BTreeMap<PathBuf, [u8; HASH_OUTPUT_SIZE]>;
+
+/// How a file differs between two manifests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Difference {
+    /// Only in the second manifest.
+    Added,
+    /// Only in the first manifest.
+    Removed,
+    /// In both, with different content hashes.
+    Changed,
+}
+
+impl Difference {
+    fn name(self) -> &'static str {
+        match self {
+            Difference::Added => "added",
+            Difference::Removed => "removed",
+            Difference::Changed => "changed",
+        }
+    }
+}
+
+/// Compare two manifests (either of which may be standard input, for "-"), printing each file
+/// which was added, removed, or changed in the second, in order by path, and a count of each.
+/// Returns the exit code: 0 if they list the same files with the same hashes, 1 if not.
+pub fn run(a: &Path, b: &Path) -> Result<i32, String> {
+    let a = read(a)?;
+    let b = read(b)?;
+    let differences = diff(&a, &b);
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (path, difference) in &differences {
+        println!("{:<7} {}", difference.name(), path.display());
+        match difference {
+            Difference::Added => added += 1,
+            Difference::Removed => removed += 1,
+            Difference::Changed => changed += 1,
+        }
+    }
+    println!("{} added, {} removed, {} changed, {} unchanged",
+        added, removed, changed, b.len() - added - changed);
+    Ok(if differences.is_empty() { 0 } else { 1 })
+}
+
+/// Read the hashes from a manifest, warning about lines which can't be parsed. If a file is listed
+/// more than once, the last hash for it is used.
+fn read(manifest: &Path) -> Result<Manifest, String> {
+    let failed = |e: io::Error| format!("Failed to read {:?}: {}", manifest, e);
+    let reader: Box<dyn BufRead> = if manifest == Path::new("-") {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(manifest).map_err(failed)?))
+    };
+    let mut hashes = Manifest::new();
+    let mut malformed = 0;
+    let mut with_metadata = false;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(failed)?;
+        if line.is_empty() {
+            continue;
+        }
+        if number == 0 && line == MANIFEST_HEADER {
+            with_metadata = true;
+            continue;
+        }
+        match parse_any_line(&line, with_metadata) {
+            Some((hash, path)) => {
+                hashes.insert(path, hash);
+            }
+            None => malformed += 1,
+        }
+    }
+    if malformed != 0 {
+        eprintln!("WARNING: {}: {} improperly formatted line{} skipped", manifest.display(),
+            malformed, if malformed == 1 { "" } else { "s" });
+    }
+    Ok(hashes)
+}
+
+/// The files which differ between two manifests, in order by path.
+fn diff<'a>(a: &'a Manifest, b: &'a Manifest) -> Vec<(&'a Path, Difference)> {
+    let mut differences = vec![];
+    for (path, hash) in a {
+        match b.get(path) {
+            None => differences.push((path.as_path(), Difference::Removed)),
+            Some(other) if other != hash => differences.push((path.as_path(), Difference::Changed)),
+            Some(_) => (),
+        }
+    }
+    for path in b.keys() {
+        if !a.contains_key(path) {
+            differences.push((path.as_path(), Difference::Added));
+        }
+    }
+    differences.sort_by_key(|(path, _)| *path);
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn differences() {
+        let manifest = |entries: &[(&str, u8)]| -> Manifest {
+            entries.iter()
+                .map(|(path, byte)| (PathBuf::from(path), [*byte; HASH_OUTPUT_SIZE]))
+                .collect()
+        };
+        let a = manifest(&[("a", 1), ("b", 2), ("c", 3), ("e", 5)]);
+        let b = manifest(&[("a", 1), ("c", 4), ("d", 4), ("e", 5), ("f", 6)]);
+        assert_eq!(vec![
+            (Path::new("b"), Difference::Removed),
+            (Path::new("c"), Difference::Changed),
+            (Path::new("d"), Difference::Added),
+            (Path::new("f"), Difference::Added),
+        ], diff(&a, &b));
+        assert!(diff(&a, &a).is_empty());
+    }
+}
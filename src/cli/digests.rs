@@ -0,0 +1,193 @@
+//! Other digests of the same data, computed while it's read for the content hash, for `--also`.
+
+#[cfg(not(target_os = "wasi"))]
+use ring::digest::{Context, SHA1_FOR_LEGACY_USE_ONLY, SHA256, SHA512};
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/// The digests which can be asked for with `--also`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    /// The names accepted by [`FromStr`].
+    pub const NAMES: &'static [&'static str] = &["md5", "sha1", "sha256", "sha512", "blake3"];
+
+    /// The lowercase name, as used in JSON and CSV output.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// The name BSD digest tools use in their tagged lines.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha512 => "SHA512",
+            Algorithm::Blake3 => "BLAKE3",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "md5" if cfg!(not(feature = "md5")) => {
+                Err("MD5 is not available in this build".to_owned())
+            }
+            "blake3" if cfg!(not(feature = "blake3")) => {
+                Err("BLAKE3 is not available in this build".to_owned())
+            }
+            "sha1" if cfg!(target_os = "wasi") => {
+                Err("SHA-1 is not available on WASI".to_owned())
+            }
+            "md5" => Ok(Algorithm::Md5),
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(format!("unknown digest {:?}", s)),
+        }
+    }
+}
+
+enum State {
+    #[cfg(not(target_os = "wasi"))]
+    Ring(Context),
+    #[cfg(target_os = "wasi")]
+    Sha2(Box<dyn sha2::digest::DynDigest>),
+    #[cfg(feature = "md5")]
+    Md5(md5::Md5),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Several digests being computed over the same data.
+pub struct Digests {
+    states: Vec<(Algorithm, State)>,
+}
+
+impl Digests {
+    /// Start computing the given digests.
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        let states = algorithms.iter()
+            .map(|&algorithm| {
+                let state = match algorithm {
+                    #[cfg(feature = "md5")]
+                    Algorithm::Md5 => State::Md5(md5::Digest::new()),
+                    #[cfg(not(target_os = "wasi"))]
+                    Algorithm::Sha1 => State::Ring(Context::new(&SHA1_FOR_LEGACY_USE_ONLY)),
+                    #[cfg(not(target_os = "wasi"))]
+                    Algorithm::Sha256 => State::Ring(Context::new(&SHA256)),
+                    #[cfg(not(target_os = "wasi"))]
+                    Algorithm::Sha512 => State::Ring(Context::new(&SHA512)),
+                    #[cfg(target_os = "wasi")]
+                    Algorithm::Sha256 => State::Sha2(Box::new(sha2::Sha256::default())),
+                    #[cfg(target_os = "wasi")]
+                    Algorithm::Sha512 => State::Sha2(Box::new(sha2::Sha512::default())),
+                    #[cfg(feature = "blake3")]
+                    Algorithm::Blake3 => State::Blake3(Box::default()),
+                    #[allow(unreachable_patterns)]
+                    _ => unreachable!("{:?} isn't available in this build", algorithm),
+                };
+                (algorithm, state)
+            })
+            .collect();
+        Self { states }
+    }
+
+    /// Add more data.
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, state) in &mut self.states {
+            match state {
+                #[cfg(not(target_os = "wasi"))]
+                State::Ring(ctx) => ctx.update(data),
+                #[cfg(target_os = "wasi")]
+                State::Sha2(ctx) => ctx.update(data),
+                #[cfg(feature = "md5")]
+                State::Md5(ctx) => md5::Digest::update(ctx, data),
+                #[cfg(feature = "blake3")]
+                State::Blake3(hasher) => {
+                    hasher.update(data);
+                }
+            }
+        }
+    }
+
+    /// Finish computing the digests, returning them in the order they were asked for.
+    pub fn finish(self) -> Vec<(Algorithm, Vec<u8>)> {
+        self.states.into_iter()
+            .map(|(algorithm, state)| {
+                let digest = match state {
+                    #[cfg(not(target_os = "wasi"))]
+                    State::Ring(ctx) => ctx.finish().as_ref().to_vec(),
+                    #[cfg(target_os = "wasi")]
+                    State::Sha2(ctx) => ctx.finalize().to_vec(),
+                    #[cfg(feature = "md5")]
+                    State::Md5(ctx) => md5::Digest::finalize(ctx).to_vec(),
+                    #[cfg(feature = "blake3")]
+                    State::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+                };
+                (algorithm, digest)
+            })
+            .collect()
+    }
+}
+
+/// Adds everything read through it to the digests.
+pub struct DigestReader<'a, R> {
+    pub inner: R,
+    pub digests: &'a mut Digests,
+}
+
+impl<R: Read> Read for DigestReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digests.update(&buf[.. n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dropbox_content_hash::hex_string;
+
+    #[test]
+    fn digests() {
+        let mut algorithms = vec![Algorithm::Sha1, Algorithm::Sha256];
+        if cfg!(feature = "md5") {
+            algorithms.push(Algorithm::Md5);
+        }
+        let mut digests = Digests::new(&algorithms);
+        let mut reader = DigestReader { inner: &b"abc"[..], digests: &mut digests };
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let digests = digests.finish()
+            .into_iter()
+            .map(|(algorithm, digest)| (algorithm, hex_string(&digest)))
+            .collect::<Vec<_>>();
+        assert_eq!((Algorithm::Sha1, "a9993e364706816aba3e25717850c26c9cd0d89d".to_owned()),
+            digests[0]);
+        assert_eq!((Algorithm::Sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()),
+            digests[1]);
+        if cfg!(feature = "md5") {
+            assert_eq!((Algorithm::Md5, "900150983cd24fb0d6963f7d28e17f72".to_owned()),
+                digests[2]);
+        }
+    }
+}
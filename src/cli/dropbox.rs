@@ -0,0 +1,231 @@
+//! Looking up content hashes of files stored in Dropbox, using the Dropbox API.
+
+use dropbox_content_hash::{parse_hex, HASH_OUTPUT_SIZE};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::jobs::map_ordered;
+use super::progress::Progress;
+use super::walk::Walker;
+use super::Hashed;
+
+const API_URL: &str = "https://api.dropboxapi.com/2";
+
+/// A file's metadata, as returned by the API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    /// The path, with the case as stored in Dropbox.
+    pub path_display: String,
+    /// The size in bytes.
+    pub size: u64,
+    /// The content hash.
+    pub content_hash: [u8; HASH_OUTPUT_SIZE],
+}
+
+/// Makes API calls with an access token.
+pub struct Client {
+    token: String,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    /// Make a client which uses the given OAuth 2 access token.
+    pub fn new(token: String) -> Self {
+        Self { token, agent: ureq::Agent::new() }
+    }
+
+    /// Get the metadata of the file at the given path, such as "/Photos/cat.jpg".
+    pub fn file_metadata(&self, path: &str) -> Result<FileMetadata, String> {
+        let response = self.call("files/get_metadata", json!({ "path": path }))?;
+        match response[".tag"].as_str() {
+            Some("file") => parse_file_metadata(&response)
+                .ok_or_else(|| format!("Malformed metadata for {:?} from Dropbox", path)),
+            Some(tag) => Err(format!("{:?} in Dropbox is a {}, not a file", path, tag)),
+            None => Err(format!("Malformed metadata for {:?} from Dropbox", path)),
+        }
+    }
+
+    /// List every file in the given folder and its subfolders. The folder is "" for the root.
+    pub fn list_files(&self, folder: &str) -> Result<Vec<FileMetadata>, String> {
+        let mut files = vec![];
+        let mut response = self.call("files/list_folder",
+            json!({ "path": folder, "recursive": true }))?;
+        loop {
+            let entries = response["entries"].as_array()
+                .ok_or_else(|| format!("Malformed listing of {:?} from Dropbox", folder))?;
+            for entry in entries.iter().filter(|entry| entry[".tag"] == "file") {
+                files.push(parse_file_metadata(entry)
+                    .ok_or_else(|| format!("Malformed metadata in {:?} from Dropbox", folder))?);
+            }
+            if response["has_more"] != true {
+                return Ok(files);
+            }
+            let cursor = response["cursor"].clone();
+            response = self.call("files/list_folder/continue", json!({ "cursor": cursor }))?;
+        }
+    }
+
+    /// Call an RPC-style API endpoint, returning the result.
+    fn call(&self, endpoint: &str, arg: Value) -> Result<Value, String> {
+        let result = self.agent.post(&format!("{}/{}", API_URL, endpoint))
+            .set("Authorization", &format!("Bearer {}", self.token))
+            .send_json(arg);
+        match result {
+            Ok(response) => response.into_json()
+                .map_err(|e| format!("Failed to read response from Dropbox: {}", e)),
+            Err(ureq::Error::Status(status, response)) => {
+                // Errors for a bad request are JSON with a summary; others are plain text.
+                let body = response.into_string().unwrap_or_default();
+                let message = serde_json::from_str::<Value>(&body).ok()
+                    .and_then(|error| error["error_summary"].as_str().map(str::to_owned))
+                    .unwrap_or(body);
+                Err(format!("Dropbox API error {} from {}: {}", status, endpoint, message.trim()))
+            }
+            Err(e) => Err(format!("Failed to call the Dropbox API: {}", e)),
+        }
+    }
+}
+
+/// Compare the files in a local directory with those in a Dropbox folder and its subfolders,
+/// printing the status of each file, in order by path:
+///
+/// * `PATH: OK` if the content hashes match
+/// * `PATH: FAILED` if they don't
+/// * `PATH: FAILED open or read` if the local file couldn't be hashed
+/// * `PATH: missing locally` if it's only in Dropbox
+/// * `PATH: not in Dropbox` if it's only in the local directory
+///
+/// Paths are compared ignoring case, as Dropbox does. Local files are hashed using the given
+/// function, `jobs` at a time. Returns the exit code: 0 if every file is OK, 1 if not, or 2 if
+/// the Dropbox folder couldn't be listed.
+pub fn compare(
+    client: &Client,
+    local_dir: &Path,
+    folder: &str,
+    jobs: usize,
+    progress: &Progress,
+    hash: impl Fn(&Path) -> Result<Hashed, String> + Sync,
+) -> i32 {
+    let folder = folder.trim_end_matches('/');
+    let remote = match client.list_files(folder) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+
+    // Keyed by the lowercased relative path.
+    let mut entries = BTreeMap::<String, Entry>::new();
+    for file in remote {
+        let relative = relative_remote_path(folder, &file.path_display);
+        let entry = entries.entry(relative.to_lowercase()).or_default();
+        entry.display = relative;
+        entry.remote = Some(file.content_hash);
+    }
+    let mut failed = false;
+    Walker::new(&[], &[]).unwrap().walk(local_dir, |file| match file {
+        Ok(path) => {
+            let relative = path.strip_prefix(local_dir).unwrap_or(path);
+            let relative = relative.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let entry = entries.entry(relative.to_lowercase()).or_default();
+            entry.display = relative;
+            entry.local = Some(path.to_owned());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            failed = true;
+        }
+    });
+
+    let mut counts = BTreeMap::<&str, usize>::new();
+    map_ordered(
+        jobs,
+        |send| entries.into_values().for_each(send),
+        |entry| {
+            let status = match (&entry.local, &entry.remote) {
+                (Some(local), Some(remote)) => match hash(local) {
+                    Ok(hashed) if &hashed.hash == remote => "OK",
+                    Ok(_) => "FAILED",
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        "FAILED open or read"
+                    }
+                },
+                (None, _) => "missing locally",
+                (_, None) => "not in Dropbox",
+            };
+            (entry.display, status)
+        },
+        |(display, status)| {
+            progress.suspend(|| println!("{}: {}", display, status));
+            *counts.entry(status).or_default() += 1;
+        });
+
+    for (status, count) in &counts {
+        if *status != "OK" {
+            eprintln!("WARNING: {} {}: {}", count, if *count == 1 { "file" } else { "files" },
+                status);
+            failed = true;
+        }
+    }
+    if failed { 1 } else { 0 }
+}
+
+/// The path of a file in Dropbox relative to the folder containing it, which has no trailing
+/// slash.
+fn relative_remote_path(folder: &str, path: &str) -> String {
+    let skip = folder.split('/').count();
+    path.split('/').skip(skip).collect::<Vec<_>>().join("/")
+}
+
+/// A file in the local directory, Dropbox, or both.
+#[derive(Default)]
+struct Entry {
+    /// The path relative to the directory, with the case from the local file if there is one.
+    display: String,
+    local: Option<PathBuf>,
+    remote: Option<[u8; HASH_OUTPUT_SIZE]>,
+}
+
+fn parse_file_metadata(value: &Value) -> Option<FileMetadata> {
+    Some(FileMetadata {
+        path_display: value["path_display"].as_str()?.to_owned(),
+        size: value["size"].as_u64()?,
+        content_hash: parse_hex(value["content_hash"].as_str()?).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_metadata() {
+        let hash = "ab".repeat(HASH_OUTPUT_SIZE);
+        let value = json!({
+            ".tag": "file",
+            "name": "cat.jpg",
+            "path_display": "/Photos/cat.jpg",
+            "size": 1234,
+            "content_hash": hash,
+        });
+        assert_eq!(Some(FileMetadata {
+            path_display: "/Photos/cat.jpg".to_owned(),
+            size: 1234,
+            content_hash: [0xab; HASH_OUTPUT_SIZE],
+        }), parse_file_metadata(&value));
+        assert_eq!(None, parse_file_metadata(&json!({ "path_display": "/a", "size": 1 })));
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!("cat.jpg", relative_remote_path("", "/cat.jpg"));
+        assert_eq!("2020/cat.jpg", relative_remote_path("/Photos", "/Photos/2020/cat.jpg"));
+        assert_eq!("cat.jpg", relative_remote_path("/photos/2020", "/Photos/2020/cat.jpg"));
+    }
+}
@@ -0,0 +1,191 @@
+//! Finding files with the same contents, for the `dupes` subcommand.
+
+use super::jobs::map_ordered;
+use super::walk::Walker;
+use super::Hashed;
+use dropbox_content_hash::{hex_string, HASH_OUTPUT_SIZE};
+use indicatif::HumanBytes;
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use dropbox_content_hash::trace::debug;
+
+/// A set of files with the same contents.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Set {
+    /// The content hash.
+    pub hash: [u8; HASH_OUTPUT_SIZE],
+    /// The size of each file.
+    pub size: u64,
+    /// In the order they were found.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Set {
+    /// The space that would be freed by keeping only one of the files.
+    pub fn reclaimable(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// Find the files under the given directories with the same contents, and print each set of them
+/// with their content hash, most reclaimable space first, followed by the total.
+///
+/// Only files which are the same size as another are hashed, on `jobs` threads. Empty files are
+/// skipped, as are extra hard links to a file already found. Returns the exit code: 0, or 2 if any
+/// file couldn't be read.
+pub fn run(
+    dirs: &[PathBuf],
+    walker: &Walker,
+    jobs: usize,
+    hash: impl Fn(&Path) -> Result<Hashed, String> + Sync,
+) -> i32 {
+    let mut code = 0;
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    for dir in dirs {
+        walker.walk(dir, |path| {
+            let (path, meta) = match path.map(|path| (path, path.metadata())) {
+                Ok((path, Ok(meta))) => (path, meta),
+                Ok((path, Err(e))) => {
+                    eprintln!("{}: {}", path.display(), e);
+                    code = 2;
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    code = 2;
+                    return;
+                }
+            };
+            if meta.len() == 0 {
+                return;
+            }
+            if let Some(id) = file_id(&meta) {
+                if !seen.insert(id) {
+                    debug!(path = %path.display(), "skipping another link to a file already found");
+                    return;
+                }
+            }
+            files.push((path.to_owned(), meta.len()));
+        });
+    }
+
+    let mut hashed = vec![];
+    map_ordered(
+        jobs,
+        |send| same_size(files).into_iter().for_each(send),
+        |path: PathBuf| {
+            let result = hash(&path);
+            (path, result)
+        },
+        |(path, result)| match result {
+            Ok(Hashed { hash, size, .. }) => hashed.push((path, size, hash)),
+            Err(e) => {
+                eprintln!("{}", e);
+                code = 2;
+            }
+        });
+
+    let sets = sets(hashed);
+    for set in &sets {
+        println!("{}  {} each, {} reclaimable", hex_string(&set.hash), HumanBytes(set.size),
+            HumanBytes(set.reclaimable()));
+        for path in &set.paths {
+            println!("  {}", path.display());
+        }
+    }
+    let total = sets.iter().map(Set::reclaimable).sum::<u64>();
+    let files = sets.iter().map(|set| set.paths.len() - 1).sum::<usize>();
+    println!("{} duplicate file{} in {} set{}, {} ({} bytes) reclaimable",
+        files, if files == 1 { "" } else { "s" },
+        sets.len(), if sets.len() == 1 { "" } else { "s" },
+        HumanBytes(total), total);
+    code
+}
+
+/// The paths of the files which are the same size as at least one other, in the same order.
+fn same_size(files: Vec<(PathBuf, u64)>) -> Vec<PathBuf> {
+    let mut counts = HashMap::<u64, usize>::new();
+    for (_, size) in &files {
+        *counts.entry(*size).or_default() += 1;
+    }
+    files.into_iter()
+        .filter(|(_, size)| counts[size] > 1)
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// Group hashed files into sets with the same size and content hash, leaving out files with no
+/// duplicates, and sort them by how much space they'd free, most first.
+fn sets(hashed: Vec<(PathBuf, u64, [u8; HASH_OUTPUT_SIZE])>) -> Vec<Set> {
+    let mut sets = Vec::<Set>::new();
+    let mut index = HashMap::<_, usize>::new();
+    for (path, size, hash) in hashed {
+        match index.get(&(size, hash)) {
+            Some(&i) => sets[i].paths.push(path),
+            None => {
+                index.insert((size, hash), sets.len());
+                sets.push(Set { hash, size, paths: vec![path] });
+            }
+        }
+    }
+    sets.retain(|set| set.paths.len() > 1);
+    // Stable, so sets freeing the same space stay in the order they were found.
+    sets.sort_by_key(|set| std::cmp::Reverse(set.reclaimable()));
+    sets
+}
+
+/// Something identifying a file, so hard links to it can be recognized.
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grouping() {
+        let files = vec![
+            (PathBuf::from("a"), 10),
+            (PathBuf::from("b"), 20),
+            (PathBuf::from("c"), 10),
+            (PathBuf::from("d"), 30),
+            (PathBuf::from("e"), 20),
+            (PathBuf::from("f"), 10),
+        ];
+        assert_eq!(["a", "b", "c", "e", "f"].iter().map(PathBuf::from).collect::<Vec<_>>(),
+            same_size(files));
+
+        let hashed = vec![
+            (PathBuf::from("a"), 10, [1; HASH_OUTPUT_SIZE]),
+            (PathBuf::from("b"), 20, [2; HASH_OUTPUT_SIZE]),
+            (PathBuf::from("c"), 10, [3; HASH_OUTPUT_SIZE]),
+            (PathBuf::from("e"), 20, [2; HASH_OUTPUT_SIZE]),
+            (PathBuf::from("f"), 10, [1; HASH_OUTPUT_SIZE]),
+            (PathBuf::from("g"), 10, [1; HASH_OUTPUT_SIZE]),
+        ];
+        let sets = sets(hashed);
+        assert_eq!(vec![
+            Set {
+                hash: [1; HASH_OUTPUT_SIZE],
+                size: 10,
+                paths: vec![PathBuf::from("a"), PathBuf::from("f"), PathBuf::from("g")],
+            },
+            Set {
+                hash: [2; HASH_OUTPUT_SIZE],
+                size: 20,
+                paths: vec![PathBuf::from("b"), PathBuf::from("e")],
+            },
+        ], sets);
+        assert_eq!(20, sets[0].reclaimable());
+    }
+}
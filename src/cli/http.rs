@@ -0,0 +1,17 @@
+//! Downloading files to hash from HTTP and HTTPS URLs.
+
+use std::io::Read;
+
+/// Start downloading a URL, returning the response body, and its length if the server gave one.
+pub fn get(url: &str) -> Result<(Box<dyn Read + Send>, Option<u64>), String> {
+    match ureq::get(url).call() {
+        Ok(response) => {
+            let len = response.header("Content-Length").and_then(|len| len.parse().ok());
+            Ok((response.into_reader(), len))
+        }
+        Err(ureq::Error::Status(status, response)) => {
+            Err(format!("{}: HTTP {} {}", url, status, response.status_text()))
+        }
+        Err(e) => Err(format!("{}: {}", url, e)),
+    }
+}
@@ -0,0 +1,89 @@
+//! Keeping a database of the content hashes of the files in directory trees up to date, for the
+//! `index` subcommand.
+
+use super::watch::{self, Change};
+use super::walk::Walker;
+use super::Hashed;
+use dropbox_content_hash::blocks::BlockHashList;
+use dropbox_content_hash::cache::{Cache, Entry};
+use std::path::{Path, PathBuf};
+use dropbox_content_hash::trace::{info, warn};
+
+/// Add every file under the given directories which isn't in the cache (or has changed since it
+/// was added) to it, then watch them and update the cache whenever files change or are removed,
+/// until watching them fails.
+pub fn run(
+    cache: &Cache,
+    dirs: &[PathBuf],
+    walker: &Walker,
+    hash: impl Fn(&Path) -> Result<Hashed, String>,
+) -> Result<(), String> {
+    let pruned = cache.prune().map_err(|e| e.to_string())?;
+    info!(pruned, "removed entries for files which changed or no longer exist");
+
+    let mut hashed = 0;
+    let mut current = 0;
+    for dir in dirs {
+        walker.walk(dir, |path| match path {
+            Ok(path) => match cache.lookup(path) {
+                Ok(Some(_)) => current += 1,
+                Ok(None) => {
+                    if update(cache, path, &hash) {
+                        hashed += 1;
+                    }
+                }
+                Err(e) => warn!(path = %path.display(), "{}", e),
+            },
+            Err(e) => warn!("{}", e),
+        });
+    }
+    eprintln!("Indexed {} file{} ({} already up to date); watching for changes",
+        hashed, if hashed == 1 { "" } else { "s" }, current);
+
+    watch::watch(dirs, true, walker, |change| match change {
+        Change::Modified(path) => {
+            update(cache, path, &hash);
+        }
+        Change::Removed(path) => {
+            info!(path = %path.display(), "removing from index");
+            if let Err(e) = cache.remove(path) {
+                warn!(path = %path.display(), "{}", e);
+            }
+        }
+    })
+    .map_err(|e| format!("Failed to watch for changes: {}", e))
+}
+
+/// Hash a file and store it in the cache, returning whether that worked.
+fn update(cache: &Cache, path: &Path, hash: impl Fn(&Path) -> Result<Hashed, String>) -> bool {
+    // Before hashing, so the entry doesn't match the file if it changes in the meantime.
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            warn!(path = %path.display(), "{}", e);
+            return false;
+        }
+    };
+    let hashed = match hash(path) {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            warn!(path = %path.display(), "{}", e);
+            return false;
+        }
+    };
+    let size = hashed.size;
+    let entry = Entry {
+        content_hash: hashed.hash,
+        blocks: hashed.blocks.and_then(|blocks| BlockHashList::from_hashes(size, blocks)),
+    };
+    match cache.insert(path, &metadata, &entry) {
+        Ok(()) => {
+            info!(path = %path.display(), "indexed");
+            true
+        }
+        Err(e) => {
+            warn!(path = %path.display(), "{}", e);
+            false
+        }
+    }
+}
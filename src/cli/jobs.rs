@@ -0,0 +1,92 @@
+//! Hashing several files at once, for `--jobs`.
+
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+/// Run `work` on each item passed to the callback given to `items`, on `jobs` threads, and pass
+/// the results to `done` in the same order as the items.
+///
+/// `items` and `done` run on separate threads, so listing the items isn't held up by printing the
+/// results, and vice versa. With one job, everything runs on the calling thread instead, one item
+/// at a time.
+pub fn map_ordered<T: Send, R: Send>(
+    jobs: usize,
+    items: impl FnOnce(&mut dyn FnMut(T)),
+    work: impl Fn(T) -> R + Sync,
+    mut done: impl FnMut(R) + Send,
+) {
+    if jobs <= 1 {
+        items(&mut |item| done(work(item)));
+        return;
+    }
+
+    // Don't let the items get too far ahead of the workers.
+    let (item_tx, item_rx) = mpsc::sync_channel::<(usize, T)>(jobs);
+    let item_rx = Mutex::new(item_rx);
+    let (result_tx, result_rx) = mpsc::channel::<(usize, R)>();
+
+    thread::scope(|scope| {
+        for _ in 0 .. jobs {
+            let result_tx = result_tx.clone();
+            let item_rx = &item_rx;
+            let work = &work;
+            scope.spawn(move || loop {
+                let next = item_rx.lock().unwrap().recv();
+                match next {
+                    Ok((index, item)) => {
+                        if result_tx.send((index, work(item))).is_err() {
+                            break;
+                        }
+                    }
+                    Err(mpsc::RecvError) => break,
+                }
+            });
+        }
+        drop(result_tx);
+
+        scope.spawn(move || {
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (index, result) in result_rx {
+                pending.insert(index, result);
+                while let Some(result) = pending.remove(&next) {
+                    done(result);
+                    next += 1;
+                }
+            }
+        });
+
+        let mut index = 0;
+        items(&mut |item| {
+            // This only fails if every worker has panicked, which the scope will report.
+            let _ = item_tx.send((index, item));
+            index += 1;
+        });
+        // Let the workers finish.
+        drop(item_tx);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn in_order() {
+        for &jobs in &[1, 4] {
+            let mut results = vec![];
+            map_ordered(
+                jobs,
+                |send| (0 .. 20u64).for_each(send),
+                |i| {
+                    // Make later items tend to finish first.
+                    thread::sleep(Duration::from_millis(20 - i));
+                    i * 2
+                },
+                |result| results.push(result));
+            assert_eq!((0 .. 20).map(|i| i * 2).collect::<Vec<_>>(), results, "jobs={}", jobs);
+        }
+    }
+}
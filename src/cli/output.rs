@@ -0,0 +1,672 @@
+//! Printing results in the various output formats.
+
+use super::check::{format_mtime, MANIFEST_HEADER};
+use super::digests::Algorithm;
+use super::Hashed;
+use dropbox_content_hash::{hex_string, HASH_OUTPUT_SIZE};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// The output formats, as given to `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Lines of text, as chosen by [`PlainStyle`].
+    Plain,
+    /// A JSON array with an object for each file.
+    Json,
+    /// A JSON object for each file, one per line, printed as soon as each file is done.
+    Jsonl,
+    /// Comma-separated values, with a header row.
+    Csv,
+    /// The audit file format of hashdeep: a header, then the size, content hash, and path of each
+    /// file.
+    Hashdeep,
+}
+
+impl Format {
+    /// The names accepted by [`FromStr`].
+    pub const NAMES: &'static [&'static str] = &["plain", "json", "jsonl", "csv", "hashdeep"];
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "hashdeep" => Ok(Format::Hashdeep),
+            _ => Err(format!("unknown format {:?}", s)),
+        }
+    }
+}
+
+/// How hashes are written, as given to `--encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Lowercase hexadecimal.
+    Hex,
+    /// Uppercase hexadecimal.
+    UpperHex,
+    /// Base64 with the standard alphabet and padding.
+    Base64,
+    /// Base64 with the URL-safe alphabet ("-" and "_" instead of "+" and "/"), and no padding.
+    Base64Url,
+}
+
+impl Encoding {
+    /// The names accepted by [`FromStr`].
+    pub const NAMES: &'static [&'static str] = &["hex", "HEX", "base64", "base64url"];
+
+    /// Encode a hash.
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Hex => hex_string(bytes),
+            Encoding::UpperHex => hex_string(bytes).to_ascii_uppercase(),
+            Encoding::Base64 => base64(bytes, BASE64, true),
+            Encoding::Base64Url => base64(bytes, BASE64_URL, false),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "hex" => Ok(Encoding::Hex),
+            "HEX" => Ok(Encoding::UpperHex),
+            "base64" => Ok(Encoding::Base64),
+            "base64url" => Ok(Encoding::Base64Url),
+            _ => Err(format!("unknown encoding {:?}", s)),
+        }
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+fn base64(bytes: &[u8], alphabet: &[u8; 64], pad: bool) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate()
+            .fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+        for i in 0 ..= chunk.len() {
+            out.push(alphabet[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+        if pad {
+            for _ in chunk.len() .. 3 {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// How each file's line looks in the plain format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlainStyle {
+    /// Just the hash.
+    Bare,
+    /// `HASH  PATH`, like sha256sum.
+    WithPath,
+    /// `DropboxContentHash (PATH) = HASH`, like BSD digest tools.
+    Tag,
+    /// `HASH  PATH` like `rclone hashsum dropbox`, with the path relative to the root given to
+    /// [`Output::root`] and "/" between its components, and `ERROR` in place of the hash of any
+    /// file which couldn't be read.
+    Rclone,
+    /// `HASH  SIZE  MTIME  PATH`, after [`MANIFEST_HEADER`], for `--manifest`.
+    Manifest,
+}
+
+/// How paths are written, as chosen by `--absolute` and `--relative-to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Paths {
+    /// As they were given or found.
+    AsGiven,
+    /// Made absolute by joining them to the current directory, and removing ".." components along
+    /// with the ones before them, without resolving symbolic links.
+    Absolute,
+    /// Relative to the given absolute path of a directory, using ".." where needed.
+    RelativeTo(PathBuf),
+}
+
+impl Paths {
+    /// A path as it should be written.
+    pub fn apply<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let absolute = match self {
+            Paths::AsGiven => return Cow::Borrowed(path),
+            _ => match absolute_path(path) {
+                Ok(absolute) => absolute,
+                Err(_) => return Cow::Borrowed(path),
+            },
+        };
+        match self {
+            Paths::RelativeTo(base) => Cow::Owned(relative_path(&absolute, base)),
+            _ => Cow::Owned(absolute),
+        }
+    }
+}
+
+/// A path made absolute as described for [`Paths::Absolute`].
+pub fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let mut absolute = PathBuf::new();
+    for part in std::path::absolute(path)?.components() {
+        match part {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            part => absolute.push(part),
+        }
+    }
+    Ok(absolute)
+}
+
+/// The path to get from one absolute path to another. If they have nothing in common (on
+/// different drives on Windows), that's just the path itself.
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    let mut path_parts = path.components().peekable();
+    let mut base_parts = base.components().peekable();
+    let mut common = 0;
+    while path_parts.peek().is_some() && path_parts.peek() == base_parts.peek() {
+        path_parts.next();
+        base_parts.next();
+        common += 1;
+    }
+    if common == 0 {
+        return path.to_owned();
+    }
+    let relative = base_parts.map(|_| Component::ParentDir).chain(path_parts).collect::<PathBuf>();
+    if relative.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        relative
+    }
+}
+
+/// Writes results to standard output or a file as they come in.
+pub struct Output {
+    out: Destination,
+    format: Format,
+    style: PlainStyle,
+    encoding: Encoding,
+    /// The number of `--also` digests, which failed files leave blank in the CSV format.
+    also: usize,
+    root: PathBuf,
+    zero: bool,
+    paths: Paths,
+    count: usize,
+}
+
+impl Output {
+    /// Start the output, writing anything that has to come before the first file, to the given
+    /// file or to standard output.
+    /// The `also` digests are the ones asked for with `--also`, which need columns in some formats.
+    pub fn new(
+        format: Format,
+        style: PlainStyle,
+        also: &[Algorithm],
+        file: Option<AtomicFile>,
+    ) -> io::Result<Self> {
+        let mut out = match file {
+            Some(file) => Destination::File(file),
+            None => Destination::Stdout(io::stdout()),
+        };
+        let appending = matches!(&out, Destination::File(file) if file.appending);
+        match format {
+            Format::Json => write!(out, "[")?,
+            // Appending to a file which already has a header.
+            Format::Csv | Format::Hashdeep if appending => (),
+            Format::Csv => {
+                write!(out, "path,size,content_hash,")?;
+                for algorithm in also {
+                    write!(out, "{},", algorithm.name())?;
+                }
+                writeln!(out, "duration_ms,error")?;
+            }
+            Format::Hashdeep => hashdeep_header(&mut out, also)?,
+            Format::Plain if style == PlainStyle::Manifest && !appending => {
+                writeln!(out, "{}", MANIFEST_HEADER)?
+            }
+            _ => (),
+        }
+        out.flush()?;
+        Ok(Self {
+            out,
+            format,
+            style,
+            encoding: Encoding::Hex,
+            also: also.len(),
+            root: PathBuf::new(),
+            zero: false,
+            paths: Paths::AsGiven,
+            count: 0,
+        })
+    }
+
+    /// Write hashes in the given encoding, instead of lowercase hexadecimal.
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// In the plain format, end each line with a NUL byte instead of a newline, and don't escape
+    /// paths.
+    pub fn zero(mut self, zero: bool) -> Self {
+        self.zero = zero;
+        self
+    }
+
+    /// Change how paths are written.
+    pub fn paths(mut self, paths: Paths) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// The file or directory that paths are written relative to in [`PlainStyle::Rclone`].
+    pub fn root(mut self, root: &Path) -> Self {
+        self.root = root.to_owned();
+        self
+    }
+
+    /// Write the result of hashing a file, which took the given time. Errors are printed to
+    /// standard error, and also included in formats which have a place for them.
+    pub fn record(
+        &mut self,
+        path: &Path,
+        result: &Result<Hashed, String>,
+        elapsed: Duration,
+    ) -> io::Result<()> {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+        }
+        let path = &*self.paths.apply(path);
+        match self.format {
+            Format::Plain => match result {
+                Ok(hashed) => self.plain(path, hashed)?,
+                Err(_) if self.style == PlainStyle::Rclone => {
+                    // Lined up with the hashes, as rclone does.
+                    let width = self.encoding.encode(&[0; HASH_OUTPUT_SIZE]).len();
+                    write!(self.out, "{:>width$}  {}{}", "ERROR", rclone_path(&self.root, path),
+                        if self.zero { '\0' } else { '\n' }, width = width)?;
+                }
+                Err(_) => (),
+            },
+            Format::Json => {
+                writeln!(self.out, "{}", if self.count == 0 { "" } else { "," })?;
+                write!(self.out, "{}", json_object(path, result, self.encoding))?;
+            }
+            Format::Jsonl => writeln!(self.out, "{}", json_object(path, result, self.encoding))?,
+            Format::Csv => {
+                writeln!(self.out, "{}",
+                    csv_row(path, result, self.also, elapsed, self.encoding))?;
+            }
+            Format::Hashdeep => {
+                if let Ok(hashed) = result {
+                    write!(self.out, "{},{},", hashed.size, self.encoding.encode(&hashed.hash))?;
+                    for (_, digest) in &hashed.also {
+                        write!(self.out, "{},", self.encoding.encode(digest))?;
+                    }
+                    writeln!(self.out, "{}", path.display())?;
+                }
+            }
+        }
+        self.count += 1;
+        self.out.flush()
+    }
+
+    fn plain(&mut self, path: &Path, hashed: &Hashed) -> io::Result<()> {
+        let end = if self.zero { '\0' } else { '\n' };
+        for (i, block) in hashed.blocks.iter().flatten().enumerate() {
+            write!(self.out, "block {}: {}{}", i, self.encoding.encode(block), end)?;
+        }
+        let hash = self.encoding.encode(&hashed.hash);
+        // Each line is on its own with -z, so names don't need escaping.
+        let (escaped, name) = match super::check::escape(path) {
+            (true, _) if self.zero => (false, path.to_string_lossy().into_owned()),
+            escaped => escaped,
+        };
+        let prefix = if escaped { "\\" } else { "" };
+        for (algorithm, digest) in &hashed.also {
+            let digest = self.encoding.encode(digest);
+            if self.style == PlainStyle::Tag {
+                write!(self.out, "{}{} ({}) = {}{}", prefix, algorithm.tag(), name, digest, end)?;
+            } else {
+                write!(self.out, "{}: {}{}", algorithm.name(), digest, end)?;
+            }
+        }
+        match self.style {
+            PlainStyle::Bare => write!(self.out, "{}{}", hash, end),
+            PlainStyle::WithPath => write!(self.out, "{}{}  {}{}", prefix, hash, name, end),
+            PlainStyle::Tag => {
+                write!(self.out, "{}{} ({}) = {}{}", prefix, super::check::TAG, name, hash, end)
+            }
+            PlainStyle::Rclone => {
+                write!(self.out, "{}  {}{}", hash, rclone_path(&self.root, path), end)
+            }
+            PlainStyle::Manifest => {
+                let mtime = hashed.mtime.map_or_else(|| "-".to_owned(), format_mtime);
+                write!(self.out, "{}{}  {}  {}  {}{}", prefix, hash, hashed.size, mtime, name, end)
+            }
+        }
+    }
+
+    /// Write anything that has to come after the last file, and if it's going to a file, put the
+    /// file in place.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.format == Format::Json {
+            writeln!(self.out, "{}]", if self.count == 0 { "" } else { "\n" })?;
+        }
+        match self.out {
+            Destination::Stdout(mut stdout) => stdout.flush(),
+            Destination::File(file) => file.commit(),
+        }
+    }
+}
+
+enum Destination {
+    Stdout(io::Stdout),
+    File(AtomicFile),
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::Stdout(stdout) => stdout.write(buf),
+            Destination::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::Stdout(stdout) => stdout.flush(),
+            // Only flushed when it's committed, as it isn't visible until then anyway.
+            Destination::File(_) => Ok(()),
+        }
+    }
+}
+
+/// A file which is written under a unique temporary name next to it, and renamed into place once
+/// it's complete, so it's never left half-written, and an existing file is only replaced if
+/// writing the new one finishes. If it's dropped without being committed, the temporary file is
+/// removed.
+pub struct AtomicFile {
+    path: PathBuf,
+    file: BufWriter<NamedTempFile>,
+    /// Whether it started with the contents of an existing file.
+    appending: bool,
+}
+
+impl AtomicFile {
+    /// Start writing the file. With `append`, it starts with a copy of the existing file, if
+    /// there is one.
+    pub fn create(path: &Path, append: bool) -> io::Result<Self> {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut prefix = OsString::from(".");
+        prefix.push(path.file_name().unwrap_or_default());
+        prefix.push(".");
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix).suffix(".tmp");
+        // The same permissions as a file made with File::create, rather than only the owner's.
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+        let mut file = BufWriter::new(builder.tempfile_in(dir)?);
+        let mut appending = false;
+        if append {
+            match File::open(path) {
+                Ok(mut existing) => {
+                    appending = io::copy(&mut existing, &mut file)? != 0;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Self { path: path.to_owned(), file, appending })
+    }
+
+    /// Finish writing the file, and replace the existing one with it.
+    pub fn commit(self) -> io::Result<()> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.as_file().sync_all()?;
+        file.persist(&self.path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A path as `rclone hashsum` would show it: relative to the root, with "/" between components.
+/// A file given as the root itself is shown by its name.
+fn rclone_path(root: &Path, path: &Path) -> String {
+    match path.strip_prefix(root) {
+        Ok(relative) if relative != Path::new("") => relative.iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        _ => path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned(),
+    }
+}
+
+/// The header hashdeep writes at the start of an audit file, naming the columns and saying how
+/// the file was made.
+fn hashdeep_header(out: &mut impl Write, also: &[Algorithm]) -> io::Result<()> {
+    writeln!(out, "%%%% HASHDEEP-1.0")?;
+    write!(out, "%%%% size,{},", HASHDEEP_ALGORITHM)?;
+    for algorithm in also {
+        write!(out, "{},", algorithm.name())?;
+    }
+    writeln!(out, "filename")?;
+    writeln!(out, "## Invoked from: {}",
+        std::env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default())?;
+    let args = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    writeln!(out, "## $ {}", args.join(" "))?;
+    writeln!(out, "##")
+}
+
+/// The name of the hash in hashdeep's header, which has no name of its own for it.
+const HASHDEEP_ALGORITHM: &str = "dropbox";
+
+/// A JSON object describing a file's hash, or the error hashing it.
+pub fn json_object(path: &Path, result: &Result<Hashed, String>, encoding: Encoding) -> String {
+    let path = json_string(&path.to_string_lossy());
+    match result {
+        Ok(hashed) => {
+            let mut obj = format!("{{\"path\":{},\"size\":{},\"content_hash\":\"{}\"",
+                path, hashed.size, encoding.encode(&hashed.hash));
+            for (algorithm, digest) in &hashed.also {
+                obj += &format!(",\"{}\":\"{}\"", algorithm.name(), encoding.encode(digest));
+            }
+            if let Some(blocks) = &hashed.blocks {
+                let blocks = blocks.iter()
+                    .map(|block| format!("\"{}\"", encoding.encode(block)))
+                    .collect::<Vec<_>>();
+                obj += &format!(",\"blocks\":[{}]", blocks.join(","));
+            }
+            obj + "}"
+        }
+        Err(e) => format!("{{\"path\":{},\"error\":{}}}", path, json_string(e)),
+    }
+}
+
+/// Quote and escape a string for JSON.
+pub fn json_string(s: &str) -> String {
+    serde_json::to_string(s).expect("a string can always be written as JSON")
+}
+
+/// A CSV row describing a file's hash, or with the size and hashes left blank and the error
+/// hashing it in the last column. There are `also` digests after the content hash.
+fn csv_row(
+    path: &Path,
+    result: &Result<Hashed, String>,
+    also: usize,
+    elapsed: Duration,
+    encoding: Encoding,
+) -> String {
+    let mut row = csv_field(&path.to_string_lossy()) + ",";
+    match result {
+        Ok(hashed) => {
+            row += &format!("{},{},", hashed.size, encoding.encode(&hashed.hash));
+            for (_, digest) in &hashed.also {
+                row += &format!("{},", encoding.encode(digest));
+            }
+        }
+        Err(_) => row += &",".repeat(also + 2),
+    }
+    row += &format!("{:.3},", elapsed.as_secs_f64() * 1000.);
+    if let Err(e) = result {
+        row += &csv_field(e);
+    }
+    row
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\r', '\n'][..]) || s.starts_with(' ') || s.ends_with(' ') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn csv() {
+        assert_eq!("plain.txt", csv_field("plain.txt"));
+        assert_eq!(r#""a,b""#, csv_field("a,b"));
+        assert_eq!(r#""say ""hi""""#, csv_field(r#"say "hi""#));
+        assert_eq!("\"two\nlines\"", csv_field("two\nlines"));
+        assert_eq!(r#"" padded""#, csv_field(" padded"));
+
+        let hashed = Hashed {
+            hash: [0; 32],
+            size: 5,
+            blocks: None,
+            also: vec![(Algorithm::Sha1, vec![2; 2])],
+            mtime: None,
+        };
+        let elapsed = Duration::from_micros(1500);
+        assert_eq!(format!("x,5,{},0202,1.500,", "00".repeat(32)),
+            csv_row(Path::new("x"), &Ok(hashed), 1, elapsed, Encoding::Hex));
+        assert_eq!(r#"x,,,,1.500,"oops, no""#,
+            csv_row(Path::new("x"), &Err("oops, no".to_owned()), 1, elapsed, Encoding::Hex));
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(r#""a \"b\" \\ \n\u0001é""#, json_string("a \"b\" \\ \n\u{1}é"));
+        let hashed = Hashed {
+            hash: [0; 32],
+            size: 5,
+            blocks: Some(vec![[1; 32]]),
+            also: vec![(Algorithm::Sha1, vec![2; 2])],
+            mtime: None,
+        };
+        assert_eq!(
+            format!(r#"{{"path":"x","size":5,"content_hash":"{}","sha1":"0202","blocks":["{}"]}}"#,
+                "00".repeat(32), "01".repeat(32)),
+            json_object(Path::new("x"), &Ok(hashed), Encoding::Hex));
+        assert_eq!(r#"{"path":"x","error":"oops"}"#,
+            json_object(Path::new("x"), &Err("oops".to_owned()), Encoding::Hex));
+    }
+
+    #[test]
+    fn atomic_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("output");
+        let mut file = AtomicFile::create(&path, true).unwrap();
+        assert!(!file.appending);
+        file.file.write_all(b"one\n").unwrap();
+        assert!(!path.exists());
+        file.commit().unwrap();
+        assert_eq!("one\n", fs::read_to_string(&path).unwrap());
+
+        let mut file = AtomicFile::create(&path, true).unwrap();
+        assert!(file.appending);
+        file.file.write_all(b"two\n").unwrap();
+        // Not committed, so nothing changes, and the temporary file is gone.
+        drop(file);
+        assert_eq!("one\n", fs::read_to_string(&path).unwrap());
+        let temps = fs::read_dir(path.parent().unwrap()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy()
+                .starts_with(&format!(".{}.", path.file_name().unwrap().to_string_lossy())))
+            .count();
+        assert_eq!(0, temps);
+
+        // Two at once don't get in each other's way.
+        let mut file = AtomicFile::create(&path, true).unwrap();
+        let other = AtomicFile::create(&path, true).unwrap();
+        file.write_all(b"two\n").unwrap();
+        drop(other);
+        file.commit().unwrap();
+        assert_eq!("one\ntwo\n", fs::read_to_string(&path).unwrap());
+
+        let mut file = AtomicFile::create(&path, false).unwrap();
+        file.file.write_all(b"three\n").unwrap();
+        file.commit().unwrap();
+        assert_eq!("three\n", fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn encodings() {
+        let bytes = [0xfb, 0xff, 0x0a, 0x61];
+        assert_eq!("fbff0a61", Encoding::Hex.encode(&bytes));
+        assert_eq!("FBFF0A61", Encoding::UpperHex.encode(&bytes));
+        assert_eq!("+/8KYQ==", Encoding::Base64.encode(&bytes));
+        assert_eq!("-_8KYQ", Encoding::Base64Url.encode(&bytes));
+        assert_eq!("+/8K", Encoding::Base64.encode(&bytes[.. 3]));
+        assert_eq!("+/8=", Encoding::Base64.encode(&bytes[.. 2]));
+        assert_eq!("", Encoding::Base64.encode(&[]));
+    }
+
+    #[test]
+    fn hashdeep() {
+        let mut header = vec![];
+        hashdeep_header(&mut header, &[Algorithm::Sha256]).unwrap();
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.starts_with("%%%% HASHDEEP-1.0\n%%%% size,dropbox,sha256,filename\n## "));
+        assert!(header.ends_with("\n##\n"));
+    }
+
+    #[test]
+    fn relative_paths() {
+        let relative = |path, base| relative_path(Path::new(path), Path::new(base));
+        assert_eq!(Path::new("c/d"), relative("/a/b/c/d", "/a/b"));
+        assert_eq!(Path::new("../../x/y"), relative("/a/x/y", "/a/b/c"));
+        assert_eq!(Path::new("."), relative("/a/b", "/a/b"));
+        assert_eq!(Path::new(".."), relative("/a", "/a/b"));
+        assert_eq!(Path::new("a/b"), relative("/a/b", "/"));
+        assert_eq!(Path::new("/a/c"), absolute_path(Path::new("/a/b/../c/.")).unwrap());
+    }
+
+    #[test]
+    fn rclone_paths() {
+        assert_eq!("a/b c", rclone_path(Path::new("dir"), Path::new("dir/a/b c")));
+        assert_eq!("a", rclone_path(Path::new("./dir/"), Path::new("./dir/a")));
+        assert_eq!("file", rclone_path(Path::new("dir/file"), Path::new("dir/file")));
+    }
+}
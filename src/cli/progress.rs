@@ -0,0 +1,309 @@
+//! Showing progress on standard error, as progress bars or as a stream of JSON events.
+
+use super::output::json_string;
+use dropbox_content_hash::progress::ProgressRead;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, TermLike};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const FILE_TEMPLATE: &str =
+    "{wide_msg} [{bar:30}] {percent:>3}% {binary_bytes_per_sec:>12} {elapsed:>4} ETA {eta:>4}";
+const SPINNER_TEMPLATE: &str =
+    "{spinner} {wide_msg} {binary_bytes:>10} {binary_bytes_per_sec:>12} {elapsed:>4}";
+
+/// How often JSON progress events are written for each file.
+const JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The ways of showing progress, as given to `--progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Progress bars, if standard error is a terminal.
+    Auto,
+    /// Progress bars, even if standard error isn't a terminal.
+    Always,
+    /// Nothing.
+    Never,
+    /// JSON objects, one per line.
+    Json,
+}
+
+impl Mode {
+    /// The names accepted by [`FromStr`].
+    pub const NAMES: &'static [&'static str] = &["auto", "always", "never", "json"];
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "auto" => Ok(Mode::Auto),
+            "always" => Ok(Mode::Always),
+            "never" => Ok(Mode::Never),
+            "json" => Ok(Mode::Json),
+            _ => Err(format!("unknown progress mode {:?}", s)),
+        }
+    }
+}
+
+/// The progress display for a run.
+pub struct Progress {
+    kind: Kind,
+}
+
+enum Kind {
+    /// A bar for each file being hashed, and optionally an overall one below them.
+    Bars {
+        multi: MultiProgress,
+        overall: Option<ProgressBar>,
+        files_done: Arc<AtomicU64>,
+    },
+    Json(Arc<Mutex<Box<dyn Write + Send>>>),
+}
+
+impl Progress {
+    /// Show progress bars on standard error, including an overall one if `overall` is true.
+    ///
+    /// Unless `always` is true, nothing is shown if standard error isn't a terminal.
+    pub fn bars(overall: bool, always: bool) -> Self {
+        let target = if always {
+            ProgressDrawTarget::term_like(Box::new(Stderr))
+        } else if io::stderr().is_terminal() {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        Self::with_target(target, overall)
+    }
+
+    /// Don't show any progress.
+    pub fn hidden() -> Self {
+        Self::with_target(ProgressDrawTarget::hidden(), false)
+    }
+
+    fn with_target(target: ProgressDrawTarget, overall: bool) -> Self {
+        let multi = MultiProgress::with_draw_target(target);
+        let overall = if overall {
+            let bar = multi.add(ProgressBar::new_spinner()
+                .with_style(ProgressStyle::with_template(SPINNER_TEMPLATE).unwrap())
+                .with_message("0 files"));
+            Some(bar)
+        } else {
+            None
+        };
+        Self { kind: Kind::Bars { multi, overall, files_done: Arc::default() } }
+    }
+
+    /// Write a JSON object to the given stream, one per line, every so often while each file is
+    /// hashed, and when it's finished. They look like:
+    ///
+    /// `{"current_file":"foo","bytes_done":4194304,"bytes_total":10485760}`
+    ///
+    /// `bytes_total` is null if the file's length isn't known.
+    pub fn json(out: Box<dyn Write + Send>) -> Self {
+        Self { kind: Kind::Json(Arc::new(Mutex::new(out))) }
+    }
+
+    /// Start showing the progress of a file, until the returned value is dropped. If the length
+    /// is known, bars show the percentage done and the time remaining.
+    pub fn file(&self, path: &Path, len: Option<u64>) -> FileProgress {
+        let display = match &self.kind {
+            Kind::Bars { multi, overall, .. } => {
+                let bar = match len {
+                    Some(len) => ProgressBar::new(len)
+                        .with_style(ProgressStyle::with_template(FILE_TEMPLATE).unwrap()
+                            .progress_chars("=> ")),
+                    None => ProgressBar::new_spinner()
+                        .with_style(ProgressStyle::with_template(SPINNER_TEMPLATE).unwrap()),
+                };
+                let bar = match overall {
+                    Some(overall) => multi.insert_before(overall, bar),
+                    None => multi.add(bar),
+                };
+                bar.set_message(path.display().to_string());
+                Display::Bar { bar, overall: overall.clone() }
+            }
+            Kind::Json(out) => Display::Json {
+                out: Arc::clone(out),
+                path: json_string(&path.to_string_lossy()).into(),
+                len,
+                last: Arc::new(Mutex::new(Instant::now())),
+            },
+        };
+        FileProgress {
+            tracker: Tracker { display, position: Arc::default() },
+            multi: match &self.kind {
+                Kind::Bars { multi, files_done, .. } => {
+                    Some((multi.clone(), Arc::clone(files_done)))
+                }
+                Kind::Json(_) => None,
+            },
+        }
+    }
+
+    /// Hide any bars while running the given function, so it can print to the terminal.
+    pub fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.kind {
+            Kind::Bars { multi, .. } => multi.suspend(f),
+            Kind::Json(_) => f(),
+        }
+    }
+
+    /// Remove the overall bar.
+    pub fn finish(&self) {
+        if let Kind::Bars { overall: Some(overall), .. } = &self.kind {
+            overall.finish_and_clear();
+        }
+    }
+}
+
+/// The progress of one file.
+pub struct FileProgress {
+    tracker: Tracker,
+    /// For bars, the set of bars this one is in, and the count of files done so far.
+    multi: Option<(MultiProgress, Arc<AtomicU64>)>,
+}
+
+impl FileProgress {
+    /// Something to update the progress with, which can be sent to other threads.
+    pub fn tracker(&self) -> Tracker {
+        self.tracker.clone()
+    }
+
+    /// Wrap a reader so the progress shows how much has been read from it.
+    pub fn reader<R: Read>(&self, inner: R) -> ProgressRead<R, impl FnMut(u64, Option<u64>)> {
+        let tracker = self.tracker();
+        // The tracker throttles JSON events itself, and the bars redraw at their own rate.
+        ProgressRead::new(inner, None, move |position, _| tracker.set_position(position))
+            .interval(Duration::ZERO)
+    }
+}
+
+impl Drop for FileProgress {
+    fn drop(&mut self) {
+        match &self.tracker.display {
+            Display::Bar { bar, overall } => {
+                bar.finish_and_clear();
+                if let Some((multi, files_done)) = &self.multi {
+                    multi.remove(bar);
+                    if let Some(overall) = overall {
+                        let done = files_done.fetch_add(1, Ordering::Relaxed) + 1;
+                        overall.set_message(
+                            format!("{} file{}", done, if done == 1 { "" } else { "s" }));
+                    }
+                }
+            }
+            Display::Json { .. } => {
+                self.tracker.write_json(self.tracker.position.load(Ordering::Relaxed));
+            }
+        }
+    }
+}
+
+/// Updates the progress of a file, and the overall progress.
+#[derive(Clone)]
+pub struct Tracker {
+    display: Display,
+    position: Arc<AtomicU64>,
+}
+
+#[derive(Clone)]
+enum Display {
+    Bar {
+        bar: ProgressBar,
+        overall: Option<ProgressBar>,
+    },
+    Json {
+        out: Arc<Mutex<Box<dyn Write + Send>>>,
+        /// Already quoted.
+        path: Arc<str>,
+        len: Option<u64>,
+        /// When the last event was written.
+        last: Arc<Mutex<Instant>>,
+    },
+}
+
+impl Tracker {
+    /// Record that the given number of bytes have been hashed so far.
+    pub fn set_position(&self, position: u64) {
+        let previous = self.position.swap(position, Ordering::Relaxed);
+        match &self.display {
+            Display::Bar { bar, overall } => {
+                bar.set_position(position);
+                if let Some(overall) = overall {
+                    overall.inc(position.saturating_sub(previous));
+                }
+            }
+            Display::Json { last, .. } => {
+                let mut last = last.lock().unwrap();
+                if last.elapsed() >= JSON_INTERVAL {
+                    *last = Instant::now();
+                    drop(last);
+                    self.write_json(position);
+                }
+            }
+        }
+    }
+
+    fn write_json(&self, position: u64) {
+        if let Display::Json { out, path, len, .. } = &self.display {
+            let total = len.map_or_else(|| "null".to_owned(), |len| len.to_string());
+            let mut out = out.lock().unwrap();
+            // Progress is best-effort; don't fail the hash if it can't be written.
+            let _ = writeln!(out, "{{\"current_file\":{},\"bytes_done\":{},\"bytes_total\":{}}}",
+                path, position, total)
+                .and_then(|()| out.flush());
+        }
+    }
+}
+
+/// Standard error, treated as a terminal whether it is one or not, for `--progress=always`.
+#[derive(Debug)]
+struct Stderr;
+
+impl Stderr {
+    fn write(&self, s: &str) -> io::Result<()> {
+        io::stderr().write_all(s.as_bytes())
+    }
+}
+
+impl TermLike for Stderr {
+    fn width(&self) -> u16 {
+        std::env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()).unwrap_or(80)
+    }
+
+    fn move_cursor_up(&self, n: usize) -> io::Result<()> {
+        if n == 0 { Ok(()) } else { self.write(&format!("\x1b[{}A", n)) }
+    }
+
+    fn move_cursor_down(&self, n: usize) -> io::Result<()> {
+        if n == 0 { Ok(()) } else { self.write(&format!("\x1b[{}B", n)) }
+    }
+
+    fn move_cursor_right(&self, n: usize) -> io::Result<()> {
+        if n == 0 { Ok(()) } else { self.write(&format!("\x1b[{}C", n)) }
+    }
+
+    fn move_cursor_left(&self, n: usize) -> io::Result<()> {
+        if n == 0 { Ok(()) } else { self.write(&format!("\x1b[{}D", n)) }
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        self.write(&format!("{}\n", s))
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        self.write(s)
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        self.write("\r\x1b[2K")
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -0,0 +1,96 @@
+//! Retrying reads which fail, for `--retries`.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use dropbox_content_hash::trace::warn;
+
+/// Retries failed reads after a delay, seeking back to where the read started first, so errors
+/// from flaky network filesystems don't abort a long hash.
+pub struct RetryingReader<'a, R> {
+    inner: R,
+    path: &'a Path,
+    position: u64,
+    retries: u32,
+    delay: Duration,
+}
+
+impl<'a, R: Read + Seek> RetryingReader<'a, R> {
+    /// Retry each failed read up to `retries` times, waiting `delay` before each attempt. The
+    /// path is only used in the warnings printed for each failure.
+    pub fn new(mut inner: R, path: &'a Path, retries: u32, delay: Duration) -> io::Result<Self> {
+        let position = inner.stream_position()?;
+        Ok(Self { inner, path, position, retries, delay })
+    }
+}
+
+impl<R: Read + Seek> Read for RetryingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.read(buf) {
+                Ok(n) => {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("Read error in {:?} at offset {}: {}; retrying ({} of {})",
+                        self.path, self.position, e, attempt, self.retries);
+                    thread::sleep(self.delay);
+                    self.inner.seek(SeekFrom::Start(self.position))?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Fails every other read.
+    struct Flaky {
+        inner: Cursor<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail = !self.fail;
+            if self.fail {
+                // Move the position, as a partly-failed read might.
+                self.inner.seek(SeekFrom::Current(1))?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "flaky"));
+            }
+            let len = buf.len().min(3);
+            self.inner.read(&mut buf[.. len])
+        }
+    }
+
+    impl Seek for Flaky {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn retries() {
+        let data = (0 .. 10).collect::<Vec<u8>>();
+        let flaky = || Flaky { inner: Cursor::new(data.clone()), fail: false };
+        let path = Path::new("flaky");
+
+        let mut out = vec![];
+        RetryingReader::new(flaky(), path, 1, Duration::ZERO).unwrap()
+            .read_to_end(&mut out).unwrap();
+        assert_eq!(data, out);
+
+        let err = RetryingReader::new(flaky(), path, 0, Duration::ZERO).unwrap()
+            .read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    }
+}
@@ -0,0 +1,198 @@
+//! A small HTTP service which hashes what's sent to it, for `--serve`.
+//!
+//! `POST /hash` hashes the request body, and `POST /hash/path` hashes a local file whose path is
+//! given in the body as a JSON object, like `{"path": "/srv/data/file"}`. Either responds with a
+//! JSON object like the ones `--format json` prints, with the block hashes too if `?blocks` is
+//! added to the URL.
+
+use super::output::{json_object, json_string, Encoding};
+use super::Hashed;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tiny_http::{Header, Method, Request, Response, Server};
+use dropbox_content_hash::trace::{info, warn};
+
+/// The most a `/hash/path` request body can be.
+const MAX_PATH_REQUEST: u64 = 64 * 1024;
+
+/// What to hash for a request.
+pub enum Source<'a> {
+    /// A request body, and its length, if the client gave one.
+    Body(&'a mut dyn Read, Option<u64>),
+    /// A local file.
+    Path(PathBuf),
+}
+
+/// Listen on the given address, handling requests on the given number of threads, until
+/// receiving a request fails. `hash` is expected to collect block hashes; they're dropped from
+/// responses to requests which didn't ask for them.
+pub fn serve(
+    addr: &str,
+    threads: usize,
+    encoding: Encoding,
+    hash: impl Fn(Source<'_>) -> Result<Hashed, String> + Sync,
+) -> Result<(), String> {
+    let server = Server::http(addr).map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+    info!(addr = %server.server_addr(), "listening");
+    run(&server, threads, encoding, &hash)
+}
+
+fn run(
+    server: &Server,
+    threads: usize,
+    encoding: Encoding,
+    hash: &(dyn Fn(Source<'_>) -> Result<Hashed, String> + Sync),
+) -> Result<(), String> {
+    std::thread::scope(|scope| {
+        let workers = (0 .. threads.max(1))
+            .map(|_| scope.spawn(|| -> Result<(), String> {
+                loop {
+                    let request = server.recv()
+                        .map_err(|e| format!("Failed to receive a request: {}", e))?;
+                    handle(request, encoding, hash);
+                }
+            }))
+            .collect::<Vec<_>>();
+        workers.into_iter()
+            .try_for_each(|worker| worker.join().expect("server thread panicked"))
+    })
+}
+
+fn handle(
+    mut request: Request,
+    encoding: Encoding,
+    hash: &(dyn Fn(Source<'_>) -> Result<Hashed, String> + Sync),
+) {
+    let url = request.url().to_owned();
+    let (route, query) = url.split_once('?').unwrap_or((&url, ""));
+    let blocks = query.split('&')
+        .any(|param| matches!(param, "blocks" | "blocks=true" | "blocks=1"));
+    let response = match (request.method(), route) {
+        (Method::Post, "/hash") => {
+            let len = request.body_length().map(|len| len as u64);
+            let result = hash(Source::Body(request.as_reader(), len));
+            hashed(Path::new("-"), result, blocks, encoding)
+        }
+        (Method::Post, "/hash/path") => match read_path(&mut request) {
+            Ok(path) => {
+                let result = hash(Source::Path(path.clone()));
+                hashed(&path, result, blocks, encoding)
+            }
+            Err(e) => error(400, &e),
+        },
+        (_, "/hash") | (_, "/hash/path") => {
+            error(405, "only POST is allowed").with_header(header("Allow", "POST"))
+        }
+        _ => error(404, "not found"),
+    };
+    info!(method = %request.method(), url, status = response.status_code().0, "request");
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to send a response: {}", e);
+    }
+}
+
+/// Read the path to hash from the body of a `/hash/path` request.
+fn read_path(request: &mut Request) -> Result<PathBuf, String> {
+    let mut body = String::new();
+    request.as_reader().take(MAX_PATH_REQUEST).read_to_string(&mut body)
+        .map_err(|e| format!("Failed to read the request: {}", e))?;
+    let value = serde_json::from_str::<serde_json::Value>(&body)
+        .map_err(|e| format!("The request isn't valid JSON: {}", e))?;
+    match value.get("path").and_then(|path| path.as_str()) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Err("The request has no \"path\" string".to_owned()),
+    }
+}
+
+type JsonResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+fn hashed(
+    path: &Path,
+    mut result: Result<Hashed, String>,
+    blocks: bool,
+    encoding: Encoding,
+) -> JsonResponse {
+    if let (Ok(hashed), false) = (&mut result, blocks) {
+        hashed.blocks = None;
+    }
+    let status = if result.is_ok() { 200 } else { 500 };
+    json(status, json_object(path, &result, encoding))
+}
+
+fn error(status: u16, message: &str) -> JsonResponse {
+    json(status, format!("{{\"error\":{}}}", json_string(message)))
+}
+
+fn json(status: u16, body: String) -> JsonResponse {
+    Response::from_string(body + "\n")
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dropbox_content_hash::{hex_string, ContentHasher};
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    fn hash(source: Source<'_>) -> Result<Hashed, String> {
+        let data = match source {
+            Source::Body(body, _) => {
+                let mut data = vec![];
+                body.read_to_end(&mut data).map(|_| data)
+            }
+            Source::Path(path) => std::fs::read(path),
+        }.map_err(|e| e.to_string())?;
+        let mut ctx = ContentHasher::default();
+        ctx.update(&data);
+        let size = data.len() as u64;
+        Ok(Hashed { hash: ctx.finish(), size, blocks: Some(vec![]), also: vec![], mtime: None })
+    }
+
+    fn request(addr: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{}", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn requests() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        std::thread::spawn(move || run(&server, 1, Encoding::Hex, &hash));
+
+        let empty = hex_string(&dropbox_content_hash::EMPTY_CONTENT_HASH);
+        let response = request(&addr,
+            "POST /hash HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with(&format!(
+            "{{\"path\":\"-\",\"size\":0,\"content_hash\":\"{}\"}}\n", empty)), "{}", response);
+
+        let response = request(&addr,
+            "POST /hash?blocks HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(response.ends_with(",\"blocks\":[]}\n"), "{}", response);
+
+        let body = "{\"path\": \"/nonexistent\"}";
+        let response = request(&addr, &format!(
+            "POST /hash/path HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(), body));
+        assert!(response.starts_with("HTTP/1.1 500"), "{}", response);
+        assert!(response.contains("\"path\":\"/nonexistent\",\"error\":"), "{}", response);
+
+        let response = request(&addr,
+            "POST /hash/path HTTP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}");
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+        let response = request(&addr, "GET /hash HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+        let response = request(&addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+}
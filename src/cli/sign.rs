@@ -0,0 +1,290 @@
+//! Signing manifests with minisign keys, for `--sign-key`, and checking those signatures, for
+//! `--verify-key`.
+//!
+//! Signatures are written next to the manifest with ".minisig" added to its name, as minisign
+//! does, so they can also be checked with `minisign -V`.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512, Digest};
+use minisign_verify::{PublicKey, Signature};
+use ring::signature::Ed25519KeyPair;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+const ED25519: &[u8; 2] = b"Ed";
+const PREHASHED: &[u8; 2] = b"ED";
+const SCRYPT: &[u8; 2] = b"Sc";
+const UNENCRYPTED: &[u8; 2] = &[0, 0];
+const BLAKE2B: &[u8; 2] = b"B2";
+
+/// The length of the part of a secret key which is encrypted: the key ID, the secret key itself,
+/// and a checksum.
+const KEYNUM_SK_LEN: usize = 8 + 64 + 32;
+
+/// The path of the signature of a manifest.
+pub fn signature_path(manifest: &Path) -> PathBuf {
+    let mut path = OsString::from(manifest);
+    path.push(".minisig");
+    PathBuf::from(path)
+}
+
+/// Sign a manifest with a minisign secret key, writing the signature next to it. If the key is
+/// encrypted, `password` is called to ask for its password.
+pub fn sign(
+    manifest: &Path,
+    secret_key: &Path,
+    password: impl FnOnce() -> io::Result<String>,
+) -> Result<(), String> {
+    let key = read_secret_key(secret_key, password)?;
+    let data = fs::read(manifest).map_err(|e| format!("Failed to read {:?}: {}", manifest, e))?;
+    let signature = key.pair.sign(&Blake2b512::digest(&data));
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let file_name = manifest.file_name().unwrap_or_default().to_string_lossy();
+    let trusted_comment = format!("timestamp:{}\tfile:{}\thashed", timestamp, file_name);
+    let mut global = signature.as_ref().to_vec();
+    global.extend_from_slice(trusted_comment.as_bytes());
+    let global_signature = key.pair.sign(&global);
+
+    let mut line = PREHASHED.to_vec();
+    line.extend_from_slice(&key.id);
+    line.extend_from_slice(signature.as_ref());
+    let contents = format!(
+        "untrusted comment: signature from dropbox-content-hash secret key\n{}\n\
+        trusted comment: {}\n{}\n",
+        BASE64.encode(&line), trusted_comment, BASE64.encode(global_signature.as_ref()));
+    let path = signature_path(manifest);
+    fs::write(&path, contents).map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+/// Read a manifest and check its signature with a minisign public key, returning its contents if
+/// the signature is good.
+pub fn verify(manifest: &Path, public_key: &Path) -> Result<Vec<u8>, String> {
+    let public_key = PublicKey::from_file(public_key)
+        .map_err(|e| format!("Failed to read public key {:?}: {}", public_key, e))?;
+    let signature_path = signature_path(manifest);
+    let signature = Signature::from_file(&signature_path)
+        .map_err(|e| format!("Failed to read signature {:?}: {}", signature_path, e))?;
+    let data = fs::read(manifest).map_err(|e| format!("Failed to read {:?}: {}", manifest, e))?;
+    public_key.verify(&data, &signature, false)
+        .map_err(|e| format!("{}: bad signature: {}", manifest.display(), e))?;
+    Ok(data)
+}
+
+struct SecretKey {
+    id: [u8; 8],
+    pair: Ed25519KeyPair,
+}
+
+fn read_secret_key(
+    path: &Path,
+    password: impl FnOnce() -> io::Result<String>,
+) -> Result<SecretKey, String> {
+    let invalid = || format!("{:?} is not a minisign secret key", path);
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read secret key {:?}: {}", path, e))?;
+    let bytes = text.lines().nth(1)
+        .and_then(|line| BASE64.decode(line.trim()).ok())
+        .map(Zeroizing::new)
+        .ok_or_else(invalid)?;
+    if bytes.len() != 6 + 32 + 8 + 8 + KEYNUM_SK_LEN
+        || &bytes[0 .. 2] != ED25519
+        || &bytes[4 .. 6] != BLAKE2B
+    {
+        return Err(invalid());
+    }
+    let salt = &bytes[6 .. 38];
+    let opslimit = u64::from_le_bytes(bytes[38 .. 46].try_into().unwrap());
+    let memlimit = u64::from_le_bytes(bytes[46 .. 54].try_into().unwrap());
+    let mut keynum_sk = Zeroizing::new(bytes[54 ..].to_vec());
+    match &bytes[2 .. 4] {
+        kdf if kdf == SCRYPT => {
+            let password = password().map(Zeroizing::new)
+                .map_err(|e| format!("Failed to read the password: {}", e))?;
+            let (log_n, r, p) = scrypt_params(opslimit, memlimit);
+            let params = scrypt::Params::new(log_n, r, p, 64).map_err(|e| e.to_string())?;
+            let mut stream = Zeroizing::new([0; KEYNUM_SK_LEN]);
+            scrypt::scrypt(password.as_bytes(), salt, &params, &mut *stream)
+                .map_err(|e| e.to_string())?;
+            keynum_sk.iter_mut().zip(stream.iter()).for_each(|(byte, key)| *byte ^= key);
+        }
+        kdf if kdf == UNENCRYPTED => (),
+        _ => return Err(format!("{:?} is encrypted in a way that isn't supported", path)),
+    }
+
+    let (id, rest) = keynum_sk.split_at(8);
+    let (secret, checksum) = rest.split_at(64);
+    let mut expected = Blake2b::<U32>::new();
+    expected.update(ED25519);
+    expected.update(id);
+    expected.update(secret);
+    if expected.finalize().as_slice() != checksum {
+        return Err(format!("Wrong password for {:?}, or the key is corrupt", path));
+    }
+    let pair = Ed25519KeyPair::from_seed_and_public_key(&secret[.. 32], &secret[32 ..])
+        .map_err(|e| format!("{:?} has an invalid key: {}", path, e))?;
+    Ok(SecretKey { id: id.try_into().unwrap(), pair })
+}
+
+/// The scrypt parameters (log2 N, r, p) minisign uses for the given limits, as libsodium picks
+/// them.
+fn scrypt_params(opslimit: u64, memlimit: u64) -> (u8, u32, u32) {
+    let opslimit = opslimit.max(32768);
+    let r = 8;
+    let log_n_for = |max_n: u64| (1 .. 63).find(|&log_n| 1 << log_n > max_n / 2).unwrap_or(63);
+    if opslimit < memlimit / 32 {
+        (log_n_for(opslimit / (r * 4)), r as u32, 1)
+    } else {
+        let log_n = log_n_for(memlimit / (r * 128));
+        let max_rp = ((opslimit / 4) >> log_n).min(0x3fff_ffff);
+        (log_n, r as u32, (max_rp / r) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+    use tempfile::TempDir;
+
+    /// Write a secret key file for the given seed, encrypted with the given password if there is
+    /// one, and the matching public key file.
+    fn write_keys(dir: &Path, seed: &[u8; 32], password: Option<&str>) -> (PathBuf, PathBuf) {
+        let pair = Ed25519KeyPair::from_seed_unchecked(seed).unwrap();
+        let id = [7; 8];
+        let mut keynum_sk = id.to_vec();
+        keynum_sk.extend_from_slice(seed);
+        keynum_sk.extend_from_slice(pair.public_key().as_ref());
+        let mut checksum = Blake2b::<U32>::new();
+        checksum.update(ED25519);
+        checksum.update(&keynum_sk);
+        keynum_sk.extend_from_slice(&checksum.finalize());
+
+        let (opslimit, memlimit) = (32768u64, 16u64 << 20);
+        let salt = [3; 32];
+        let mut secret = ED25519.to_vec();
+        match password {
+            Some(password) => {
+                secret.extend_from_slice(SCRYPT);
+                let (log_n, r, p) = scrypt_params(opslimit, memlimit);
+                let params = scrypt::Params::new(log_n, r, p, 64).unwrap();
+                let mut stream = [0; KEYNUM_SK_LEN];
+                scrypt::scrypt(password.as_bytes(), &salt, &params, &mut stream).unwrap();
+                keynum_sk.iter_mut().zip(stream).for_each(|(byte, key)| *byte ^= key);
+            }
+            None => secret.extend_from_slice(UNENCRYPTED),
+        }
+        secret.extend_from_slice(BLAKE2B);
+        secret.extend_from_slice(&salt);
+        secret.extend_from_slice(&opslimit.to_le_bytes());
+        secret.extend_from_slice(&memlimit.to_le_bytes());
+        secret.extend_from_slice(&keynum_sk);
+        let secret_path = dir.join("key.sec");
+        fs::write(&secret_path, format!("untrusted comment: test\n{}\n", BASE64.encode(&secret)))
+            .unwrap();
+
+        let mut public = ED25519.to_vec();
+        public.extend_from_slice(&id);
+        public.extend_from_slice(pair.public_key().as_ref());
+        let public_path = dir.join("key.pub");
+        fs::write(&public_path, format!("untrusted comment: test\n{}\n", BASE64.encode(&public)))
+            .unwrap();
+        (secret_path, public_path)
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let manifest = dir.join("manifest");
+        fs::write(&manifest, b"some hashes\n").unwrap();
+        let no_password = || -> io::Result<String> { panic!("asked for a password") };
+
+        let (secret, public) = write_keys(dir, &[1; 32], None);
+        sign(&manifest, &secret, no_password).unwrap();
+        assert_eq!(b"some hashes\n".to_vec(), verify(&manifest, &public).unwrap());
+        fs::write(&manifest, b"other hashes\n").unwrap();
+        assert!(verify(&manifest, &public).unwrap_err().contains("bad signature"));
+
+        let (secret, public) = write_keys(dir, &[2; 32], Some("hunter2"));
+        assert!(sign(&manifest, &secret, || Ok("wrong".to_owned())).unwrap_err()
+            .starts_with("Wrong password"));
+        sign(&manifest, &secret, || Ok("hunter2".to_owned())).unwrap();
+        verify(&manifest, &public).unwrap();
+    }
+
+    #[test]
+    fn params() {
+        // minisign's defaults.
+        assert_eq!((20, 8, 1), scrypt_params(33554432, 1073741824));
+        assert_eq!((10, 8, 1), scrypt_params(32768, 16 << 20));
+        assert_eq!((14, 8, 2), scrypt_params(1 << 20, 16 << 20));
+    }
+
+    /// A key made and a manifest signed the way `minisign -G` and `minisign -S` do it, with
+    /// libsodium's crypto_sign, crypto_generichash, and crypto_pwhash_scryptsalsa208sha256 (which
+    /// picks the scrypt parameters from the limits itself). The limits are lower than minisign's
+    /// defaults, which would take a gigabyte of memory to decrypt.
+    const SECRET_KEY: &str = "untrusted comment: minisign encrypted secret key
+RWRTY0IyZGVmZ2hpamtsbW5vcHFyc3R1dnd4eXp7fH1+f4CBgoMAABAAAAAAAAAAAAEAAAAAuvd9LW9NNpWM7pKZQID8dIct\
+F0MC/rq8RAP9uejlh8jDqFwGfo75Dk8v010zgIrCvjdcSL0aT3foUgGj9mlFOJ9qlue39SpU7gGHiV9H2gIxCVntwXK5Y3jN\
+ZsgO9lbN3D0Q2r0rY58=
+";
+    const PASSWORD: &str = "correct horse battery staple";
+    const PUBLIC_KEY: &str = "untrusted comment: minisign public key 88796A5B4C3D2E1F
+RWQfLj1MW2p5iAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4
+";
+    const MANIFEST: &[u8] = b"some hashes\n";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQfLj1MW2p5iN+9OI0JmURgRqEoAqJ43XTLrbYm9Gx6tUFKm1kr3EsR/SnZeV5doYcufG5qg0AlGGmJjcufOFayGbRomd8T+gA=
+trusted comment: timestamp:1760000000\tfile:manifest\thashed
+rmWy0iGUv1DR8ohoIhbG5rWuQ9qqM5hT7424LPkS9ql7lzM9NxWTK3tWeEONvcoS2DWld4wWlRLWwvAhgOlXAQ==
+";
+
+    #[test]
+    fn known_keys() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let secret = dir.join("key.sec");
+        let public = dir.join("key.pub");
+        let manifest = dir.join("manifest");
+        fs::write(&secret, SECRET_KEY).unwrap();
+        fs::write(&public, PUBLIC_KEY).unwrap();
+        fs::write(&manifest, MANIFEST).unwrap();
+
+        // The existing signature checks out.
+        fs::write(signature_path(&manifest), SIGNATURE).unwrap();
+        assert_eq!(MANIFEST, &verify(&manifest, &public).unwrap()[..]);
+
+        // Ed25519 signatures are deterministic, so signing again gives the same signature, and
+        // only the trusted comment (with its timestamp) and its signature differ.
+        assert!(sign(&manifest, &secret, || Ok("wrong".to_owned())).unwrap_err()
+            .starts_with("Wrong password"));
+        sign(&manifest, &secret, || Ok(PASSWORD.to_owned())).unwrap();
+        let signature = fs::read_to_string(signature_path(&manifest)).unwrap();
+        assert_eq!(SIGNATURE.lines().nth(1), signature.lines().nth(1));
+        verify(&manifest, &public).unwrap();
+
+        // A signature made by minisign itself, from the minisign-verify documentation.
+        fs::write(&public, "untrusted comment: minisign public key E7620F1842B4E81F
+RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3
+").unwrap();
+        fs::write(&manifest, b"test").unwrap();
+        fs::write(signature_path(&manifest), "\
+untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1633700835\tfile:test\tprehashed
+wLMDjy9FLAuxZ3q4NlEvkgtyhrr0gtTu6KC4KBJdITbbOeAi1zBIYo0v4iTgt8jJpIidRJnp94ABQkJAgAooBQ==
+").unwrap();
+        assert_eq!(b"test", &verify(&manifest, &public).unwrap()[..]);
+        fs::write(&manifest, b"Test").unwrap();
+        assert!(verify(&manifest, &public).unwrap_err().contains("bad signature"));
+    }
+}
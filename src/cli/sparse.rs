@@ -0,0 +1,106 @@
+//! Finding the holes in sparse files, so the blocks of zeros in them don't have to be read.
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+/// Find the blocks of the file between `start` and `end` which lie entirely within holes, and so
+/// are all zeros. Blocks are counted from `start`, and the ranges returned are relative to it.
+///
+/// Files which don't look sparse aren't searched, and nothing is found on platforms or
+/// filesystems which can't report holes. The file's position is left unspecified.
+#[cfg(target_os = "linux")]
+pub fn zero_blocks(
+    file: &File,
+    start: u64,
+    end: u64,
+    block_size: u64,
+) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = file.metadata()?;
+    if meta.blocks().saturating_mul(512) >= meta.len() {
+        return Ok(vec![]);
+    }
+
+    let mut blocks = vec![];
+    let mut position = start;
+    while position < end {
+        let data = match seek(file, position, libc::SEEK_DATA) {
+            Ok(data) => data.min(end),
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => end,
+            Err(e) => return Err(e),
+        };
+        let first = (position - start).div_ceil(block_size) * block_size;
+        let last = (data - start) / block_size * block_size;
+        if first < last {
+            blocks.push(first .. last);
+        }
+        if data == end {
+            break;
+        }
+        position = seek(file, data, libc::SEEK_HOLE)?;
+    }
+    Ok(blocks)
+}
+
+#[cfg(target_os = "linux")]
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    use std::convert::TryInto;
+    use std::os::unix::io::AsRawFd;
+    let offset = offset.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset is too big"))?;
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as u64)
+    }
+}
+
+/// Find the blocks of the file which lie entirely within holes.
+///
+/// This is not supported on this platform, and never finds any.
+#[cfg(not(target_os = "linux"))]
+pub fn zero_blocks(
+    _file: &File,
+    _start: u64,
+    _end: u64,
+    _block_size: u64,
+) -> io::Result<Vec<Range<u64>>> {
+    Ok(vec![])
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use tempfile::TempDir;
+
+    #[test]
+    fn holes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sparse");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.seek(SeekFrom::Start(50_000)).unwrap();
+        file.write_all(b"world").unwrap();
+        file.set_len(100_000).unwrap();
+        drop(file);
+
+        // Whether holes are found depends on the filesystem, but any that are must be zeros.
+        let mut file = File::open(&path).unwrap();
+        for start in [0, 1000] {
+            let mut previous = 0;
+            for range in zero_blocks(&file, start, 100_000, 8192).unwrap() {
+                assert!(previous <= range.start && range.start < range.end);
+                assert_eq!(0, range.start % 8192);
+                assert_eq!(0, range.end % 8192);
+                let mut buf = vec![0xff; (range.end - range.start) as usize];
+                file.seek(SeekFrom::Start(start + range.start)).unwrap();
+                file.read_exact(&mut buf).unwrap();
+                assert!(buf.iter().all(|&b| b == 0));
+                previous = range.end;
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! The summary printed at the end of a run, for `--stats`.
+
+use super::Hashed;
+use indicatif::HumanBytes;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The formats of the summary, as given to `--stats-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A line of text for each figure.
+    Text,
+    /// A JSON object on one line.
+    Json,
+}
+
+impl Format {
+    /// The names accepted by [`FromStr`].
+    pub const NAMES: &'static [&'static str] = &["text", "json"];
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown stats format {:?}", s)),
+        }
+    }
+}
+
+/// Counts of what happened during a run.
+#[derive(Debug, Default)]
+pub struct Stats {
+    files: u64,
+    bytes: u64,
+    errors: u64,
+}
+
+impl Stats {
+    /// Count the result of hashing a file.
+    pub fn record(&mut self, result: &Result<Hashed, String>) {
+        match result {
+            Ok(hashed) => {
+                self.files += 1;
+                self.bytes += hashed.size;
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    /// Count an error that isn't from hashing a file, such as a directory that couldn't be read.
+    pub fn error(&mut self) {
+        self.errors += 1;
+    }
+
+    /// The summary of a run which took the given time.
+    pub fn summary(&self, elapsed: Duration, format: Format) -> String {
+        let seconds = elapsed.as_secs_f64();
+        let rate = if seconds > 0. { self.bytes as f64 / seconds } else { 0. };
+        match format {
+            Format::Text => format!(
+                "Files hashed: {}\nBytes hashed: {} ({})\nElapsed: {:.3} s\n\
+                    Throughput: {}/s\nErrors: {}",
+                self.files, self.bytes, HumanBytes(self.bytes), seconds, HumanBytes(rate as u64),
+                self.errors),
+            Format::Json => format!(
+                "{{\"files\":{},\"bytes\":{},\"elapsed_seconds\":{:.3},\"bytes_per_second\":{:.0},\
+                    \"errors\":{}}}",
+                self.files, self.bytes, seconds, rate, self.errors),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let mut stats = Stats::default();
+        let hashed = |size| Hashed { hash: [0; 32], size, blocks: None, also: vec![], mtime: None };
+        stats.record(&Ok(hashed(3 << 20)));
+        stats.record(&Ok(hashed(1 << 20)));
+        stats.record(&Err("nope".to_owned()));
+        stats.error();
+        let elapsed = Duration::from_millis(2000);
+        assert_eq!("Files hashed: 2\nBytes hashed: 4194304 (4.00 MiB)\nElapsed: 2.000 s\n\
+            Throughput: 2.00 MiB/s\nErrors: 2",
+            stats.summary(elapsed, Format::Text));
+        assert_eq!("{\"files\":2,\"bytes\":4194304,\"elapsed_seconds\":2.000,\
+            \"bytes_per_second\":2097152,\"errors\":2}",
+            stats.summary(elapsed, Format::Json));
+        assert!(Stats::default().summary(Duration::ZERO, Format::Json)
+            .contains("\"bytes_per_second\":0,"));
+    }
+}
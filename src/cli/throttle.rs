@@ -0,0 +1,87 @@
+//! Limiting how fast files are read, for `--throttle`.
+
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A read rate limit, shared by everything reading through it.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    /// When the bytes read so far will have been allowed.
+    next: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    /// Allow reading the given number of bytes per second.
+    pub fn new(bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second != 0, "rate must not be zero");
+        Self { bytes_per_second, next: Mutex::new(None) }
+    }
+
+    /// Wrap a reader so it's limited by this throttle.
+    pub fn reader<R: Read>(&self, inner: R) -> ThrottledReader<'_, R> {
+        ThrottledReader { inner, throttle: self }
+    }
+
+    /// Account for some bytes having been read, sleeping until they're allowed.
+    fn consume(&self, bytes: usize) {
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            // Don't let time spent not reading be saved up for a burst later.
+            let start = next.filter(|next| *next > now).unwrap_or(now);
+            *next = Some(start + cost);
+            start - now
+        };
+        if wait > Duration::ZERO {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Parses rates like "100MiB/s", or a number of bytes per second.
+impl FromStr for Throttle {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let rate = super::parse_size(s.strip_suffix("/s").unwrap_or(s))?;
+        if rate == 0 {
+            return Err("the rate must not be zero".to_owned());
+        }
+        Ok(Self::new(rate))
+    }
+}
+
+/// Reads at no more than the rate allowed by a [`Throttle`].
+pub struct ThrottledReader<'a, R> {
+    inner: R,
+    throttle: &'a Throttle,
+}
+
+impl<R: Read> Read for ThrottledReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.throttle.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate() {
+        let throttle = "100KiB/s".parse::<Throttle>().unwrap();
+        assert_eq!(100 * 1024, throttle.bytes_per_second);
+        let start = Instant::now();
+        let n = io::copy(&mut throttle.reader(&[0u8; 30 * 1024][..]), &mut io::sink()).unwrap();
+        assert_eq!(30 * 1024, n);
+        // The last read isn't waited for.
+        assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+        assert!("0/s".parse::<Throttle>().is_err());
+    }
+}
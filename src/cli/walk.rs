@@ -0,0 +1,212 @@
+//! Finding the files to hash in directory trees, for `--recursive`.
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::io;
+use std::path::Path;
+use dropbox_content_hash::trace::debug;
+use walkdir::WalkDir;
+
+/// Which files in a tree to hash.
+pub struct Walker {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+    max_depth: Option<usize>,
+    follow_symlinks: bool,
+    error_on_broken_symlinks: bool,
+}
+
+impl Walker {
+    /// Hash only files whose path relative to the directory being walked matches one of the
+    /// `include` patterns (or all files if there are none), and doesn't match any of the
+    /// `exclude` patterns. Directories matching an `exclude` pattern are skipped entirely.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, globset::Error> {
+        Ok(Self {
+            include: glob_set(include)?,
+            exclude: glob_set(exclude)?,
+            max_depth: None,
+            follow_symlinks: false,
+            error_on_broken_symlinks: false,
+        })
+    }
+
+    /// Don't descend more than this many directories below the root. With 1, only the files
+    /// directly in the root directory are hashed.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Hash the targets of symbolic links, and descend into linked directories. Otherwise links
+    /// are skipped, apart from the root itself.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Report symbolic links whose targets don't exist as errors, instead of skipping them.
+    pub fn error_on_broken_symlinks(mut self, error: bool) -> Self {
+        self.error_on_broken_symlinks = error;
+        self
+    }
+
+    /// Walk the tree under `root` in order by name, calling `visit` with the path of each regular
+    /// file to hash, or an error message for anything that couldn't be read.
+    ///
+    /// Directory loops found while following symbolic links are always reported as errors.
+    pub fn walk(&self, root: &Path, mut visit: impl FnMut(Result<&Path, String>)) {
+        let mut walk = WalkDir::new(root)
+            .sort_by_file_name()
+            .follow_links(self.follow_symlinks);
+        if let Some(max_depth) = self.max_depth {
+            walk = walk.max_depth(max_depth);
+        }
+        let walk = walk
+            .into_iter()
+            .filter_entry(|entry| {
+                let excluded = entry.depth() != 0
+                    && self.exclude.as_ref()
+                        .is_some_and(|exclude| exclude.is_match(relative(root, entry.path())));
+                if excluded {
+                    debug!(path = %entry.path().display(), "skipping excluded path");
+                }
+                !excluded
+            });
+        for entry in walk {
+            match entry {
+                Ok(entry) if entry.file_type().is_file() => {
+                    let included = self.include.as_ref()
+                        .is_none_or(|include| include.is_match(relative(root, entry.path())));
+                    if included {
+                        visit(Ok(entry.path()));
+                    } else {
+                        debug!(path = %entry.path().display(), "skipping file not included");
+                    }
+                }
+                Ok(entry) if entry.path_is_symlink() => {
+                    // Only reached when not following links.
+                    match entry.path().metadata() {
+                        Err(e) if self.error_on_broken_symlinks => {
+                            visit(Err(broken_symlink(entry.path(), &e)));
+                        }
+                        _ => debug!(path = %entry.path().display(), "skipping symbolic link"),
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => match (e.path(), e.io_error()) {
+                    (Some(path), Some(io_error))
+                        if e.loop_ancestor().is_none()
+                            && io_error.kind() == io::ErrorKind::NotFound
+                            && path.is_symlink() =>
+                    {
+                        if self.error_on_broken_symlinks {
+                            visit(Err(broken_symlink(path, io_error)));
+                        } else {
+                            debug!(path = %path.display(), "skipping broken symbolic link");
+                        }
+                    }
+                    _ => visit(Err(e.to_string())),
+                },
+            }
+        }
+    }
+
+    /// Whether a file at the given path under `root` would be hashed by [`walk`](Self::walk),
+    /// going by its path alone.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn accepts(&self, root: &Path, path: &Path) -> bool {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) if relative != Path::new("") => relative,
+            _ => return false,
+        };
+        let depth = relative.components().count();
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return false;
+        }
+        if let Some(exclude) = &self.exclude {
+            if relative.ancestors().any(|ancestor| exclude.is_match(ancestor)) {
+                return false;
+            }
+        }
+        self.include.as_ref().is_none_or(|include| include.is_match(relative))
+    }
+}
+
+fn broken_symlink(path: &Path, e: &io::Error) -> String {
+    format!("Broken symbolic link {:?}: {}", path, e)
+}
+
+fn relative<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn walk(walker: &Walker, root: &Path) -> (Vec<PathBuf>, usize) {
+        let mut files = vec![];
+        let mut errors = 0;
+        walker.walk(root, |entry| match entry {
+            Ok(path) => files.push(path.strip_prefix(root).unwrap().to_owned()),
+            Err(_) => errors += 1,
+        });
+        (files, errors)
+    }
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn filters() {
+        let temp = TempDir::new().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("d/e")).unwrap();
+        for file in &["a.txt", "b.tmp", "d/c.txt", "d/e/f.txt"] {
+            fs::write(root.join(file), file).unwrap();
+        }
+        let patterns = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let all = Walker::new(&[], &[]).unwrap();
+        assert_eq!((paths(&["a.txt", "b.tmp", "d/c.txt", "d/e/f.txt"]), 0), walk(&all, root));
+
+        let txt = Walker::new(&patterns(&["*.txt"]), &patterns(&["**/e"])).unwrap();
+        assert_eq!((paths(&["a.txt", "d/c.txt"]), 0), walk(&txt, root));
+
+        let shallow = Walker::new(&[], &patterns(&["*.tmp"])).unwrap().max_depth(Some(1));
+        assert_eq!((paths(&["a.txt"]), 0), walk(&shallow, root));
+
+        assert!(txt.accepts(root, &root.join("d/c.txt")));
+        assert!(!txt.accepts(root, &root.join("d/e/f.txt")));
+        assert!(!txt.accepts(root, &root.join("b.tmp")));
+        assert!(!shallow.accepts(root, &root.join("d/c.txt")));
+        assert!(!all.accepts(root, root));
+        assert!(!all.accepts(root, Path::new("/elsewhere/a.txt")));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+            std::os::unix::fs::symlink("missing", root.join("broken")).unwrap();
+            let shallow = shallow.error_on_broken_symlinks(true);
+            assert_eq!((paths(&["a.txt"]), 1), walk(&shallow, root));
+            let shallow = shallow.follow_symlinks(true);
+            assert_eq!((paths(&["a.txt", "link"]), 1), walk(&shallow, root));
+            let shallow = shallow.error_on_broken_symlinks(false);
+            assert_eq!((paths(&["a.txt", "link"]), 0), walk(&shallow, root));
+        }
+    }
+}
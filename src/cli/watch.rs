@@ -0,0 +1,123 @@
+//! Hashing files again whenever they change, for `--watch`.
+
+use super::walk::Walker;
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind};
+use notify::{RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use dropbox_content_hash::trace::debug;
+
+/// How long to wait for a file to stop changing before hashing it, so a file which is written in
+/// many pieces is only hashed once.
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// A change to a file being watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    /// The file was created or modified.
+    Modified(&'a Path),
+    /// The file was removed, or renamed to somewhere else.
+    Removed(&'a Path),
+}
+
+/// A path given on the command line, and what to watch for it.
+struct Root<'a> {
+    /// As given, for reporting changes.
+    path: &'a Path,
+    /// The absolute path, which events are reported with.
+    canonical: PathBuf,
+    /// Whether it's a directory being watched recursively, rather than a single file.
+    dir: bool,
+}
+
+/// Watch the given files (and with `recursive`, directories) until an error happens, calling
+/// `changed` for each file that has changed, once it stops changing. Files in directories are only
+/// reported if the walker accepts them.
+pub fn watch(
+    paths: &[PathBuf],
+    recursive: bool,
+    walker: &Walker,
+    mut changed: impl FnMut(Change<'_>),
+) -> notify::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    let mut roots = vec![];
+    for path in paths {
+        let canonical = fs::canonicalize(path)
+            .map_err(|e| notify::Error::io(e).add_path(path.clone()))?;
+        let dir = recursive && canonical.is_dir();
+        if dir {
+            watcher.watch(&canonical, RecursiveMode::Recursive)?;
+        } else {
+            // Watch the directory the file is in, so it's still seen if it's replaced by renaming
+            // another file over it.
+            let parent = canonical.parent().unwrap_or(&canonical);
+            watcher.watch(parent, RecursiveMode::NonRecursive)?;
+        }
+        roots.push(Root { path, canonical, dir });
+    }
+
+    loop {
+        let mut pending = BTreeSet::new();
+        let mut timeout = None;
+        loop {
+            let event = match timeout {
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                Some(timeout) => rx.recv_timeout(timeout),
+            };
+            let event = match event {
+                Ok(event) => event?,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            if !is_change(&event.kind) {
+                continue;
+            }
+            for path in event.paths {
+                if let Some(reported) = report_path(&roots, walker, &path) {
+                    pending.insert(reported);
+                    timeout = Some(SETTLE_TIME);
+                }
+            }
+        }
+
+        for path in pending {
+            match fs::symlink_metadata(&path) {
+                Ok(_) if path.is_file() => changed(Change::Modified(&path)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => changed(Change::Removed(&path)),
+                _ => debug!(path = %path.display(), "ignoring changed path which isn't a file"),
+            }
+        }
+    }
+}
+
+/// Whether an event may mean a file's contents changed, or that it was removed. Opening and
+/// reading files is ignored, especially since hashing them does that.
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        _ => false,
+    }
+}
+
+/// The path to report a change to the given file with, if it's one being watched.
+fn report_path(roots: &[Root<'_>], walker: &Walker, path: &Path) -> Option<PathBuf> {
+    for root in roots {
+        if root.dir {
+            if walker.accepts(&root.canonical, path) {
+                let relative = path.strip_prefix(&root.canonical).ok()?;
+                return Some(root.path.join(relative));
+            }
+        } else if path == root.canonical {
+            return Some(root.path.to_owned());
+        }
+    }
+    None
+}
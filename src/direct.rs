@@ -0,0 +1,197 @@
+//! Read files bypassing the operating system's page cache.
+//!
+//! Files opened this way have to be read into buffers that are aligned in memory, in multiples of
+//! the device's block size, which is taken care of internally by [`DirectReader`] and by the
+//! functions in [`parallel`](crate::parallel) that take a [`File`].
+//!
+//! This is supported on Linux (using `O_DIRECT`) and Windows (using `FILE_FLAG_NO_BUFFERING`).
+//! On Windows, reads have to be aligned to the volume's sector size, so [`open`] fails for
+//! volumes whose sectors are bigger than the 4 KiB used here; `FileBackend::Overlapped` in
+//! [`parallel`](crate::parallel) reads any file that way, in buffers aligned to its sector size.
+
+use crate::read::read_full;
+use crate::BLOCK_SIZE;
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+/// The alignment, in memory, file offset, and size, used for reads. This is the largest block
+/// size any storage device is likely to require.
+pub(crate) const ALIGNMENT: usize = 4096;
+
+/// Round the given length up to the next multiple of [`ALIGNMENT`].
+pub(crate) fn align_up(len: usize) -> usize {
+    len.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Open a file for reading, bypassing the page cache (using `O_DIRECT`).
+#[cfg(target_os = "linux")]
+pub fn open(path: impl AsRef<Path>) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+/// Open a file for reading, bypassing the file cache (using `FILE_FLAG_NO_BUFFERING`).
+///
+/// This fails if the volume's sectors are bigger than the alignment reads of the file are done
+/// with.
+#[cfg(windows)]
+pub fn open(path: impl AsRef<Path>) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_NO_BUFFERING;
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(FILE_FLAG_NO_BUFFERING)
+        .open(path)?;
+    let sector_size = sector_size(&file)?;
+    if sector_size > ALIGNMENT {
+        return Err(io::Error::other(format!(
+            "reading files without the file cache is not supported on volumes with {}-byte \
+            sectors", sector_size)));
+    }
+    Ok(file)
+}
+
+/// The size of the sectors of the volume the file is on, which reads of it have to be aligned to
+/// if it was opened without the file cache.
+#[cfg(windows)]
+pub(crate) fn sector_size(file: &File) -> io::Result<usize> {
+    use std::mem::{self, MaybeUninit};
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{FileStorageInfo, GetFileInformationByHandleEx,
+        FILE_STORAGE_INFO};
+    let mut info = MaybeUninit::<FILE_STORAGE_INFO>::uninit();
+    // SAFETY: the handle is valid for as long as the file is, and the buffer is the size given.
+    let ok = unsafe {
+        GetFileInformationByHandleEx(file.as_raw_handle(), FileStorageInfo,
+            info.as_mut_ptr().cast(), mem::size_of::<FILE_STORAGE_INFO>() as u32)
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: it was filled in.
+    let info = unsafe { info.assume_init() };
+    // The physical sector size can be bigger than the logical one, on drives which emulate 512-byte
+    // sectors, and unbuffered reads have to be aligned to whichever is bigger.
+    Ok(info.LogicalBytesPerSector.max(info.PhysicalBytesPerSectorForAtomicity) as usize)
+}
+
+/// Open a file for reading, bypassing the page cache.
+///
+/// This is not supported on this platform, and always returns an error.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn open(_path: impl AsRef<Path>) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "reading files without the page cache is not supported on this platform"))
+}
+
+/// A reader for a file opened with [`open`], which reads whole aligned blocks into an internal
+/// buffer, so that it can be used with [`ContentHasher::read_stream`] or anything else which reads
+/// into unaligned buffers.
+///
+/// [`ContentHasher::read_stream`]: crate::ContentHasher::read_stream
+pub struct DirectReader {
+    file: File,
+    buf: AlignedBuffer,
+    pos: usize,
+    len: usize,
+}
+
+impl DirectReader {
+    /// Wrap a file opened with [`open`].
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            buf: AlignedBuffer::new(BLOCK_SIZE),
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    /// Open a file for reading, bypassing the page cache.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        open(path).map(Self::new)
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.len {
+            self.pos = 0;
+            // Only the read at the end of the file can come up short, so it's safe to keep
+            // reading at the unaligned offset that follows one.
+            self.len = read_full(&mut self.file, &mut self.buf)?;
+        }
+        let n = buf.len().min(self.len - self.pos);
+        buf[.. n].copy_from_slice(&self.buf[self.pos .. self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// A zero-filled heap buffer whose address is a multiple of [`ALIGNMENT`], or of a bigger sector
+/// size.
+pub(crate) struct AlignedBuffer {
+    ptr: *mut u8,
+    len: usize,
+    alignment: usize,
+}
+
+// The buffer is uniquely owned, just like a Vec.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate a buffer of the given size, which must be a nonzero multiple of [`ALIGNMENT`].
+    pub fn new(len: usize) -> Self {
+        Self::with_alignment(len, ALIGNMENT)
+    }
+
+    /// Allocate a buffer of the given size and alignment. The alignment must be a power of two,
+    /// and the size a nonzero multiple of it.
+    pub fn with_alignment(len: usize, alignment: usize) -> Self {
+        assert!(len != 0 && len.is_multiple_of(alignment),
+            "buffer size must be a multiple of {}", alignment);
+        let layout = Self::layout(len, alignment);
+        // SAFETY: the layout has nonzero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, len, alignment }
+    }
+
+    fn layout(len: usize, alignment: usize) -> Layout {
+        Layout::from_size_align(len, alignment).expect("bad buffer layout")
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the pointer is valid for len bytes, which are initialized.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the pointer is valid for len bytes, which are initialized, and uniquely owned.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: the pointer was allocated with this same layout.
+        unsafe { alloc::dealloc(self.ptr, Self::layout(self.len, self.alignment)) }
+    }
+}
@@ -0,0 +1,235 @@
+//! Hash a file by path, picking the fastest reasonable way of doing it.
+
+use crate::{multibuffer, num_blocks, parallel, ContentHasher, BLOCK_SIZE, HASH_OUTPUT_SIZE};
+use crate::parallel::{Error, FileBackend};
+use crate::read::CountingReader;
+use crate::trace::{debug, debug_span};
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Options for [`content_hash_file`].
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    max_threads: Option<usize>,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    #[cfg(windows)]
+    overlapped: bool,
+}
+
+impl Options {
+    /// Use as many threads as there are logical CPUs, and don't memory-map files or bypass the
+    /// file cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use no more than the given number of threads. With 1, the file is hashed on the calling
+    /// thread.
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads.max(1));
+        self
+    }
+
+    /// Allow hashing files from a memory mapping. See [`FileBackend::Mmap`] for the caveats.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, allow: bool) -> Self {
+        self.mmap = allow;
+        self
+    }
+
+    /// Allow hashing files without the file cache, using [`FileBackend::Overlapped`]. This is
+    /// faster for big files which aren't already cached, and slower for those which are. It takes
+    /// precedence over allowing memory mapping.
+    #[cfg(windows)]
+    pub fn overlapped(mut self, allow: bool) -> Self {
+        self.overlapped = allow;
+        self
+    }
+}
+
+/// How [`content_hash_file`] hashed a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Read and hashed one block at a time, on the calling thread.
+    Serial,
+
+    /// Read and hashed [`multibuffer::LANES`] blocks at a time, on the calling thread.
+    Multibuffer,
+
+    /// Hashed in parallel using [`parallel::content_hash_from_file_with_backend`].
+    File {
+        /// The number of threads used.
+        threads: usize,
+        /// How the file was read.
+        backend: FileBackend,
+    },
+
+    /// Hashed in parallel using [`parallel::content_hash_from_stream`], because the file isn't a
+    /// regular file and can only be read from start to end.
+    Stream {
+        /// The number of threads used.
+        threads: usize,
+    },
+}
+
+/// Information about how a file was hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// How the file was hashed.
+    pub strategy: Strategy,
+    /// The number of bytes hashed.
+    pub bytes: u64,
+    /// How long it took, including opening the file.
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    /// The average hashing speed, in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Compute the content hash of the file at the given path.
+///
+/// This looks at the file's type and size and the number of CPUs available, and hashes small
+/// files on the calling thread and big ones in parallel. Files which aren't regular files (such as
+/// pipes) are read from start to end. Block devices are hashed like regular files.
+pub fn content_hash_file(
+    path: impl AsRef<Path>,
+    options: &Options,
+) -> Result<([u8; HASH_OUTPUT_SIZE], Stats), Error> {
+    let start = Instant::now();
+    let path = path.as_ref();
+    let _span = debug_span!("content_hash_file", path = %path.display()).entered();
+    let file = File::open(path)?;
+    let meta = file.metadata()?;
+    let mut threads = parallel::available_threads();
+    if let Some(max) = options.max_threads {
+        threads = threads.min(max);
+    }
+
+    let (hash, strategy, bytes) = if meta.is_file() || is_block_device(&meta) {
+        let len = len(&file)?;
+        threads = threads.min(num_blocks(len) as usize);
+        if threads > 1 {
+            #[cfg(feature = "mmap")]
+            let backend = if options.mmap { FileBackend::Mmap } else { FileBackend::Pread };
+            #[cfg(not(feature = "mmap"))]
+            let backend = FileBackend::Pread;
+            // Enough reads in flight that the next block for each thread is being read while it
+            // hashes the last one.
+            #[cfg(windows)]
+            let backend = if options.overlapped {
+                FileBackend::Overlapped { queue_depth: 2 * threads }
+            } else {
+                backend
+            };
+            let hash = parallel::content_hash_from_file_with_backend(&file, threads, backend)?;
+            (hash, Strategy::File { threads, backend }, len)
+        } else {
+            let mut reader = CountingReader::new(file);
+            let (hash, strategy) = serial_hash(&mut reader, len)?;
+            (hash, strategy, reader.count)
+        }
+    } else {
+        let mut reader = CountingReader::new(file);
+        let (hash, strategy) = if threads > 1 {
+            (parallel::content_hash_from_stream(&mut reader, threads)?,
+                Strategy::Stream { threads })
+        } else {
+            serial_hash(&mut reader, 0)?
+        };
+        (hash, strategy, reader.count)
+    };
+
+    let stats = Stats {
+        strategy,
+        bytes,
+        elapsed: start.elapsed(),
+    };
+    debug!(strategy = ?stats.strategy, bytes, elapsed = ?stats.elapsed, "hashed the file");
+    Ok((hash, stats))
+}
+
+/// The length of a file, or the size of a block device, which its metadata doesn't give.
+///
+/// For block devices, this seeks to the end to find the size, and then back again.
+pub fn len(file: &File) -> io::Result<u64> {
+    let meta = file.metadata()?;
+    if !is_block_device(&meta) {
+        return Ok(meta.len());
+    }
+    let mut file = file;
+    let position = file.stream_position()?;
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(position))?;
+    Ok(len)
+}
+
+#[cfg(unix)]
+pub(crate) fn is_block_device(meta: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    meta.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_block_device(_meta: &Metadata) -> bool {
+    false
+}
+
+/// Hash on the current thread, using [`multibuffer`] if it's faster and the file is big enough to
+/// benefit from it.
+fn serial_hash(
+    source: impl Read,
+    len: u64,
+) -> io::Result<([u8; HASH_OUTPUT_SIZE], Strategy)> {
+    if len >= (multibuffer::LANES * BLOCK_SIZE) as u64 && multibuffer::is_faster() {
+        Ok((multibuffer::content_hash_from_stream(source)?, Strategy::Multibuffer))
+    } else {
+        Ok((ContentHasher::from_stream(source)?.finish(), Strategy::Serial))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn matches_serial() {
+        let dir = TempDir::new().unwrap();
+        for &len in &[0, 5, 2 * BLOCK_SIZE + 1, 8 * BLOCK_SIZE] {
+            let data = (0 .. len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let path = dir.path().join(format!("file-{}", len));
+            std::fs::write(&path, &data).unwrap();
+            let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+
+            let (hash, stats) = content_hash_file(&path, &Options::new().max_threads(1)).unwrap();
+            assert_eq!(expected, hash, "len={} serial", len);
+            assert_eq!(len as u64, stats.bytes);
+            if multibuffer::is_faster() && len >= multibuffer::LANES * BLOCK_SIZE {
+                assert_eq!(Strategy::Multibuffer, stats.strategy);
+            } else {
+                assert_eq!(Strategy::Serial, stats.strategy);
+            }
+
+            let options = Options::new().max_threads(2);
+            #[cfg(feature = "mmap")]
+            let options = options.mmap(true);
+            #[cfg(windows)]
+            let options = options.overlapped(true);
+            let (hash, stats) = content_hash_file(&path, &options).unwrap();
+            assert_eq!(expected, hash, "len={} parallel", len);
+            assert_eq!(len as u64, stats.bytes);
+            if len > BLOCK_SIZE && parallel::available_threads() > 1 {
+                assert!(matches!(stats.strategy, Strategy::File { threads: 2, .. }),
+                    "len={} {:?}", len, stats.strategy);
+            }
+        }
+    }
+}
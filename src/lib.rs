@@ -9,11 +9,18 @@
 //! Dropbox keeps a Content Hash of each file stored, which can be quickly obtained through the
 //! API, and can be used to verify the integrity of files uploaded to or downloaded from Dropbox.
 
-use ring::digest::Context as HashContext;
-use ring::digest::SHA256;
+use crate::sha256::Context as HashContext;
+use crate::sha256::SHA256;
+use crate::trace::trace;
 
 use std::cell::Cell;
-use std::io::{self, Read};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, BufRead, IoSlice, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 /// The size of a Dropbox block: 4 MiB.
 pub const BLOCK_SIZE: usize = 4 * 1024 * 1024;
@@ -21,15 +28,124 @@ pub const BLOCK_SIZE: usize = 4 * 1024 * 1024;
 /// The size of the resulting content hash: 256 bits.
 pub const HASH_OUTPUT_SIZE: usize = 256 / 8;
 
+/// The content hash of an empty file, which is the SHA-256 hash of nothing, as it has no blocks.
+pub const EMPTY_CONTENT_HASH: [u8; HASH_OUTPUT_SIZE] = [
+    0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+    0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+];
+
+/// The number of the block containing the byte at the given offset.
+pub fn block_index(offset: u64) -> u64 {
+    offset / BLOCK_SIZE as u64
+}
+
+/// The range of byte offsets covered by the block with the given number. The last block of a file
+/// may be shorter than this; use `range.end.min(len)` to account for that.
+pub fn block_range(index: u64) -> Range<u64> {
+    let start = index * BLOCK_SIZE as u64;
+    start .. start + BLOCK_SIZE as u64
+}
+
+/// The number of blocks in a file of the given length. An empty file has no blocks.
+pub fn num_blocks(len: u64) -> u64 {
+    len.div_ceil(BLOCK_SIZE as u64)
+}
+
+pub mod blocks;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod direct;
+pub mod file;
+pub mod metrics;
+pub mod multibuffer;
 pub mod parallel;
+pub mod progress;
+#[doc(hidden)]
+pub mod read;
+#[cfg(feature = "digest")]
+mod rustcrypto;
+mod sha256;
+#[cfg(any(test, feature = "test-vectors"))]
+pub mod test_vectors;
+#[doc(hidden)]
+pub mod trace;
+#[cfg(all(unix, feature = "xattr"))]
+pub mod xattr;
+
+pub use file::content_hash_file;
+pub use metrics::Metrics;
+
+/// A function which is given each block's number and hash as they are computed.
+pub type BlockHashesFn = Box<dyn Fn(u64, &[u8])>;
+
+/// A function which is given the total number of bytes hashed so far, after each update.
+pub type ProgressFn = Box<dyn Fn(u64)>;
+
+/// A flag which can be set, from any thread, to stop a hash that is in progress.
+///
+/// Clones of a token share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Make a new token, which is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request that anything using this token stop as soon as possible.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`cancel`](Self::cancel) has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error returned when hashing is stopped using a [`CancelToken`].
+///
+/// Functions which return [`io::Error`] wrap this in an error of kind
+/// [`Other`](io::ErrorKind::Other); use [`Cancelled::is_cause_of`] to check for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// Whether the given I/O error was caused by cancellation.
+    pub fn is_cause_of(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("hashing was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(c: Cancelled) -> io::Error {
+        io::Error::other(c)
+    }
+}
 
 /// A context for multi-step Content Hash calculation.
 pub struct ContentHasher {
     ctx: HashContext,
     block_ctx: Cell<HashContext>,
     block_num: u64,
+    block_size: usize,
     partial: usize,
-    block_hashes_fn: Option<Box<dyn Fn(u64, &[u8])>>,
+    block_hashes_fn: Option<BlockHashesFn>,
+    cancel: Option<CancelToken>,
+    metrics: Option<(Arc<dyn Metrics>, Instant)>,
+    progress_fn: Option<ProgressFn>,
+    bytes: u64,
+    skip_zero_blocks: bool,
+    zero_hash: Option<[u8; HASH_OUTPUT_SIZE]>,
 }
 
 impl ContentHasher {
@@ -39,32 +155,122 @@ impl ContentHasher {
             ctx: HashContext::new(&SHA256),
             block_ctx: Cell::new(HashContext::new(&SHA256)),
             block_num: 0,
+            block_size: BLOCK_SIZE,
             partial: 0,
             block_hashes_fn: None,
+            cancel: None,
+            metrics: None,
+            progress_fn: None,
+            bytes: 0,
+            skip_zero_blocks: false,
+            zero_hash: None,
         }
     }
 
-    /// Create a new, empty, hasher that feeds block hashes to the given function.
-    pub fn with_block_hashes_fn(f: Box<dyn Fn(u64, &[u8])>) -> Self {
-        Self {
-            block_hashes_fn: Some(f),
-            .. Self::default()
-        }
+    /// Start setting up a hasher with more options than [`new`](Self::new) has.
+    pub fn builder() -> ContentHasherBuilder {
+        ContentHasherBuilder::default()
     }
 
-    /// Read and hash an arbitrary byte stream.
-    pub fn read_stream(&mut self, mut r: impl Read) -> io::Result<()> {
-        let mut buf = vec![0u8; BLOCK_SIZE];
+    /// Create a new, empty, hasher that feeds block hashes to the given function. This is the same
+    /// as `ContentHasher::builder().block_hashes_fn(f).build()`.
+    pub fn with_block_hashes_fn(f: BlockHashesFn) -> Self {
+        Self::builder().block_hashes_fn(f).build()
+    }
+
+    /// Make [`read_stream`](Self::read_stream) check the given token before each read, and stop
+    /// with a [`Cancelled`] error if it has been cancelled.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// Report the bytes read by [`read_stream`](Self::read_stream) or given to
+    /// [`update`](Self::update), each block hashed, and the time from now until
+    /// [`finish`](Self::finish) to the given metrics.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = Some((metrics, Instant::now()));
+    }
+
+    /// Hash the data in blocks of the given size instead of [`BLOCK_SIZE`], for chunked hashing
+    /// schemes other than Dropbox's. The result is not a Dropbox content hash unless the size is
+    /// [`BLOCK_SIZE`].
+    ///
+    /// Panics if the size is zero, or if any data has been hashed already.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        assert!(block_size != 0, "block size must not be zero");
+        assert!(self.block_num == 0 && self.partial == 0,
+            "block size must be set before hashing any data");
+        self.block_size = block_size;
+    }
+
+    /// The index of the block being filled: the number of whole blocks hashed so far.
+    pub fn current_block(&self) -> u64 {
+        self.block_num
+    }
+
+    /// The number of bytes in the block being filled, which haven't been hashed yet. This is zero
+    /// whenever the data given so far ends on a block boundary.
+    pub fn partial_len(&self) -> usize {
+        self.partial
+    }
+
+    /// Read and hash an arbitrary byte stream, returning the number of bytes read from it.
+    pub fn read_stream(&mut self, r: impl Read) -> io::Result<u64> {
+        self.read_stream_with_buffer(r, &mut vec![0u8; BLOCK_SIZE])
+    }
+
+    /// Like [`read_stream`](Self::read_stream), but read into the given buffer instead of
+    /// allocating a new one, so it can be reused for many streams. Any size of buffer works, but
+    /// reads smaller than a few KiB are slow.
+    pub fn read_stream_with_buffer(&mut self, mut r: impl Read, buf: &mut [u8]) -> io::Result<u64> {
+        assert!(!buf.is_empty(), "buffer must not be empty");
+        let mut total = 0;
         loop {
-            let nread = match r.read(&mut buf) {
+            if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+                return Err(Cancelled.into());
+            }
+            let nread = match r.read(buf) {
                 Ok(0) => break,
                 Ok(n) => n,
                 Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
                 Err(e) => return Err(e),
             };
-            self.update(&buf[0..nread]);
+            self.update_unmetered(&buf[0..nread]);
+            self.record_read(nread);
+            total += nread as u64;
         }
-        Ok(())
+        Ok(total)
+    }
+
+    /// Like [`read_stream`](Self::read_stream), but hash the data straight out of the reader's own
+    /// buffer, instead of copying it into another one first. This is better when the reader is
+    /// already buffered, like a `BufReader` or a decompressor.
+    pub fn read_bufread(&mut self, mut r: impl BufRead) -> io::Result<u64> {
+        let mut total = 0;
+        loop {
+            if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+                return Err(Cancelled.into());
+            }
+            let buf = match r.fill_buf() {
+                Ok([]) => break,
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let nread = buf.len();
+            self.update_unmetered(buf);
+            r.consume(nread);
+            self.record_read(nread);
+            total += nread as u64;
+        }
+        Ok(total)
+    }
+
+    fn record_read(&self, len: usize) {
+        if let Some((metrics, _)) = &self.metrics {
+            metrics.bytes_read(len as u64);
+        }
+        self.report_progress();
     }
 
     /// Convenience function to hash an arbitrary byte stream in one shot.
@@ -75,23 +281,88 @@ impl ContentHasher {
     }
 
     fn finish_block(&mut self) {
+        if let Some((metrics, _)) = &self.metrics {
+            metrics.block_hashed(if self.partial == 0 { self.block_size } else { self.partial });
+        }
         let block_hash = self.block_ctx
             .replace(HashContext::new(&SHA256))
             .finish();
+        trace!(block = self.block_num, "hashed a block");
+        self.add_block_hash(block_hash.as_ref());
+        self.partial = 0;
+    }
+
+    fn add_block_hash(&mut self, block_hash: &[u8]) {
         if let Some(f) = &self.block_hashes_fn {
-            f(self.block_num, block_hash.as_ref());
+            f(self.block_num, block_hash);
         }
-        self.ctx.update(block_hash.as_ref());
-        self.partial = 0;
+        self.ctx.update(block_hash);
         self.block_num += 1;
     }
 
+    /// Add the hashes of whole blocks which were hashed before, as if the blocks themselves had
+    /// been passed to [`update`](Self::update). Together with collecting the block hashes, this
+    /// lets a hash which was interrupted carry on from the end of its last whole block.
+    ///
+    /// Panics if the data hashed so far doesn't end on a block boundary.
+    pub fn update_block_hashes(&mut self, hashes: &[[u8; HASH_OUTPUT_SIZE]]) {
+        assert!(self.partial == 0, "block hashes must start on a block boundary");
+        trace!(block = self.block_num, count = hashes.len(), "adding blocks hashed before");
+        for hash in hashes {
+            self.add_block_hash(hash);
+        }
+        self.bytes += (hashes.len() * self.block_size) as u64;
+    }
+
+    /// Add `count` whole blocks of zero bytes to the hash, as if they had been passed to
+    /// [`update`](Self::update), but only hashing one block's worth of zeros. This is for
+    /// skipping over the holes in sparse files.
+    ///
+    /// Panics if the data hashed so far doesn't end on a block boundary.
+    pub fn update_zero_blocks(&mut self, count: u64) {
+        assert!(self.partial == 0, "zero blocks must start on a block boundary");
+        if count == 0 {
+            return;
+        }
+        trace!(block = self.block_num, count, "adding blocks of zeros");
+        let zero_hash = self.zero_hash();
+        for _ in 0 .. count {
+            self.add_block_hash(&zero_hash);
+        }
+        self.bytes += count * self.block_size as u64;
+    }
+
+    /// The hash of a whole block of zeros, which is only computed once.
+    fn zero_hash(&mut self) -> [u8; HASH_OUTPUT_SIZE] {
+        let block_size = self.block_size;
+        *self.zero_hash.get_or_insert_with(|| {
+            crate::sha256::digest(&SHA256, &vec![0u8; block_size]).as_ref().try_into().unwrap()
+        })
+    }
+
     /// Update the content hash with some data.
-    pub fn update(&mut self, mut bytes: &[u8]) {
+    pub fn update(&mut self, bytes: &[u8]) {
+        if let Some((metrics, _)) = &self.metrics {
+            metrics.bytes_read(bytes.len() as u64);
+        }
+        self.update_unmetered(bytes);
+        self.report_progress();
+    }
+
+    fn report_progress(&self) {
+        if let Some(f) = &self.progress_fn {
+            f(self.bytes);
+        }
+    }
+
+    /// Update the content hash with some data, without counting it as read, because the caller
+    /// already has.
+    fn update_unmetered(&mut self, mut bytes: &[u8]) {
+        self.bytes += bytes.len() as u64;
         // First, add to any partial block.
         if self.partial != 0 {
             // can we finish off the partial block?
-            let partial_needed = BLOCK_SIZE - self.partial;
+            let partial_needed = self.block_size - self.partial;
             let (first, remaining) = if partial_needed <= bytes.len() {
                 bytes.split_at(partial_needed)
             } else {
@@ -100,7 +371,7 @@ impl ContentHasher {
             };
             self.block_ctx.get_mut().update(first);
             self.partial += first.len();
-            if self.partial == BLOCK_SIZE {
+            if self.partial == self.block_size {
                 self.finish_block();
                 self.partial = 0;
             } else {
@@ -110,9 +381,20 @@ impl ContentHasher {
             bytes = remaining;
         }
 
-        for block in bytes.chunks(BLOCK_SIZE) {
+        for block in bytes.chunks(self.block_size) {
+            if self.skip_zero_blocks && block.len() == self.block_size
+                && block.iter().all(|&byte| byte == 0)
+            {
+                trace!(block = self.block_num, "skipped hashing a block of zeros");
+                if let Some((metrics, _)) = &self.metrics {
+                    metrics.block_hashed(self.block_size);
+                }
+                let zero_hash = self.zero_hash();
+                self.add_block_hash(&zero_hash);
+                continue;
+            }
             self.block_ctx.get_mut().update(block);
-            if block.len() < BLOCK_SIZE {
+            if block.len() < self.block_size {
                 // last block in this update
                 self.partial = block.len();
             } else {
@@ -121,6 +403,14 @@ impl ContentHasher {
         }
     }
 
+    /// Add the contents of several buffers to the hash, in order, as if they were one contiguous
+    /// buffer.
+    pub fn update_vectored(&mut self, bufs: &[IoSlice<'_>]) {
+        for buf in bufs {
+            self.update(buf);
+        }
+    }
+
     /// Finish the content hash and return the bytes.
     pub fn finish(mut self) -> [u8; HASH_OUTPUT_SIZE] {
         if self.partial != 0 {
@@ -128,6 +418,9 @@ impl ContentHasher {
         }
         let mut out = [0u8; HASH_OUTPUT_SIZE];
         out.copy_from_slice(self.ctx.finish().as_ref());
+        if let Some((metrics, start)) = &self.metrics {
+            metrics.finished(start.elapsed());
+        }
         out
     }
 
@@ -143,14 +436,162 @@ impl Default for ContentHasher {
     }
 }
 
+/// Options for a [`ContentHasher`], made with [`ContentHasher::builder`].
+///
+/// To hash on several threads, see [`parallel::Options`] instead, and to pick how files are read,
+/// [`file::Options`].
+#[derive(Default)]
+pub struct ContentHasherBuilder {
+    block_size: Option<usize>,
+    block_hashes_fn: Option<BlockHashesFn>,
+    progress_fn: Option<ProgressFn>,
+    cancel: Option<CancelToken>,
+    metrics: Option<Arc<dyn Metrics>>,
+    skip_zero_blocks: bool,
+}
+
+impl ContentHasherBuilder {
+    /// Hash the data in blocks of the given size instead of [`BLOCK_SIZE`]. See
+    /// [`ContentHasher::set_block_size`].
+    ///
+    /// Panics if the size is zero.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        assert!(block_size != 0, "block size must not be zero");
+        self.block_size = Some(block_size);
+        self
+    }
+
+    /// Give each block's number and hash to the given function as they are computed.
+    pub fn block_hashes_fn(mut self, f: BlockHashesFn) -> Self {
+        self.block_hashes_fn = Some(f);
+        self
+    }
+
+    /// Give the total number of bytes hashed so far to the given function after each update, and
+    /// after each read by [`ContentHasher::read_stream`].
+    pub fn progress_fn(mut self, f: ProgressFn) -> Self {
+        self.progress_fn = Some(f);
+        self
+    }
+
+    /// Check the given token before each read. See [`ContentHasher::set_cancel_token`].
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Report to the given metrics. The time is measured from when the hasher is built. See
+    /// [`ContentHasher::set_metrics`].
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Check whether each whole block is all zeros before hashing it, and if so, use the hash of
+    /// a block of zeros computed once. This makes data with long runs of zeros, such as disk
+    /// images, faster to hash, at the cost of a little time for every other block. Only blocks
+    /// passed to a single [`update`](ContentHasher::update) whole are checked.
+    pub fn skip_zero_blocks(mut self, skip: bool) -> Self {
+        self.skip_zero_blocks = skip;
+        self
+    }
+
+    /// Create the hasher.
+    pub fn build(self) -> ContentHasher {
+        let mut hasher = ContentHasher::new();
+        if let Some(block_size) = self.block_size {
+            hasher.set_block_size(block_size);
+        }
+        hasher.block_hashes_fn = self.block_hashes_fn;
+        hasher.progress_fn = self.progress_fn;
+        hasher.cancel = self.cancel;
+        if let Some(metrics) = self.metrics {
+            hasher.set_metrics(metrics);
+        }
+        hasher.skip_zero_blocks = self.skip_zero_blocks;
+        hasher
+    }
+}
+
+/// Compute the content hash of `len` bytes of the given source, starting at `offset`, as if they
+/// were a file of their own.
+///
+/// Returns an error of kind `UnexpectedEof` if the source ends before the end of the range.
+pub fn content_hash_of_range(
+    mut source: impl Read + Seek,
+    offset: u64,
+    len: u64,
+) -> io::Result<[u8; HASH_OUTPUT_SIZE]> {
+    source.seek(SeekFrom::Start(offset))?;
+    let mut ctx = ContentHasher::new();
+    let mut range = source.take(len);
+    ctx.read_stream(&mut range)?;
+    if range.limit() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("source ended at offset {:#x}, before the end of the range",
+                offset + len - range.limit())));
+    }
+    Ok(ctx.finish())
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
 /// Given a slice of bytes, return a hexadecimal string representation.
 pub fn hex_string(bytes: &[u8]) -> String {
-    bytes.iter().fold(String::new(), |s, byte| s + &format!("{:02x}", byte))
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for &byte in bytes {
+        s.push(char::from(HEX_DIGITS[usize::from(byte >> 4)]));
+        s.push(char::from(HEX_DIGITS[usize::from(byte & 0xf)]));
+    }
+    s
+}
+
+/// Parse a content hash (or block hash) written in hexadecimal, such as one from the Dropbox API
+/// or [`hex_string`]. Upper and lower case digits are both accepted.
+pub fn parse_hex(hex: &str) -> Result<[u8; HASH_OUTPUT_SIZE], ParseHexError> {
+    if hex.len() != 2 * HASH_OUTPUT_SIZE {
+        return Err(ParseHexError::WrongLength(hex.len()));
+    }
+    let digit = |i: usize| match hex.as_bytes()[i] {
+        c @ b'0' ..= b'9' => Ok(c - b'0'),
+        c @ b'a' ..= b'f' => Ok(c - b'a' + 10),
+        c @ b'A' ..= b'F' => Ok(c - b'A' + 10),
+        _ => Err(ParseHexError::InvalidDigit(i)),
+    };
+    let mut hash = [0u8; HASH_OUTPUT_SIZE];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = digit(2 * i)? << 4 | digit(2 * i + 1)?;
+    }
+    Ok(hash)
 }
 
+/// The error returned by [`parse_hex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseHexError {
+    /// The string isn't 64 bytes long; this is how long it is.
+    WrongLength(usize),
+    /// The string has something other than a hex digit at this byte offset.
+    InvalidDigit(usize),
+}
+
+impl fmt::Display for ParseHexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseHexError::WrongLength(len) => write!(f,
+                "expected {} hex digits, not {} characters", 2 * HASH_OUTPUT_SIZE, len),
+            ParseHexError::InvalidDigit(i) => write!(f, "invalid hex digit at offset {}", i),
+        }
+    }
+}
+
+impl std::error::Error for ParseHexError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn zero_bytes() {
@@ -163,6 +604,7 @@ mod tests {
 
         assert_eq!(&r1, &r2);
         assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", &r1);
+        assert_eq!(EMPTY_CONTENT_HASH, ContentHasher::new().finish());
     }
 
     #[test]
@@ -239,6 +681,195 @@ mod tests {
             &ctx.finish_str());
     }
 
+    #[test]
+    fn position() {
+        let mut ctx = ContentHasher::new();
+        assert_eq!((0, 0), (ctx.current_block(), ctx.partial_len()));
+        ctx.update(&[30; BLOCK_SIZE - 1]);
+        assert_eq!((0, BLOCK_SIZE - 1), (ctx.current_block(), ctx.partial_len()));
+        ctx.update(&[30; 2]);
+        assert_eq!((1, 1), (ctx.current_block(), ctx.partial_len()));
+        ctx.update(&[30; 2 * BLOCK_SIZE - 1]);
+        assert_eq!((3, 0), (ctx.current_block(), ctx.partial_len()));
+        ctx.update_zero_blocks(2);
+        assert_eq!((5, 0), (ctx.current_block(), ctx.partial_len()));
+    }
+
+    #[test]
+    fn cancel_read_stream() {
+        let token = CancelToken::new();
+        let mut ctx = ContentHasher::new();
+        ctx.set_cancel_token(token.clone());
+        token.cancel();
+        let err = ctx.read_stream(&b"hello"[..]).unwrap_err();
+        assert!(Cancelled::is_cause_of(&err));
+    }
+
+    #[test]
+    fn metrics() {
+        let counters = Arc::new(metrics::Counters::new());
+        let mut ctx = ContentHasher::new();
+        ctx.set_metrics(counters.clone());
+        ctx.read_stream(&vec![1u8; BLOCK_SIZE + 10][..]).unwrap();
+        ctx.update(&[2; 5]);
+        assert_eq!(1, counters.total_blocks_hashed());
+        ctx.finish();
+        assert_eq!(BLOCK_SIZE as u64 + 15, counters.total_bytes_read());
+        assert_eq!(2, counters.total_blocks_hashed());
+        assert_eq!(BLOCK_SIZE as u64 + 15, counters.total_bytes_hashed());
+        assert_eq!(1, counters.hashes_finished());
+    }
+
+    #[test]
+    fn read_stream_with_buffer() {
+        let data = vec![30u8; BLOCK_SIZE + 1];
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        let mut buf = vec![0u8; 1000];
+        for _ in 0 .. 2 {
+            let mut ctx = ContentHasher::new();
+            let len = ctx.read_stream_with_buffer(&data[..], &mut buf).unwrap();
+            assert_eq!(data.len() as u64, len);
+            assert_eq!(expected, ctx.finish());
+        }
+    }
+
+    #[test]
+    fn read_bufread() {
+        let data = (0 .. 2 * BLOCK_SIZE + 5).map(|i| i as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        for &capacity in &[1000, BLOCK_SIZE, 3 * BLOCK_SIZE] {
+            let mut ctx = ContentHasher::new();
+            let reader = io::BufReader::with_capacity(capacity, &data[..]);
+            assert_eq!(data.len() as u64, ctx.read_bufread(reader).unwrap());
+            assert_eq!(expected, ctx.finish(), "capacity={}", capacity);
+        }
+    }
+
+    #[test]
+    fn update_vectored() {
+        let data = (0 .. 2 * BLOCK_SIZE + 3).map(|i| i as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        let (a, rest) = data.split_at(1000);
+        let (b, c) = rest.split_at(BLOCK_SIZE);
+        let mut ctx = ContentHasher::new();
+        ctx.update_vectored(&[IoSlice::new(a), IoSlice::new(&[]), IoSlice::new(b)]);
+        ctx.update_vectored(&[IoSlice::new(c)]);
+        assert_eq!(expected, ctx.finish());
+    }
+
+    #[test]
+    fn block_size() {
+        let data = (0 .. 1000).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut expected = HashContext::new(&SHA256);
+        for block in data.chunks(300) {
+            expected.update(crate::sha256::digest(&SHA256, block).as_ref());
+        }
+        let mut ctx = ContentHasher::new();
+        ctx.set_block_size(300);
+        ctx.update(&data[.. 100]);
+        ctx.update(&data[100 ..]);
+        assert_eq!(expected.finish().as_ref(), &ctx.finish()[..]);
+    }
+
+    #[test]
+    fn builder() {
+        let mut data = vec![0u8; 3 * BLOCK_SIZE + 5];
+        data[BLOCK_SIZE] = 1;
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+
+        let blocks = Rc::new(RefCell::new(vec![]));
+        let progress = Rc::new(Cell::new(0));
+        let (f_blocks, f_progress) = (Rc::clone(&blocks), Rc::clone(&progress));
+        let mut ctx = ContentHasher::builder()
+            .block_hashes_fn(Box::new(move |block_num, _hash| {
+                f_blocks.borrow_mut().push(block_num)
+            }))
+            .progress_fn(Box::new(move |bytes| f_progress.set(bytes)))
+            .skip_zero_blocks(true)
+            .build();
+        ctx.update(&data[.. 2 * BLOCK_SIZE]);
+        assert_eq!(2 * BLOCK_SIZE as u64, progress.get());
+        ctx.update(&data[2 * BLOCK_SIZE ..]);
+        assert_eq!(expected, ctx.finish());
+        assert_eq!(vec![0, 1, 2, 3], *blocks.borrow());
+        assert_eq!(data.len() as u64, progress.get());
+
+        let ctx = ContentHasher::builder().block_size(300).build();
+        assert_eq!(300, ctx.block_size);
+    }
+
+    #[test]
+    fn zero_blocks() {
+        let mut data = vec![0u8; 3 * BLOCK_SIZE + 5];
+        data[.. 5].copy_from_slice(b"hello");
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        let mut ctx = ContentHasher::new();
+        ctx.update(&data[.. BLOCK_SIZE]);
+        ctx.update_zero_blocks(0);
+        ctx.update_zero_blocks(2);
+        ctx.update(&data[3 * BLOCK_SIZE ..]);
+        assert_eq!(expected, ctx.finish());
+    }
+
+    #[test]
+    fn resume() {
+        let data = (0 .. 2 * BLOCK_SIZE + 5).map(|i| i as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        let hashes = data.chunks(BLOCK_SIZE).take(2)
+            .map(|block| crate::sha256::digest(&SHA256, block).as_ref().try_into().unwrap())
+            .collect::<Vec<[u8; HASH_OUTPUT_SIZE]>>();
+        let mut ctx = ContentHasher::new();
+        ctx.update(&data[.. BLOCK_SIZE]);
+        ctx.update_block_hashes(&hashes[1 ..]);
+        ctx.update(&data[2 * BLOCK_SIZE ..]);
+        assert_eq!(expected, ctx.finish());
+    }
+
+    #[test]
+    fn hex() {
+        let bytes = (0 ..= 255).collect::<Vec<u8>>();
+        let expected = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        assert_eq!(expected, hex_string(&bytes));
+
+        let hex = "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50";
+        let hash = parse_hex(hex).unwrap();
+        assert_eq!(hex, hex_string(&hash));
+        assert_eq!(Ok(hash), parse_hex(&hex.to_ascii_uppercase()));
+        assert_eq!(Err(ParseHexError::WrongLength(62)), parse_hex(&hex[2 ..]));
+        assert_eq!(Err(ParseHexError::InvalidDigit(5)), parse_hex(&hex.replace("c9", "cg")));
+        // Non-ASCII characters take up more than one byte, but are still caught.
+        assert_eq!(Err(ParseHexError::InvalidDigit(0)), parse_hex(&format!("é{}", &hex[2 ..])));
+    }
+
+    #[test]
+    fn block_math() {
+        let b = BLOCK_SIZE as u64;
+        assert_eq!(0, block_index(0));
+        assert_eq!(0, block_index(b - 1));
+        assert_eq!(1, block_index(b));
+        assert_eq!(0 .. b, block_range(0));
+        assert_eq!(2 * b .. 3 * b, block_range(2));
+        assert_eq!(0, num_blocks(0));
+        assert_eq!(1, num_blocks(1));
+        assert_eq!(1, num_blocks(b));
+        assert_eq!(2, num_blocks(b + 1));
+    }
+
+    #[test]
+    fn range() {
+        let data = (0 .. BLOCK_SIZE * 2).map(|i| i as u8).collect::<Vec<u8>>();
+        let range = &data[1000 .. BLOCK_SIZE + 2000];
+        let expected = ContentHasher::from_stream(range).unwrap().finish();
+        let cursor = io::Cursor::new(&data);
+        assert_eq!(expected,
+            content_hash_of_range(cursor, 1000, range.len() as u64).unwrap());
+
+        let cursor = io::Cursor::new(&data);
+        let err = content_hash_of_range(cursor, BLOCK_SIZE as u64, BLOCK_SIZE as u64 + 1)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
     #[test]
     fn partial_blocks_2() {
         let mut ctx = ContentHasher::new();
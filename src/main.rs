@@ -1,99 +1,1680 @@
 use dropbox_content_hash::*;
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::PathBuf;
+use dropbox_content_hash::blocks::BlockHash;
+use std::fs::{File, Metadata};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use structopt::clap::{ArgMatches, Shell};
 use structopt::StructOpt;
 
+mod cli;
+
+use cli::Hashed;
+use cli::blocks::BlockWriter;
+use cli::check::Verbosity;
+use cli::config::Config;
+use cli::checkpoint::Checkpoint;
+use cli::digests::{DigestReader, Digests};
+use cli::output::{AtomicFile, Output, Paths, PlainStyle};
+use cli::retry::RetryingReader;
+use cli::stats::Stats;
+use cli::progress::{FileProgress, Mode as ProgressMode, Progress};
+use cli::walk::Walker;
+#[cfg(feature = "watch")]
+use cli::watch::Change;
+use dropbox_content_hash::read::CountingReader;
+use dropbox_content_hash::trace::{debug, info, info_span};
+
+/// The names accepted by --log-level.
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
 /// Calculate and print the Dropbox Content Hash of the given file.
 #[derive(StructOpt)]
 struct Args {
-    /// If specified, run the computation in parallel on the given number of threads.
-    #[structopt(long)]
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// If specified, run the computation in parallel on the given number of threads. This can be
+    /// "auto" to use one per logical CPU, or a percentage of them, such as "50%".
+    #[structopt(long, global = true, value_name = "N", parse(try_from_str = cli::parse_threads))]
     threads: Option<usize>,
 
-    /// Path to the file to hash.
+    /// Hash up to this many files at once, each on its own thread. Results are still printed in
+    /// the same order as the files are given.
+    #[structopt(short, long, value_name = "N", default_value = "1", global = true)]
+    jobs: usize,
+
+    /// Paths to the files to hash. If none are given (and there's no --files-from), or for "-",
+    /// standard input is hashed. With the "http" feature, these can also be HTTP or HTTPS URLs,
+    /// and with the "cloud" feature, "s3://", "gs://", or "az://" URLs of objects in cloud
+    /// storage, which are hashed as they're downloaded. Credentials for cloud storage are taken
+    /// from the usual environment variables, such as AWS_ACCESS_KEY_ID.
     #[structopt(parse(from_os_str))]
-    path: PathBuf,
+    paths: Vec<PathBuf>,
 
     /// Print block hashes as well as the final hash.
     #[structopt(long = "blocks")]
     print_block_hashes: bool,
+
+    /// Also compute these digests of each file while it's read, separated by commas: "md5",
+    /// "sha1", "sha256", "sha512", or "blake3". MD5 and BLAKE3 require the "md5" and "blake3"
+    /// features.
+    #[structopt(long, value_name = "digests", use_delimiter = true,
+        possible_values = cli::digests::Algorithm::NAMES,
+        conflicts_with_all = &["pread", "uring", "overlapped", "checkpoint"])]
+    also: Vec<cli::digests::Algorithm>,
+
+    /// Write the hashes of each file's blocks to the given file, instead of printing them.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    blocks_out: Option<PathBuf>,
+
+    /// The format of the file written by --blocks-out. "text" has a "# PATH" line for each file,
+    /// followed by an "INDEX OFFSET HASH" line for each block. "json" has an object for each file
+    /// on a line of its own, giving its "path", "size", and "blocks", an array of objects with
+    /// each block's "index", "offset", and "hash".
+    #[structopt(long, value_name = "format", default_value = "text",
+        possible_values = cli::blocks::Format::NAMES)]
+    blocks_format: cli::blocks::Format,
+
+    /// With --threads, have every thread read its own blocks from the file using positioned
+    /// reads, instead of using a single reader thread. Progress is not shown in this mode.
+    #[structopt(long, requires = "threads", global = true)]
+    pread: bool,
+
+    /// With --threads, read the file using io_uring, keeping up to the given number of block
+    /// reads in flight. Progress is not shown in this mode. Requires the "uring" feature.
+    #[structopt(long, requires = "threads", conflicts_with = "pread", value_name = "queue depth",
+        global = true)]
+    uring: Option<usize>,
+
+    /// With --threads, read the file without the file cache using overlapped I/O, keeping up to
+    /// the given number of block reads in flight. Progress is not shown in this mode. Only
+    /// supported on Windows.
+    #[structopt(long, requires = "threads", conflicts_with_all = &["pread", "uring"],
+        value_name = "queue depth", global = true)]
+    overlapped: Option<usize>,
+
+    /// Read the file without going through the operating system's page cache. Only supported on
+    /// Linux and Windows.
+    #[structopt(long, global = true)]
+    direct: bool,
+
+    /// Hash every regular file in the given directories and their subdirectories, in order by
+    /// name.
+    #[structopt(short, long)]
+    recursive: bool,
+
+    /// With --recursive, only hash files whose path within the directory matches the given glob
+    /// pattern, such as "*.mkv". May be given more than once, to hash files matching any of them.
+    #[structopt(long, value_name = "pattern", number_of_values = 1, requires = "recursive")]
+    include: Vec<String>,
+
+    /// With --recursive, skip files and directories whose path within the directory matches the
+    /// given glob pattern, such as ".git" or "*.tmp". May be given more than once.
+    #[structopt(long, value_name = "pattern", number_of_values = 1, requires = "recursive")]
+    exclude: Vec<String>,
+
+    /// With --recursive, descend at most this many levels below each directory. With 1, only the
+    /// files directly inside it are hashed.
+    #[structopt(long, value_name = "depth", requires = "recursive")]
+    max_depth: Option<usize>,
+
+    /// With --recursive, hash the targets of symbolic links and descend into linked directories.
+    #[structopt(short = "L", long, requires = "recursive", overrides_with = "no-follow")]
+    follow_symlinks: bool,
+
+    /// With --recursive, skip symbolic links found in directories. This is the default.
+    #[structopt(short = "P", long, requires = "recursive", overrides_with = "follow-symlinks")]
+    no_follow: bool,
+
+    /// With --recursive, treat symbolic links whose targets don't exist as errors, instead of
+    /// skipping them.
+    #[structopt(long, requires = "recursive")]
+    error_on_broken_symlinks: bool,
+
+    /// Print each hash followed by two spaces and the file's path, like sha256sum does. This is
+    /// the default when hashing more than one file.
+    #[structopt(short = "H", long)]
+    with_filename: bool,
+
+    /// Print BSD-style "DropboxContentHash (PATH) = HASH" lines.
+    #[structopt(long)]
+    tag: bool,
+
+    /// Print lines in the format of `rclone hashsum dropbox`, with each path relative to the file
+    /// or directory given, so they can be compared with the hashes rclone gets from Dropbox. With
+    /// --check, read a manifest in that format, and look for the files under the directory given.
+    #[structopt(long, conflicts_with_all = &["tag", "with-filename", "files-from"])]
+    rclone: bool,
+
+    /// Print a manifest which gives the size and modification time of each file as well as its
+    /// hash and path, as "HASH  SIZE  MTIME  PATH" lines after a header line. The time is in
+    /// seconds since 1970, or "-" if it isn't known. --check and the diff subcommand read these.
+    #[structopt(long, conflicts_with_all = &["tag", "rclone", "print-block-hashes", "also"])]
+    manifest: bool,
+
+    /// Print the results in order by path once every file has been hashed, instead of as each one
+    /// is finished, so the output is the same whatever order the paths were given in. Files found
+    /// by --recursive are always in order by name within each directory.
+    #[structopt(long)]
+    sort: bool,
+
+    /// Print the absolute path of each file, without resolving symbolic links.
+    #[structopt(long, conflicts_with = "rclone")]
+    absolute: bool,
+
+    /// Print the path of each file relative to the given directory, using ".." where needed, so
+    /// manifests don't depend on where the files were when they were hashed.
+    #[structopt(long, value_name = "dir", parse(from_os_str),
+        conflicts_with_all = &["rclone", "absolute"])]
+    relative_to: Option<PathBuf>,
+
+    /// End each line with a NUL byte instead of a newline, and write paths as they are. Without
+    /// this, paths containing a backslash, newline, or carriage return are escaped, and the line
+    /// starts with a backslash, as sha256sum does. With --check, read a manifest written this way.
+    #[structopt(short, long)]
+    zero: bool,
+
+    /// How to write hashes: "hex", "HEX" for uppercase hexadecimal, "base64", or "base64url" for
+    /// base64 with "-" and "_" in place of "+" and "/", and no padding. --check only reads
+    /// lowercase or uppercase hexadecimal.
+    #[structopt(long, value_name = "encoding", default_value = "hex",
+        possible_values = cli::output::Encoding::NAMES, global = true)]
+    encoding: cli::output::Encoding,
+
+    /// Output format. "json" prints an array with an object for each file, giving its path, size,
+    /// content hash, and block hashes if --blocks is given. "jsonl" prints the same objects one
+    /// per line, as each file is finished. "csv" prints a header row and then the path, size,
+    /// content hash, time taken in milliseconds, and error for each file, with the size and hash
+    /// left blank if it failed. "hashdeep" prints an audit file like hashdeep's, with the size,
+    /// content hash, and path of each file.
+    #[structopt(long, default_value = "plain", possible_values = cli::output::Format::NAMES)]
+    format: cli::output::Format,
+
+    /// Also hash the files listed in the given file (or "-" for standard input), one per line.
+    #[structopt(long, value_name = "list", parse(from_os_str))]
+    files_from: Option<PathBuf>,
+
+    /// Paths given to --files-from are separated by NUL bytes instead of newlines, as printed by
+    /// `find -print0`.
+    #[structopt(short = "0", long, requires = "files-from")]
+    null: bool,
+
+    /// Read a manifest written by --blocks-out (or "-" for standard input), and check each file
+    /// listed in it block by block, reporting every block which doesn't match.
+    #[structopt(long, value_name = "manifest", parse(from_os_str),
+        conflicts_with_all = &["paths", "check"])]
+    verify_blocks: Option<PathBuf>,
+
+    /// Read no faster than the given rate, such as "100MiB/s", in total across all files being
+    /// hashed at once.
+    #[structopt(long, value_name = "rate",
+        conflicts_with_all = &["pread", "uring", "overlapped"], global = true)]
+    throttle: Option<cli::throttle::Throttle>,
+
+    /// If reading a file fails, try again up to this many times, starting from where the failed
+    /// read started, before giving up on the file.
+    #[structopt(long, value_name = "N",
+        conflicts_with_all = &["pread", "uring", "overlapped", "direct"], global = true)]
+    retries: Option<u32>,
+
+    /// How long to wait before each retry, such as "500ms" or "5s".
+    #[structopt(long, value_name = "duration", default_value = "1s",
+        parse(try_from_str = cli::parse_duration), global = true)]
+    retry_delay: Duration,
+
+    /// Hash in blocks of the given size, such as 1MiB, instead of 4 MiB. The results are NOT
+    /// Dropbox content hashes unless the size is 4 MiB; this is for experimenting and for other
+    /// chunked hashing schemes.
+    #[structopt(long, value_name = "size", parse(try_from_str = cli::parse_size),
+        conflicts_with_all = &["threads", "blocks-out"])]
+    block_size: Option<u64>,
+
+    /// Save the hashes of the blocks hashed so far to this file every 30 seconds, and if it
+    /// exists when starting, skip the blocks listed in it, so a hash which was interrupted can
+    /// carry on where it left off. It's removed once the hash is done. Only one file can be hashed
+    /// this way at a time, and it must not have changed in the meantime.
+    #[structopt(long, value_name = "path", parse(from_os_str),
+        conflicts_with_all = &["threads", "uring", "overlapped", "direct", "offset", "length",
+            "block-size", "recursive", "files-from", "check", "verify-blocks"])]
+    checkpoint: Option<PathBuf>,
+
+    /// Skip this many bytes at the start of each file, such as 512 or 1MiB, and hash the rest as
+    /// if it were a file of its own.
+    #[structopt(long, value_name = "size", parse(try_from_str = cli::parse_size),
+        conflicts_with_all = &["pread", "uring", "overlapped", "direct"])]
+    offset: Option<u64>,
+
+    /// Hash only this many bytes of each file (after --offset, if given). It's an error if the
+    /// file is shorter than that.
+    #[structopt(long, value_name = "size", parse(try_from_str = cli::parse_size),
+        conflicts_with_all = &["pread", "uring", "overlapped", "direct"])]
+    length: Option<u64>,
+
+    /// Check that the file has the given content hash, printing whether it does and exiting with
+    /// status 0 if so, or 1 if not.
+    #[structopt(long, value_name = "hash", parse(try_from_str = parse_expected),
+        conflicts_with_all = &["check", "recursive", "files-from"])]
+    expected: Option<[u8; HASH_OUTPUT_SIZE]>,
+
+    /// Treat each file given as a tar archive, and hash each regular file inside it without
+    /// extracting anything, printing the path it has in the archive. Requires the "tar" feature.
+    #[structopt(long,
+        conflicts_with_all = &["pread", "uring", "overlapped", "direct", "retries", "offset",
+            "length", "checkpoint", "recursive", "check", "verify-blocks", "expected"])]
+    tar: bool,
+
+    /// The same as --tar, but for zip archives. Files inside them can be stored or compressed
+    /// with deflate. Standard input can't be read this way. Requires the "zip" feature.
+    #[structopt(long,
+        conflicts_with_all = &["pread", "uring", "overlapped", "direct", "retries", "offset",
+            "length", "checkpoint", "recursive", "check", "verify-blocks", "expected", "tar"])]
+    zip: bool,
+
+    /// Write the hashes to this file instead of standard output. It's written under a temporary
+    /// name and only replaces the file once everything has been hashed, so if this is
+    /// interrupted, an existing file is left alone. Paths are always included.
+    #[structopt(short, long = "output", value_name = "path", parse(from_os_str),
+        conflicts_with_all = &["check", "verify-blocks", "expected", "tee", "watch"])]
+    output_file: Option<PathBuf>,
+
+    /// With --output, add to the end of the file instead of replacing it.
+    #[structopt(long, requires = "output-file")]
+    append: bool,
+
+    /// With --output, sign the file with this minisign secret key once it's written, putting the
+    /// signature next to it with ".minisig" added to its name. If the key is encrypted, its
+    /// password is asked for. Requires the "sign" feature.
+    #[structopt(long, value_name = "key", parse(from_os_str), requires = "output-file")]
+    sign_key: Option<PathBuf>,
+
+    /// Copy standard input to standard output unchanged while hashing it, and print the content
+    /// hash to standard error at the end, so it can go in the middle of a pipeline.
+    #[structopt(long,
+        conflicts_with_all = &["recursive", "files-from", "check", "verify-blocks", "expected",
+            "checkpoint", "offset", "length", "tar", "zip"])]
+    tee: bool,
+
+    /// After hashing, keep watching the files (and with --recursive, directories) given, and hash
+    /// each file again whenever it changes. Requires the "watch" feature.
+    #[structopt(long,
+        conflicts_with_all = &["files-from", "check", "verify-blocks", "expected", "checkpoint",
+            "tar", "zip", "tee"])]
+    watch: bool,
+
+    /// Instead of hashing files, listen for HTTP requests on the given address, such as
+    /// "127.0.0.1:8080", with --jobs threads. "POST /hash" hashes the request body, and "POST
+    /// /hash/path" hashes the local file given as {"path": PATH} in the body. Each responds with a
+    /// JSON object like --format json prints, with block hashes if "?blocks" is added to the URL.
+    /// Anyone who can connect can hash any file this can read. Requires the "serve" feature.
+    #[structopt(long, value_name = "addr",
+        conflicts_with_all = &["paths", "files-from", "recursive", "check", "verify-blocks",
+            "expected", "output-file", "blocks-out", "checkpoint", "tar", "zip", "tee", "watch"])]
+    serve: Option<String>,
+
+    /// Don't show progress or warnings, and with --check, --verify-blocks, or --expected, don't
+    /// print anything for files which match.
+    #[structopt(long)]
+    quiet: bool,
+
+    /// With --check, --verify-blocks, or --expected, don't print anything; the exit status shows
+    /// whether everything matched.
+    #[structopt(long)]
+    status: bool,
+
+    /// When done, print a summary to standard error of the number of files and bytes hashed, the
+    /// time taken, the average throughput, and the number of errors.
+    #[structopt(long)]
+    stats: bool,
+
+    /// The format of the --stats summary: "text", or "json" for an object giving "files",
+    /// "bytes", "elapsed_seconds", "bytes_per_second", and "errors".
+    #[structopt(long, value_name = "format", default_value = "text",
+        possible_values = cli::stats::Format::NAMES)]
+    stats_format: cli::stats::Format,
+
+    /// Log more detail about what's happening to standard error, such as how each file is read,
+    /// how long each one takes, and which files are skipped. Give twice for even more. Without
+    /// the "tracing" feature, only warnings are logged.
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    /// How much to log to standard error: "off", "error", "warn" (the default, or "error" with
+    /// --quiet), "info" (the same as -v), "debug" (-vv), or "trace".
+    #[structopt(long, value_name = "level", possible_values = LOG_LEVELS, global = true)]
+    log_level: Option<String>,
+
+    /// Read defaults for --threads, --progress, --format, and --exclude from this TOML file,
+    /// instead of from dropbox-content-hash.toml in $XDG_CONFIG_HOME or ~/.config. Options given
+    /// on the command line take precedence.
+    #[structopt(long, value_name = "path", parse(from_os_str), global = true)]
+    config: Option<PathBuf>,
+
+    /// Don't read a configuration file.
+    #[structopt(long, conflicts_with = "config", global = true)]
+    no_config: bool,
+
+    /// How to show progress on standard error: "auto" for progress bars if it's a terminal,
+    /// "always" for progress bars even if it isn't, "never", or "json" to write an object giving
+    /// "current_file", "bytes_done", and "bytes_total" on a line of its own every half a second
+    /// while each file is hashed, and when it's done.
+    #[structopt(long, value_name = "mode", default_value = "auto",
+        possible_values = cli::progress::Mode::NAMES, global = true)]
+    progress: cli::progress::Mode,
+
+    /// Don't show progress. The same as --progress=never.
+    #[structopt(long, global = true)]
+    no_progress: bool,
+
+    /// With --progress=json, write the progress events to the given file (such as /dev/fd/3)
+    /// instead of standard error.
+    #[structopt(long, value_name = "path", parse(from_os_str), global = true)]
+    progress_file: Option<PathBuf>,
+
+    /// Read a list of hashes and paths in the format printed by -H or --tag (or "-" for standard
+    /// input), and check that each file still has the listed hash.
+    #[structopt(short, long, value_name = "manifest", parse(from_os_str))]
+    check: Option<PathBuf>,
+
+    /// With --check, skip files which don't exist instead of reporting them as failures.
+    #[structopt(long, requires = "check")]
+    ignore_missing: bool,
+
+    /// With --check, fail if any line of the manifest is improperly formatted.
+    #[structopt(long, requires = "check")]
+    strict: bool,
+
+    /// With --check, warn about each improperly formatted line of the manifest, instead of only
+    /// saying how many there were.
+    #[structopt(short, long, requires = "check")]
+    warn: bool,
+
+    /// With --check, first check the manifest's signature (in the file with ".minisig" added to
+    /// its name) with this minisign public key, and stop if it isn't signed by that key. Requires
+    /// the "sign" feature.
+    #[structopt(long, value_name = "key", parse(from_os_str), requires = "check")]
+    verify_key: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Hash two files and report whether their content hashes match, exiting with status 0 if
+    /// they do, or 1 if not. With --jobs 2 or more, both are hashed at once.
+    Compare {
+        #[structopt(parse(from_os_str))]
+        file_a: PathBuf,
+        #[structopt(parse(from_os_str))]
+        file_b: PathBuf,
+
+        /// Read both files block by block and stop at the first 4 MiB block which differs,
+        /// reporting its number and byte offset.
+        #[structopt(long)]
+        first_diff: bool,
+    },
+
+    /// Compare local files with files stored in Dropbox, using the Dropbox API. Requires the
+    /// "dropbox" feature.
+    Dropbox {
+        /// A Dropbox API access token.
+        #[structopt(long, env = "DROPBOX_TOKEN", hide_env_values = true)]
+        token: String,
+
+        #[structopt(subcommand)]
+        command: DropboxCommand,
+    },
+
+    /// Compare two manifests written by this program or by sha256sum-style tools, and list the
+    /// files which were added, removed, or changed in the second one, without hashing anything.
+    /// Exits with status 0 if they're the same, or 1 if not.
+    Diff {
+        #[structopt(parse(from_os_str))]
+        manifest_a: PathBuf,
+        #[structopt(parse(from_os_str))]
+        manifest_b: PathBuf,
+    },
+
+    /// Find files with the same contents in the given directories and their subdirectories, and
+    /// list each set of them along with how much space could be freed by removing the extra
+    /// copies. Only files which are the same size as another are hashed, with --jobs at once.
+    Dupes {
+        #[structopt(parse(from_os_str), required = true)]
+        dirs: Vec<PathBuf>,
+    },
+
+    /// Add every file in the given directories and their subdirectories which isn't in a database
+    /// of content hashes and block hashes (or has changed since) to it, then keep watching them
+    /// and updating the database as files change, until interrupted. Requires the "cache" and
+    /// "watch" features.
+    Index {
+        /// The database file, which is created if it doesn't exist.
+        #[structopt(long, value_name = "path", parse(from_os_str))]
+        db: PathBuf,
+
+        #[structopt(parse(from_os_str), required = true)]
+        dirs: Vec<PathBuf>,
+    },
+
+    /// Print the content hashes of files from a database kept by "index", for those which haven't
+    /// changed since they were hashed. Exits with status 0 if all were found, or 1 if not.
+    /// Requires the "cache" feature.
+    Query {
+        /// The database file.
+        #[structopt(long, value_name = "path", parse(from_os_str))]
+        db: PathBuf,
+
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Print a completion script for the given shell to standard output.
+    Completions {
+        #[structopt(possible_values = &Shell::variants())]
+        shell: Shell,
+    },
+}
+
+#[derive(StructOpt)]
+#[cfg_attr(not(feature = "dropbox"), allow(dead_code))]
+enum DropboxCommand {
+    /// Hash a local file and check that it has the same content hash as a file in Dropbox, such
+    /// as "/Photos/cat.jpg", exiting with status 0 if so, or 1 if not.
+    Verify {
+        #[structopt(parse(from_os_str))]
+        local_path: PathBuf,
+        dropbox_path: String,
+    },
+
+    /// Compare the files in a local directory and its subdirectories with those in a Dropbox
+    /// folder, listing which match, which differ, and which are only in one place. Exits with
+    /// status 0 if every file matches, or 1 if not.
+    Compare {
+        #[structopt(parse(from_os_str))]
+        local_dir: PathBuf,
+        dropbox_folder: String,
+    },
 }
 
 fn main() {
-    let args = Args::from_args();
+    let matches = Args::clap().get_matches();
+    let mut args = Args::from_clap(&matches);
+    apply_config(&mut args, &matches);
+    init_logging(&args);
+
+    match args.block_size {
+        Some(0) => {
+            eprintln!("The block size must not be zero");
+            exit(2);
+        }
+        Some(size) if size != BLOCK_SIZE as u64 && !args.quiet => {
+            eprintln!("WARNING: with a block size other than 4 MiB, the hashes are not Dropbox \
+                content hashes");
+        }
+        _ => (),
+    }
+
+    if cfg!(target_os = "wasi") && (args.threads.is_some() || args.jobs > 1) {
+        eprintln!("WASI has no threads, so --threads and --jobs can't be used");
+        exit(2);
+    }
+
+    if args.status && args.check.is_none() && args.verify_blocks.is_none()
+        && args.expected.is_none()
+    {
+        eprintln!("--status can only be used with --check, --verify-blocks, or --expected");
+        exit(2);
+    }
 
-    let file = File::open(&args.path)
+    if (args.sign_key.is_some() || args.verify_key.is_some()) && cfg!(not(feature = "sign")) {
+        eprintln!("Signing manifests is not available in this build");
+        exit(2);
+    }
+
+    if args.watch {
+        if cfg!(not(feature = "watch")) {
+            eprintln!("Watching for changes is not available in this build");
+            exit(2);
+        }
+        if args.paths.is_empty() || args.paths.iter().any(|path| path == Path::new("-")) {
+            eprintln!("--watch needs the paths of files or directories to watch");
+            exit(2);
+        }
+        if args.format == cli::output::Format::Json {
+            eprintln!("--watch can't be used with --format json; use jsonl instead");
+            exit(2);
+        }
+    }
+
+    if args.check.is_some() && !args.paths.is_empty() && !(args.rclone && args.paths.len() == 1) {
+        eprintln!("--check takes no paths, except with --rclone, the directory the manifest is \
+            relative to");
+        exit(2);
+    }
+
+    if args.manifest && args.format != cli::output::Format::Plain {
+        eprintln!("--manifest can only be used with --format plain");
+        exit(2);
+    }
+
+    if args.rclone && args.check.is_none() {
+        if args.paths.len() != 1 || args.paths[0] == Path::new("-") {
+            eprintln!("--rclone needs exactly one file or directory to hash");
+            exit(2);
+        }
+        if args.format != cli::output::Format::Plain {
+            eprintln!("--rclone can only be used with --format plain");
+            exit(2);
+        }
+    }
+
+    if args.checkpoint.is_some() && (args.paths.len() != 1 || args.paths[0] == Path::new("-")) {
+        eprintln!("--checkpoint needs exactly one file to hash");
+        exit(2);
+    }
+
+    match &args.command {
+        Some(Command::Compare { file_a, file_b, first_diff }) => {
+            let progress = progress(&args, false);
+            if *first_diff {
+                exit(cli::compare::first_diff(file_a, file_b, args.jobs, &progress));
+            }
+            exit(cli::compare::run(file_a, file_b, args.jobs,
+                |path| hash_path(&args, &progress, path)));
+        }
+        Some(Command::Dropbox { token, command }) => exit(dropbox(&args, token, command)),
+        Some(Command::Diff { manifest_a, manifest_b }) => {
+            match cli::diff::run(manifest_a, manifest_b) {
+                Ok(code) => exit(code),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(2);
+                }
+            }
+        }
+        Some(Command::Dupes { dirs }) => {
+            let progress = progress(&args, true);
+            let code = cli::dupes::run(dirs, &Walker::new(&[], &[]).unwrap(), args.jobs,
+                |path| hash_path(&args, &progress, path));
+            progress.finish();
+            exit(code);
+        }
+        Some(Command::Index { db, dirs }) => exit(index(&args, db, dirs)),
+        Some(Command::Query { db, paths }) => exit(query(&args, db, paths)),
+        Some(Command::Completions { shell }) => {
+            Args::clap().gen_completions_to(env!("CARGO_PKG_NAME"), *shell, &mut io::stdout());
+            exit(0);
+        }
+        None => (),
+    }
+
+    if let Some(addr) = &args.serve {
+        exit(serve(&args, addr));
+    }
+
+    if let Some(manifest) = &args.check {
+        let progress = progress(&args, false);
+        let hash_fn = |path: &Path| hash_file(&args, &progress, path).map(|hashed| hashed.hash);
+        let options = cli::check::Options {
+            root: args.paths.first().map(PathBuf::as_path),
+            zero: args.zero,
+            verbosity: verbosity(&args),
+            ignore_missing: args.ignore_missing,
+            strict: args.strict,
+            warn: args.warn,
+        };
+        let reader: Box<dyn BufRead> = match &args.verify_key {
+            Some(_) if manifest == Path::new("-") => {
+                eprintln!("--verify-key needs a manifest file, not standard input");
+                exit(2);
+            }
+            Some(key) => Box::new(io::Cursor::new(verify_signature(manifest, key)
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    exit(2);
+                }))),
+            None => cli::check::open(manifest).unwrap_or_else(|e| {
+                eprintln!("Failed to read {:?}: {}", manifest, e);
+                exit(2);
+            }),
+        };
+        match cli::check::run(manifest, reader, &options, hash_fn) {
+            Ok(code) => exit(code),
+            Err(e) => {
+                eprintln!("Failed to read {:?}: {}", manifest, e);
+                exit(2);
+            }
+        }
+    }
+
+    if let Some(manifest) = &args.verify_blocks {
+        match cli::blocks::verify(manifest, &progress(&args, false), verbosity(&args)) {
+            Ok(code) => exit(code),
+            Err(e) => {
+                eprintln!("Failed to read {:?}: {}", manifest, e);
+                exit(2);
+            }
+        }
+    }
+
+    if let Some(expected) = &args.expected {
+        exit(check_expected(&args, expected));
+    }
+
+    if args.tee {
+        exit(tee(&args));
+    }
+
+    let stdin = PathBuf::from("-");
+    let paths = if args.paths.is_empty() && args.files_from.is_none() {
+        std::slice::from_ref(&stdin)
+    } else {
+        &args.paths[..]
+    };
+    let mut paths: Box<dyn Iterator<Item = io::Result<PathBuf>>> =
+        Box::new(paths.iter().cloned().map(Ok));
+    if let Some(list) = &args.files_from {
+        let listed = cli::read_path_list(list, args.null).unwrap_or_else(|e| {
+            eprintln!("Failed to read {:?}: {}", list, e);
+            exit(2);
+        });
+        paths = Box::new(paths.chain(listed));
+    }
+
+    let style = if args.tag {
+        PlainStyle::Tag
+    } else if args.manifest {
+        PlainStyle::Manifest
+    } else if args.rclone {
+        PlainStyle::Rclone
+    } else if args.paths.len() <= 1 && args.files_from.is_none() && !args.recursive
+        && !args.with_filename && !args.tar && !args.zip && args.output_file.is_none()
+        && !args.absolute && args.relative_to.is_none()
+    {
+        PlainStyle::Bare
+    } else {
+        PlainStyle::WithPath
+    };
+    let walker = Walker::new(&args.include, &args.exclude)
         .unwrap_or_else(|e| {
-            eprintln!("Failed to open {:?}: {}", args.path, e);
+            eprintln!("{}", e);
+            exit(2);
+        })
+        .max_depth(args.max_depth)
+        .follow_symlinks(args.follow_symlinks && !args.no_follow)
+        .error_on_broken_symlinks(args.error_on_broken_symlinks);
+    if args.append && args.format == cli::output::Format::Json {
+        eprintln!("--append can't be used with --format json");
+        exit(2);
+    }
+    let file = args.output_file.as_ref().map(|path| {
+        AtomicFile::create(path, args.append).unwrap_or_else(|e| {
+            eprintln!("Failed to create {:?}: {}", path, e);
             exit(2);
+        })
+    });
+    let path_style = match &args.relative_to {
+        Some(dir) => Paths::RelativeTo(cli::output::absolute_path(dir).unwrap_or_else(|e| {
+            eprintln!("Failed to get the absolute path of {:?}: {}", dir, e);
+            exit(2);
+        })),
+        None if args.absolute => Paths::Absolute,
+        None => Paths::AsGiven,
+    };
+    let mut output = Output::new(args.format, style, &args.also, file)
+        .unwrap_or_else(|e| output_failed(e))
+        .encoding(args.encoding)
+        .zero(args.zero)
+        .paths(path_style.clone())
+        .root(args.paths.first().map_or(Path::new(""), PathBuf::as_path));
+    let many = args.paths.len() > 1 || args.files_from.is_some() || args.recursive || args.tar
+        || args.zip;
+    let progress = progress(&args, many);
+
+    let mut blocks_out = args.blocks_out.as_ref().map(|path| {
+        BlockWriter::create(path, args.blocks_format).unwrap_or_else(|e| {
+            eprintln!("Failed to create {:?}: {}", path, e);
+            exit(2);
+        })
+    });
+    let mut failed = false;
+    let mut stats = Stats::default();
+    let start = Instant::now();
+    let mut record = |hashed: Outcome| match hashed {
+        Ok((path, mut result, elapsed)) => {
+            if let (Some(out), Ok(hashed)) = (&mut blocks_out, &mut result) {
+                if let Err(e) = out.write(&path_style.apply(&path), hashed) {
+                    eprintln!("Failed to write block hashes: {}", e);
+                    exit(2);
+                }
+                if !args.print_block_hashes {
+                    hashed.blocks = None;
+                }
+            }
+            #[cfg(feature = "tracing")]
+            if let Ok(hashed) = &result {
+                info!(path = %path.display(), bytes = hashed.size, ?elapsed, "hashed");
+            }
+            progress.suspend(|| output.record(&path, &result, elapsed))
+                .unwrap_or_else(|e| output_failed(e));
+            stats.record(&result);
+            failed |= result.is_err();
+        }
+        Err(e) => {
+            progress.suspend(|| eprintln!("{}", e));
+            stats.error();
+            failed = true;
+        }
+    };
+
+    let mut sorted = vec![];
+    let mut record_or_sort = |hashed: Outcome| match hashed {
+        Ok(done) if args.sort => sorted.push(done),
+        hashed => record(hashed),
+    };
+
+    if args.tar || args.zip {
+        archives(&args, paths, &progress, &mut record_or_sort);
+    } else {
+        let list = |send: &mut dyn FnMut(Result<PathBuf, String>)| {
+            for path in paths {
+                let path = match path {
+                    Ok(path) => path,
+                    Err(e) => {
+                        send(Err(format!("Failed to read the list of files: {}", e)));
+                        break;
+                    }
+                };
+                if args.recursive && path.is_dir() {
+                    walker.walk(&path, |entry| send(entry.map(Path::to_owned)));
+                } else {
+                    send(Ok(path));
+                }
+            }
+        };
+        let hash = |item: Result<PathBuf, String>| item.map(|path| {
+            let start = Instant::now();
+            let result = hash_path(&args, &progress, &path);
+            (path, result, start.elapsed())
         });
+        cli::jobs::map_ordered(args.jobs, list, hash, &mut record_or_sort);
+    }
+    sorted.sort_by_cached_key(|(path, _, _)| path_style.apply(path).into_owned());
+    for done in sorted {
+        record(Ok(done));
+    }
+    let elapsed = start.elapsed();
+    #[cfg(feature = "watch")]
+    {
+        if args.watch {
+            watch(&args, &walker, &progress, &mut output);
+        }
+    }
+    progress.finish();
+    output.finish().unwrap_or_else(|e| output_failed(e));
+    if let (Some(path), Some(key)) = (&args.output_file, &args.sign_key) {
+        if let Err(e) = sign(path, key) {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    }
+    if let Some(out) = blocks_out {
+        if let Err(e) = out.finish() {
+            eprintln!("Failed to write block hashes: {}", e);
+            exit(2);
+        }
+    }
+
+    if args.stats {
+        eprintln!("{}", stats.summary(elapsed, args.stats_format));
+    }
+
+    if failed {
+        exit(2);
+    }
+}
+
+fn output_failed(e: io::Error) -> ! {
+    eprintln!("Failed to write the output: {}", e);
+    exit(2);
+}
+
+/// Log to standard error at the level chosen by the arguments.
+fn init_logging(args: &Args) {
+    let level = args.log_level.as_deref().unwrap_or(match args.verbose {
+        _ if args.status => "off",
+        0 if args.quiet => "error",
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    });
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_max_level(level.parse::<tracing_subscriber::filter::LevelFilter>()
+            .expect("the level is one of LOG_LEVELS"))
+        .with_target(false)
+        .with_writer(io::stderr)
+        .init();
+    #[cfg(not(feature = "tracing"))]
+    dropbox_content_hash::trace::set_warnings(level != "off" && level != "error");
+}
 
-    let file_len = file.metadata()
-        .map(|meta| meta.len())
-        .ok(); // if we can't get file length, that's fine; just don't print progress
+/// Fill in the options which weren't given on the command line from the configuration file.
+fn apply_config(args: &mut Args, matches: &ArgMatches<'_>) {
+    if args.no_config {
+        return;
+    }
+    let config = match (&args.config, cli::config::default_path()) {
+        (Some(path), _) => Config::read(path, false),
+        (None, Some(path)) => Config::read(&path, true),
+        (None, None) => return,
+    };
+    let config = config.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2);
+    });
+    // Options which can't be used with --threads take precedence over its default.
+    if args.threads.is_none() && args.block_size.is_none() && args.checkpoint.is_none()
+        && cfg!(not(target_os = "wasi"))
+    {
+        args.threads = config.threads;
+    }
+    if let (0, Some(progress)) = (matches.occurrences_of("progress"), config.progress) {
+        args.progress = progress;
+    }
+    if let (0, Some(format)) = (matches.occurrences_of("format"), config.format) {
+        args.format = format;
+    }
+    if args.exclude.is_empty() {
+        args.exclude = config.exclude;
+    }
+}
+
+fn parse_expected(hex: &str) -> Result<[u8; HASH_OUTPUT_SIZE], String> {
+    parse_hex(hex).map_err(|e| format!("{:?} is not a hex content hash: {}", hex, e))
+}
 
-    let source: Box<dyn Read> = match file_len {
-        Some(len) => Box::new(ProgressReader::new(file, len)),
-        None      => Box::new(file),
+/// Hash the one file given (or standard input), and compare it with the --expected hash,
+/// returning the exit code.
+fn check_expected(args: &Args, expected: &[u8; HASH_OUTPUT_SIZE]) -> i32 {
+    let path = match &args.paths[..] {
+        [] => Path::new("-"),
+        [path] => path,
+        _ => {
+            eprintln!("--expected can only be used with a single file");
+            return 2;
+        }
     };
+    let verbosity = verbosity(args);
+    match hash_path(args, &progress(args, false), path) {
+        Ok(hashed) if &hashed.hash == expected => {
+            if verbosity.ok() {
+                println!("{}: OK", path.display());
+            }
+            0
+        }
+        Ok(hashed) => {
+            if verbosity.failures() {
+                println!("{}: FAILED: expected {}, got {}",
+                    path.display(), hex_string(expected), hex_string(&hashed.hash));
+            }
+            1
+        }
+        Err(e) => {
+            if verbosity.failures() {
+                eprintln!("{}", e);
+            }
+            2
+        }
+    }
+}
 
-    match args.threads {
-        None | Some(0) => {
-            let mut ctx = if args.print_block_hashes {
-                ContentHasher::with_block_hashes_fn(Box::new(|block_num, hash| {
-                    println!("block {}: {}", block_num, dropbox_content_hash::hex_string(hash));
-                }))
-            } else {
-                ContentHasher::default()
-            };
-            ctx.read_stream(source)
-                .unwrap_or_else(|e| {
-                    eprintln!("I/O error: {}", e);
-                    exit(2);
-                });
-            println!("{}", ctx.finish_str());
+/// Sign a manifest, asking for the key's password on the terminal if it's encrypted.
+#[cfg(feature = "sign")]
+fn sign(manifest: &Path, key: &Path) -> Result<(), String> {
+    cli::sign::sign(manifest, key, || {
+        rpassword::prompt_password(format!("Password for {}: ", key.display()))
+    })
+}
+
+#[cfg(not(feature = "sign"))]
+fn sign(_manifest: &Path, _key: &Path) -> Result<(), String> {
+    Err("Signing manifests is not available in this build".to_owned())
+}
+
+/// Read a manifest, checking its signature.
+#[cfg(feature = "sign")]
+fn verify_signature(manifest: &Path, key: &Path) -> Result<Vec<u8>, String> {
+    cli::sign::verify(manifest, key)
+}
+
+#[cfg(not(feature = "sign"))]
+fn verify_signature(_manifest: &Path, _key: &Path) -> Result<Vec<u8>, String> {
+    Err("Signing manifests is not available in this build".to_owned())
+}
+
+/// Copy standard input to standard output while hashing it, for --tee.
+fn tee(args: &Args) -> i32 {
+    if args.paths.iter().any(|path| path != Path::new("-")) {
+        eprintln!("--tee can only read standard input");
+        return 2;
+    }
+    let source = TeeReader { inner: io::stdin().lock(), copy: io::stdout().lock() };
+    let progress = progress(args, false);
+    match hash_stream(args, Box::new(source), progress.file(Path::new("-"), None)) {
+        Ok(hashed) => {
+            for (algorithm, digest) in &hashed.also {
+                eprintln!("{}: {}", algorithm.name(), args.encoding.encode(digest));
+            }
+            eprintln!("{}", args.encoding.encode(&hashed.hash));
+            0
         }
-        Some(num_threads) => {
-            match parallel::content_hash_from_stream(source, num_threads) {
-                Ok(hash) => {
-                    println!("{}", hex_string(&hash));
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+fn verbosity(args: &Args) -> Verbosity {
+    if args.status {
+        Verbosity::Status
+    } else if args.quiet {
+        Verbosity::Quiet
+    } else {
+        Verbosity::Normal
+    }
+}
+
+#[cfg(feature = "dropbox")]
+fn dropbox(args: &Args, token: &str, command: &DropboxCommand) -> i32 {
+    let client = cli::dropbox::Client::new(token.to_owned());
+    match command {
+        DropboxCommand::Verify { local_path, dropbox_path } => {
+            let remote = match client.file_metadata(dropbox_path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 2;
                 }
+            };
+            let local = match hash_path(args, &progress(args, false), local_path) {
+                Ok(hashed) => hashed,
                 Err(e) => {
                     eprintln!("{}", e);
-                    exit(2);
+                    return 2;
+                }
+            };
+            println!("{}  {}", hex_string(&local.hash), local_path.display());
+            println!("{}  dropbox:{}", hex_string(&remote.content_hash), remote.path_display);
+            if local.hash == remote.content_hash {
+                println!("Content hashes match");
+                0
+            } else {
+                println!("Content hashes differ");
+                1
+            }
+        }
+        DropboxCommand::Compare { local_dir, dropbox_folder } => {
+            let progress = progress(args, true);
+            let code = cli::dropbox::compare(&client, local_dir, dropbox_folder, args.jobs,
+                &progress, |path| hash_path(args, &progress, path));
+            progress.finish();
+            code
+        }
+    }
+}
+
+#[cfg(not(feature = "dropbox"))]
+fn dropbox(_args: &Args, _token: &str, _command: &DropboxCommand) -> i32 {
+    eprintln!("Dropbox API support is not available in this build");
+    2
+}
+
+#[cfg(all(feature = "cache", feature = "watch"))]
+fn index(args: &Args, db: &Path, dirs: &[PathBuf]) -> i32 {
+    let cache = match cache::Cache::open(db) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Failed to open {:?}: {}", db, e);
+            return 2;
+        }
+    };
+    let progress = progress(args, false);
+    match cli::index::run(&cache, dirs, &Walker::new(&[], &[]).unwrap(),
+        |path| hash_path(args, &progress, path))
+    {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+#[cfg(not(all(feature = "cache", feature = "watch")))]
+fn index(_args: &Args, _db: &Path, _dirs: &[PathBuf]) -> i32 {
+    eprintln!("Indexing is not available in this build");
+    2
+}
+
+#[cfg(feature = "cache")]
+fn query(args: &Args, db: &Path, paths: &[PathBuf]) -> i32 {
+    let cache = match cache::Cache::open(db) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Failed to open {:?}: {}", db, e);
+            return 2;
+        }
+    };
+    let mut code = 0;
+    for path in paths {
+        match cache.lookup(path) {
+            Ok(Some(entry)) => {
+                println!("{}  {}", args.encoding.encode(&entry.content_hash), path.display());
+            }
+            Ok(None) => {
+                eprintln!("{}: not indexed, or changed since it was", path.display());
+                code = code.max(1);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                code = 2;
+            }
+        }
+    }
+    code
+}
+
+#[cfg(not(feature = "cache"))]
+fn query(_args: &Args, _db: &Path, _paths: &[PathBuf]) -> i32 {
+    eprintln!("Querying an index is not available in this build");
+    2
+}
+
+/// The outcome of hashing a file, as passed from hashing it to recording the result: its path,
+/// the hash or an error, and how long it took.
+type Outcome = Result<(PathBuf, Result<Hashed, String>, Duration), String>;
+
+/// Hash each regular file inside the tar or zip archives given.
+fn archives(
+    args: &Args,
+    archives: impl Iterator<Item = io::Result<PathBuf>>,
+    progress: &Progress,
+    record: &mut dyn FnMut(Outcome),
+) {
+    for archive in archives {
+        let archive = match archive {
+            Ok(archive) => archive,
+            Err(e) => {
+                record(Err(format!("Failed to read the list of files: {}", e)));
+                break;
+            }
+        };
+        let _span = info_span!("archive", path = %archive.display()).entered();
+        let stdin = archive == Path::new("-");
+        if stdin && args.zip {
+            record(Err("--zip can't read standard input".to_owned()));
+            continue;
+        }
+        let file = if stdin { None } else {
+            match File::open(&archive) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    record(Err(format!("Failed to open {:?}: {}", archive, e)));
+                    continue;
                 }
             }
+        };
+        let mut visit = |path: &Path, entry: io::Result<(u64, &mut dyn Read)>| {
+            let start = Instant::now();
+            let result = entry
+                .map_err(|e| e.to_string())
+                .and_then(|(size, contents)| {
+                    hash_stream(args, Box::new(contents), progress.file(path, Some(size)))
+                })
+                .map_err(|e| format!("{:?} in {:?}: {}", path, archive, e));
+            record(Ok((path.to_owned(), result, start.elapsed())));
+        };
+        let result = match file {
+            Some(file) if args.zip => cli::archive::zip_entries(file, visit),
+            Some(file) => cli::archive::tar_entries(file, |path, size, contents| {
+                visit(path, Ok((size, contents)))
+            }),
+            None => cli::archive::tar_entries(io::stdin().lock(), |path, size, contents| {
+                visit(path, Ok((size, contents)))
+            }),
+        };
+        if let Err(e) = result {
+            record(Err(format!("Failed to read {:?}: {}", archive, e)));
         }
     }
 }
 
-struct ProgressReader<R> {
-    inner: R,
-    size: u64,
-    position: u64,
+/// Serve hashes over HTTP, until receiving requests fails.
+#[cfg(feature = "serve")]
+fn serve(args: &Args, addr: &str) -> i32 {
+    use cli::serve::Source;
+    let progress = Progress::hidden();
+    let result = cli::serve::serve(addr, args.jobs, args.encoding, |source| match source {
+        Source::Body(body, len) => {
+            let _span = info_span!("hash", path = "-").entered();
+            hash_stream(args, Box::new(body), progress.file(Path::new("-"), len))
+        }
+        // Not hash_path, so clients can't have this read standard input or download URLs.
+        Source::Path(path) => hash_file(args, &progress, &path),
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+#[cfg(not(feature = "serve"))]
+fn serve(_args: &Args, _addr: &str) -> i32 {
+    eprintln!("Serving hashes over HTTP is not available in this build");
+    2
+}
+
+/// Hash the files given again whenever they change, until watching them fails.
+#[cfg(feature = "watch")]
+fn watch(args: &Args, walker: &Walker, progress: &Progress, output: &mut Output) {
+    let result = cli::watch::watch(&args.paths, args.recursive, walker, |change| {
+        let path = match change {
+            Change::Modified(path) => path,
+            Change::Removed(_) => return,
+        };
+        let start = Instant::now();
+        let result = hash_path(args, progress, path);
+        progress.suspend(|| output.record(path, &result, start.elapsed()))
+            .unwrap_or_else(|e| output_failed(e));
+    });
+    if let Err(e) = result {
+        eprintln!("Failed to watch for changes: {}", e);
+        exit(2);
+    }
+}
+
+/// Set up the progress display chosen by the arguments.
+fn progress(args: &Args, overall: bool) -> Progress {
+    if args.no_progress || args.quiet || args.status {
+        return Progress::hidden();
+    }
+    match args.progress {
+        ProgressMode::Auto => Progress::bars(overall, false),
+        ProgressMode::Always => Progress::bars(overall, true),
+        ProgressMode::Never => Progress::hidden(),
+        ProgressMode::Json => {
+            let out: Box<dyn Write + Send> = match &args.progress_file {
+                Some(path) => Box::new(File::create(path).unwrap_or_else(|e| {
+                    eprintln!("Failed to open {:?}: {}", path, e);
+                    exit(2);
+                })),
+                None => Box::new(io::stderr()),
+            };
+            Progress::json(out)
+        }
+    }
 }
 
-impl<R> ProgressReader<R> {
-    pub fn new(inner: R, size: u64) -> Self {
-        Self {
-            inner,
-            size,
-            position: 0,
+/// Hash a file, standard input for "-", or what's downloaded from a URL.
+fn hash_path(args: &Args, progress: &Progress, path: &Path) -> Result<Hashed, String> {
+    let stdin = path == Path::new("-");
+    let url = cli::is_url(path);
+    let object = cli::is_object_url(path);
+    if (stdin || url || object)
+        && (args.pread || args.uring.is_some() || args.overlapped.is_some() || args.direct)
+    {
+        eprintln!("--pread, --uring, --overlapped, and --direct can only be used with files");
+        exit(2);
+    }
+    let hashed = if stdin {
+        let _span = info_span!("hash", path = "-").entered();
+        debug!(threads = ?args.threads, "reading standard input");
+        let mut stdin = io::stdin().lock();
+        skip_offset(args, &mut stdin, "Standard input")?;
+        hash_stream(args, limit(args, stdin), progress.file(path, args.length))?
+    } else if url {
+        hash_url(args, progress, path)?
+    } else if object {
+        hash_object(args, progress, path)?
+    } else {
+        hash_file(args, progress, path)?
+    };
+    if let Some(length) = args.length {
+        if hashed.size < length {
+            return Err(format!("{:?} ends {} bytes into the range given by --offset and --length",
+                path, hashed.size));
+        }
+    }
+    Ok(hashed)
+}
+
+/// Read and discard the first --offset bytes of a stream, if it was given.
+fn skip_offset(args: &Args, source: &mut impl Read, name: &str) -> Result<(), String> {
+    let offset = args.offset.unwrap_or(0);
+    let skipped = io::copy(&mut source.take(offset), &mut io::sink())
+        .map_err(|e| format!("I/O error: {}", e))?;
+    if skipped < offset {
+        return Err(format!("{} ends before --offset ({} bytes)", name, skipped));
+    }
+    Ok(())
+}
+
+/// Hash something being downloaded from a URL, whose length may be known.
+#[cfg(any(feature = "http", feature = "cloud"))]
+fn hash_download(
+    args: &Args,
+    progress: &Progress,
+    url: &str,
+    mut body: impl Read,
+    len: Option<u64>,
+) -> Result<Hashed, String> {
+    debug!(threads = ?args.threads, ?len, "downloading");
+    skip_offset(args, &mut body, url)?;
+    let len = len
+        .map(|len| len.saturating_sub(args.offset.unwrap_or(0)))
+        .map(|len| args.length.map_or(len, |length| length.min(len)));
+    hash_stream(args, limit(args, body), progress.file(Path::new(url), len))
+        .map_err(|e| format!("{}: {}", url, e))
+}
+
+/// Download a URL and hash the response body.
+#[cfg(feature = "http")]
+fn hash_url(args: &Args, progress: &Progress, url: &Path) -> Result<Hashed, String> {
+    let url = url.to_str().expect("URLs are UTF-8");
+    let _span = info_span!("hash", url).entered();
+    let (body, len) = cli::http::get(url)?;
+    hash_download(args, progress, url, body, len)
+}
+
+#[cfg(not(feature = "http"))]
+fn hash_url(_args: &Args, _progress: &Progress, url: &Path) -> Result<Hashed, String> {
+    Err(format!("{}: downloading URLs is not available in this build", url.display()))
+}
+
+/// Download an object from cloud storage and hash it.
+#[cfg(feature = "cloud")]
+fn hash_object(args: &Args, progress: &Progress, url: &Path) -> Result<Hashed, String> {
+    let url = url.to_str().expect("URLs are UTF-8");
+    let _span = info_span!("hash", url).entered();
+    let (body, len) = cli::cloud::open(url)?;
+    hash_download(args, progress, url, body, Some(len))
+}
+
+#[cfg(not(feature = "cloud"))]
+fn hash_object(_args: &Args, _progress: &Progress, url: &Path) -> Result<Hashed, String> {
+    Err(format!("{}: reading from cloud storage is not available in this build", url.display()))
+}
+
+fn hash_file(args: &Args, progress: &Progress, path: &Path) -> Result<Hashed, String> {
+    let _span = info_span!("hash", path = %path.display()).entered();
+    let file = if args.direct { direct::open(path) } else { File::open(path) }
+        .map_err(|e| format!("Failed to open {:?}: {}", path, e))?;
+    let meta = file.metadata().map_err(|e| format!("I/O error: {}", e))?;
+    let hashed = hash_open_file(args, progress, path, file, &meta)?;
+    Ok(Hashed { mtime: meta.modified().ok(), ..hashed })
+}
+
+/// Hash a file which has been opened, with its metadata from before it's read.
+fn hash_open_file(
+    args: &Args,
+    progress: &Progress,
+    path: &Path,
+    mut file: File,
+    meta: &Metadata,
+) -> Result<Hashed, String> {
+    // Block devices need seeking to find their size.
+    let total_len = if meta.is_file() { Ok(meta.len()) } else { file::len(&file) };
+
+    // Files in /proc and the like claim to be empty, so check there's nothing to read.
+    if meta.is_file() && meta.len() == 0 {
+        match file.read(&mut [0]) {
+            Ok(0) => {
+                debug!("empty file");
+                return Ok(Hashed {
+                    hash: EMPTY_CONTENT_HASH,
+                    size: 0,
+                    blocks: collect_blocks(args).then(Vec::new),
+                    also: Digests::new(&args.also).finish(),
+                    mtime: None,
+                });
+            }
+            _ => {
+                file.rewind().map_err(|e| format!("Failed to seek in {:?}: {}", path, e))?;
+            }
+        }
+    }
+
+    let file_backend = match (args.uring, args.overlapped) {
+        (Some(queue_depth), _) => Some(uring_backend(queue_depth)),
+        (_, Some(queue_depth)) => Some(overlapped_backend(queue_depth)),
+        _ if args.pread => Some(parallel::FileBackend::Pread),
+        _ => None,
+    };
+    // The backends read up to the length, so anything else, which might be longer than it says,
+    // is streamed.
+    let file_backend = file_backend.filter(|_| meta.is_file() && meta.len() != 0);
+
+    if let Some(backend) = file_backend {
+        let num_threads = args.threads.unwrap_or_default();
+        debug!(threads = num_threads, ?backend, "reading blocks in parallel");
+        let size = total_len.map_err(|e| format!("I/O error: {}", e))?;
+        let blocks = Arc::new(Mutex::new(vec![]));
+        let mut options = parallel::Options::new(num_threads).file_backend(backend);
+        if collect_blocks(args) {
+            let blocks = Arc::clone(&blocks);
+            options = options.block_hashes_fn(Arc::new(move |_block_num, hash| {
+                blocks.lock().unwrap().push(hash.try_into().unwrap());
+            }));
+        }
+        let hash = parallel::content_hash_from_file_with_options(&file, &options)
+            .map_err(|e| e.to_string())?;
+        let blocks = collected(args, &blocks);
+        return Ok(Hashed { hash, size, blocks, also: vec![], mtime: None });
+    }
+
+    let offset = args.offset.unwrap_or(0);
+    if offset != 0 {
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| format!("Failed to seek in {:?}: {}", path, e))?;
+    }
+
+    let file_len = total_len
+        .map(|len| len.saturating_sub(offset))
+        .map(|len| args.length.map_or(len, |length| length.min(len)))
+        .ok(); // if we can't get file length, that's fine; just don't show the percentage
+
+    if let Some(checkpoint) = &args.checkpoint {
+        debug!(checkpoint = %checkpoint.display(), "reading with a checkpoint");
+        return hash_resumable(args, path, file, meta, checkpoint, progress.file(path, file_len));
+    }
+
+    let serial = matches!(args.threads, None | Some(0));
+    if serial && !args.direct && args.retries.is_none() && args.length.is_none()
+        && args.also.is_empty()
+    {
+        let block_size = args.block_size.unwrap_or(BLOCK_SIZE as u64);
+        let zero_blocks = file_len
+            .and_then(|len| cli::sparse::zero_blocks(&file, offset, offset + len, block_size).ok())
+            .unwrap_or_default();
+        if !zero_blocks.is_empty() {
+            debug!(holes = zero_blocks.len(), "skipping the holes in a sparse file");
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|e| format!("Failed to seek in {:?}: {}", path, e))?;
+            return hash_sparse(args, path, &file, &zero_blocks, progress.file(path, file_len));
+        }
+    }
+
+    debug!(threads = ?args.threads, direct = args.direct, retries = ?args.retries,
+        "reading the file");
+    let file: Box<dyn Read + '_> = if args.direct {
+        Box::new(direct::DirectReader::new(file))
+    } else if let Some(retries) = args.retries {
+        let retrying = RetryingReader::new(file, path, retries, args.retry_delay)
+            .map_err(|e| format!("I/O error: {}", e))?;
+        limit(args, retrying)
+    } else {
+        limit(args, file)
+    };
+
+    hash_stream(args, file, progress.file(path, file_len))
+}
+
+/// Stop reading the source after --length bytes, if it was given.
+fn limit<'a>(args: &Args, source: impl Read + 'a) -> Box<dyn Read + 'a> {
+    match args.length {
+        Some(length) => Box::new(source.take(length)),
+        None => Box::new(source),
+    }
+}
+
+/// Hash a stream, showing its progress on the given bar.
+fn hash_stream(
+    args: &Args,
+    source: Box<dyn Read + '_>,
+    progress: FileProgress,
+) -> Result<Hashed, String> {
+    let mut digests = Digests::new(&args.also);
+    let source = DigestReader { inner: throttle(args, source), digests: &mut digests };
+    let mut source = CountingReader::new(source);
+    let collect_blocks = collect_blocks(args);
+    let blocks = Arc::new(Mutex::new(vec![]));
+    let hash = match args.threads {
+        None | Some(0) => {
+            let reader = progress.reader(&mut source);
+            let mut ctx = hasher(args, &blocks)?;
+            ctx.read_stream(reader)
+                .map_err(|e| format!("I/O error: {}", e))?;
+            ctx.finish()
+        }
+        Some(num_threads) => {
+            let mut options = parallel::Options::new(num_threads);
+            if collect_blocks {
+                let blocks = Arc::clone(&blocks);
+                options = options.block_hashes_fn(Arc::new(move |_block_num, hash| {
+                    blocks.lock().unwrap().push(hash.try_into().unwrap());
+                }));
+            }
+            let tracker = progress.tracker();
+            options = options.progress_fn(Arc::new(move |progress| {
+                tracker.set_position(progress.bytes_hashed);
+            }));
+            parallel::content_hash_from_stream_with_options(&mut source, &options)
+                .map_err(|e| e.to_string())?
+        }
+    };
+    let size = source.count;
+    let also = digests.finish();
+    Ok(Hashed { hash, size, blocks: collected(args, &blocks), also, mtime: None })
+}
+
+/// Hash a file from its current position, skipping over the given ranges of whole blocks of
+/// zeros (relative to that position) instead of reading them.
+fn hash_sparse(
+    args: &Args,
+    path: &Path,
+    file: &File,
+    zero_blocks: &[Range<u64>],
+    progress: FileProgress,
+) -> Result<Hashed, String> {
+    let block_size = args.block_size.unwrap_or(BLOCK_SIZE as u64);
+    let blocks = Arc::new(Mutex::new(vec![]));
+    let mut ctx = hasher(args, &blocks)?;
+    let mut reader = progress.reader(throttle(args, file));
+    for zeros in zero_blocks {
+        let data = zeros.start - reader.position();
+        let len = ctx.read_stream((&mut reader).take(data))
+            .map_err(|e| format!("I/O error: {}", e))?;
+        if len != data {
+            return Err(format!("{:?} was truncated while it was being hashed", path));
+        }
+        let len = zeros.end - zeros.start;
+        let mut file = file;
+        file.seek(SeekFrom::Current(len as i64))
+            .map_err(|e| format!("Failed to seek in {:?}: {}", path, e))?;
+        ctx.update_zero_blocks(len / block_size);
+        reader.skip(len);
+    }
+    ctx.read_stream(&mut reader)
+        .map_err(|e| format!("I/O error: {}", e))?;
+    let size = reader.position();
+    let blocks = collected(args, &blocks);
+    Ok(Hashed { hash: ctx.finish(), size, blocks, also: vec![], mtime: None })
+}
+
+/// Hash a file, saving the hashes of its blocks to the checkpoint every so often, and skipping
+/// the blocks already listed there by an earlier run.
+fn hash_resumable(
+    args: &Args,
+    path: &Path,
+    mut file: File,
+    meta: &Metadata,
+    checkpoint_path: &Path,
+    progress: FileProgress,
+) -> Result<Hashed, String> {
+    let mut checkpoint = Checkpoint::new(checkpoint_path, path, meta);
+    let done = checkpoint.load()
+        .map_err(|e| format!("Failed to read checkpoint {:?}: {}", checkpoint_path, e))?;
+    let start = (done.len() * BLOCK_SIZE) as u64;
+    if start != 0 {
+        info!(blocks = done.len(), "resuming from the checkpoint");
+    }
+    if file::len(&file).map_err(|e| format!("I/O error: {}", e))? < start {
+        return Err(format!("{:?} is shorter than checkpoint {:?} says", path, checkpoint_path));
+    }
+    file.seek(SeekFrom::Start(start))
+        .map_err(|e| format!("Failed to seek in {:?}: {}", path, e))?;
+
+    let blocks = Arc::new(Mutex::new(vec![]));
+    let mut ctx = hasher(args, &blocks)?;
+    ctx.update_block_hashes(&done);
+    let source: Box<dyn Read + '_> = match args.retries {
+        Some(retries) => Box::new(RetryingReader::new(&file, path, retries, args.retry_delay)
+            .map_err(|e| format!("I/O error: {}", e))?),
+        None => Box::new(&file),
+    };
+    let mut reader = progress.reader(throttle(args, source));
+    reader.skip(start);
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    loop {
+        let result = ctx.read_stream_with_buffer((&mut reader).take(BLOCK_SIZE as u64), &mut buf);
+        // Only whole blocks are saved, so a block that failed part way through is read again.
+        let saved = if result.is_ok() {
+            checkpoint.save_if_due(&blocks.lock().unwrap())
+        } else {
+            checkpoint.save(&blocks.lock().unwrap())
+        };
+        let len = result.map_err(|e| format!("I/O error: {}", e))?;
+        saved.map_err(|e| format!("Failed to save checkpoint {:?}: {}", checkpoint_path, e))?;
+        if len < BLOCK_SIZE as u64 {
+            break;
         }
     }
+    checkpoint.remove()
+        .map_err(|e| format!("Failed to remove checkpoint {:?}: {}", checkpoint_path, e))?;
+    let size = reader.position();
+    let blocks = collected(args, &blocks);
+    Ok(Hashed { hash: ctx.finish(), size, blocks, also: vec![], mtime: None })
+}
+
+/// Limit the rate of reading from the source, if --throttle was given.
+fn throttle<'a>(args: &'a Args, source: impl Read + 'a) -> Box<dyn Read + 'a> {
+    match &args.throttle {
+        Some(throttle) => Box::new(throttle.reader(source)),
+        None => Box::new(source),
+    }
+}
+
+/// A hasher for the serial path, which collects block hashes into `blocks` if they're needed for
+/// output or for --checkpoint.
+fn hasher(args: &Args, blocks: &Arc<Mutex<Vec<BlockHash>>>) -> Result<ContentHasher, String> {
+    let mut builder = ContentHasher::builder();
+    if collect_blocks(args) || args.checkpoint.is_some() {
+        let blocks = Arc::clone(blocks);
+        builder = builder.block_hashes_fn(Box::new(move |_block_num, hash| {
+            blocks.lock().unwrap().push(hash.try_into().unwrap());
+        }));
+    }
+    if let Some(size) = args.block_size {
+        builder = builder.block_size(size.try_into().map_err(|_| "block size is too big")?);
+    }
+    Ok(builder.build())
 }
 
-impl<R: Read> Read for ProgressReader<R> {
+/// Whether the block hashes of each file are needed, for printing, for the index, or for requests
+/// to the server which ask for them.
+fn collect_blocks(args: &Args) -> bool {
+    args.print_block_hashes || args.blocks_out.is_some() || args.serve.is_some()
+        || matches!(args.command, Some(Command::Index { .. }))
+}
+
+/// The block hashes collected while hashing, if they were needed.
+fn collected(args: &Args, blocks: &Mutex<Vec<BlockHash>>) -> Option<Vec<BlockHash>> {
+    if collect_blocks(args) {
+        Some(std::mem::take(&mut *blocks.lock().unwrap()))
+    } else {
+        None
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+fn uring_backend(queue_depth: usize) -> parallel::FileBackend {
+    parallel::FileBackend::Uring { queue_depth }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+fn uring_backend(_queue_depth: usize) -> parallel::FileBackend {
+    eprintln!("io_uring support is not available in this build");
+    exit(2);
+}
+
+#[cfg(windows)]
+fn overlapped_backend(queue_depth: usize) -> parallel::FileBackend {
+    parallel::FileBackend::Overlapped { queue_depth }
+}
+
+#[cfg(not(windows))]
+fn overlapped_backend(_queue_depth: usize) -> parallel::FileBackend {
+    eprintln!("overlapped I/O is only supported on Windows");
+    exit(2);
+}
+
+/// Writes everything read through it to another stream, which is flushed at the end.
+struct TeeReader<R, W> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let nread = self.inner.read(buf)?;
-        self.position += nread as u64;
-        if nread == 0 {
-            eprint!("      \r");
+        let n = self.inner.read(buf)?;
+        if n == 0 {
+            self.copy.flush()?;
         } else {
-            eprint!("{:.01}%\r", self.position as f64 / self.size as f64 * 100.);
+            self.copy.write_all(&buf[.. n])?;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_backend_unknown_length() {
+        let args = Args::from_iter_safe(["dropbox-content-hash", "--threads", "2", "--pread", "-"])
+            .unwrap();
+        let hash = |file: File| {
+            let meta = file.metadata().unwrap();
+            hash_open_file(&args, &Progress::hidden(), Path::new("-"), file, &meta).unwrap()
+        };
+
+        // A pipe says it's empty, but it isn't.
+        #[cfg(unix)]
+        {
+            use std::os::fd::OwnedFd;
+            let (reader, mut writer) = io::pipe().unwrap();
+            writer.write_all(b"hello").unwrap();
+            drop(writer);
+            let hashed = hash(File::from(OwnedFd::from(reader)));
+            assert_eq!(ContentHasher::from_stream(&b"hello"[..]).unwrap().finish(), hashed.hash);
+            assert_eq!(5, hashed.size);
+        }
+
+        // So does this.
+        #[cfg(target_os = "linux")]
+        {
+            let path = "/proc/version";
+            let expected = ContentHasher::from_stream(File::open(path).unwrap()).unwrap().finish();
+            assert_eq!(expected, hash(File::open(path).unwrap()).hash);
         }
-        Ok(nread)
     }
 }
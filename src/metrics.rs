@@ -0,0 +1,92 @@
+//! Hooks for keeping track of how much hashing is being done, and how fast, such as to feed
+//! Prometheus or StatsD metrics.
+//!
+//! Implement [`Metrics`] and attach it to a hash with
+//! [`ContentHasher::set_metrics`](crate::ContentHasher::set_metrics) or
+//! [`parallel::Options::metrics`](crate::parallel::Options::metrics). Giving each hash its own
+//! implementation (or one holding a label, such as a tenant) lets the numbers be broken down
+//! however you like.
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Receives measurements from a hash as it goes. Every method does nothing by default.
+///
+/// The methods may be called from threads other than the one that started the hash, and are
+/// called often, so they should be quick, like incrementing a counter.
+pub trait Metrics: Send + Sync {
+    /// Some bytes were read from the source, or given to the hasher.
+    fn bytes_read(&self, bytes: u64) {
+        let _ = bytes;
+    }
+
+    /// A block of the given length was hashed.
+    fn block_hashed(&self, len: usize) {
+        let _ = len;
+    }
+
+    /// The hash was finished, taking the given wall time since the metrics were attached.
+    fn finished(&self, elapsed: Duration) {
+        let _ = elapsed;
+    }
+}
+
+/// [`Metrics`] which add up the measurements, to be read at any time.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_read: AtomicU64,
+    blocks_hashed: AtomicU64,
+    bytes_hashed: AtomicU64,
+    hashes_finished: AtomicU64,
+    elapsed_nanos: AtomicU64,
+}
+
+impl Counters {
+    /// Start from zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The total number of bytes read.
+    pub fn total_bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// The total number of blocks hashed.
+    pub fn total_blocks_hashed(&self) -> u64 {
+        self.blocks_hashed.load(Ordering::Relaxed)
+    }
+
+    /// The total length of the blocks hashed.
+    pub fn total_bytes_hashed(&self) -> u64 {
+        self.bytes_hashed.load(Ordering::Relaxed)
+    }
+
+    /// The number of hashes finished.
+    pub fn hashes_finished(&self) -> u64 {
+        self.hashes_finished.load(Ordering::Relaxed)
+    }
+
+    /// The total wall time of the hashes finished.
+    pub fn total_elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::Relaxed))
+    }
+}
+
+impl Metrics for Counters {
+    fn bytes_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn block_hashed(&self, len: usize) {
+        self.blocks_hashed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_hashed.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn finished(&self, elapsed: Duration) {
+        self.hashes_finished.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
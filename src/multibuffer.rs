@@ -0,0 +1,271 @@
+//! Hash several blocks at once on a single thread, by running an independent SHA-256 computation
+//! in each lane of a SIMD vector register.
+//!
+//! On x86_64 CPUs with AVX2, this hashes eight blocks at once, and is more than twice as fast per
+//! thread as hashing them one at a time with `ring`, unless the CPU also has the SHA extensions,
+//! which `ring` uses to hash one block at a time about twice as fast again (the
+//! `multibuffer_blocks` benchmark compares them). On other CPUs, everything here still works, but
+//! hashes each block separately; [`is_faster`] tells which is the case.
+
+use crate::{BLOCK_SIZE, HASH_OUTPUT_SIZE};
+use crate::read::read_full;
+use crate::sha256::{digest, Context, SHA256};
+use std::io::{self, Read};
+
+/// The number of blocks hashed at once.
+pub const LANES: usize = 8;
+
+/// Whether hashing blocks in groups of [`LANES`] is faster than hashing them one at a time on this
+/// CPU.
+pub fn is_faster() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx2") && !is_x86_feature_detected!("sha")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+/// Compute a content hash from the given file or other stream on the current thread, reading
+/// [`LANES`] blocks at a time and hashing them together.
+///
+/// This uses `LANES * BLOCK_SIZE` bytes of memory for its buffer.
+pub fn content_hash_from_stream(mut source: impl Read) -> io::Result<[u8; HASH_OUTPUT_SIZE]> {
+    let mut buf = vec![0u8; LANES * BLOCK_SIZE];
+    let mut overall_hash = Context::new(&SHA256);
+    loop {
+        let filled = read_full(&mut source, &mut buf)?;
+        if filled == buf.len() {
+            let mut blocks = buf.chunks_exact(BLOCK_SIZE);
+            let blocks = [(); LANES].map(|()| blocks.next().unwrap());
+            for hash in &hash_blocks(blocks) {
+                overall_hash.update(hash);
+            }
+        } else {
+            // End of the stream: hash what's left one block at a time.
+            for block in buf[.. filled].chunks(BLOCK_SIZE) {
+                overall_hash.update(digest(&SHA256, block).as_ref());
+            }
+            break;
+        }
+    }
+    let mut out = [0u8; HASH_OUTPUT_SIZE];
+    out.copy_from_slice(overall_hash.finish().as_ref());
+    Ok(out)
+}
+
+/// Compute the SHA-256 hash of each of the given messages, which must all be the same length.
+pub fn hash_blocks(blocks: [&[u8]; LANES]) -> [[u8; HASH_OUTPUT_SIZE]; LANES] {
+    let len = blocks[0].len();
+    assert!(blocks.iter().all(|block| block.len() == len), "blocks must all be the same length");
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU has been checked for the required instructions.
+            return unsafe { avx2::hash_blocks(blocks) };
+        }
+    }
+
+    blocks.map(|block| {
+        let mut out = [0u8; HASH_OUTPUT_SIZE];
+        out.copy_from_slice(digest(&SHA256, block).as_ref());
+        out
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::LANES;
+    use crate::HASH_OUTPUT_SIZE;
+    use std::arch::x86_64::*;
+
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+        0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+        0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+        0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+        0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+        0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+        0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+        0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+
+    const H0: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+        0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    /// Hash eight messages of the same length, one in each 32-bit lane of the vectors.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn hash_blocks(blocks: [&[u8]; LANES]) -> [[u8; HASH_OUTPUT_SIZE]; LANES] {
+        let len = blocks[0].len();
+        let mut state = H0.map(|h| _mm256_set1_epi32(h as i32));
+
+        let full_chunks = len / 64;
+        for chunk in 0 .. full_chunks {
+            let chunks = blocks.map(|block| &block[chunk * 64 .. chunk * 64 + 64]);
+            compress(&mut state, chunks);
+        }
+
+        // The padding is a 1 bit, zeros, and the message length in bits, making a whole number of
+        // 64-byte chunks. The messages are all the same length, so they all need the same number.
+        let tail_len = len % 64;
+        let padded_len = if tail_len < 56 { 64 } else { 128 };
+        let tails = blocks.map(|block| {
+            let mut tail = [0u8; 128];
+            tail[.. tail_len].copy_from_slice(&block[full_chunks * 64 ..]);
+            tail[tail_len] = 0x80;
+            tail[padded_len - 8 .. padded_len].copy_from_slice(&(len as u64 * 8).to_be_bytes());
+            tail
+        });
+        for offset in (0 .. padded_len).step_by(64) {
+            let chunks = [0, 1, 2, 3, 4, 5, 6, 7].map(|lane| &tails[lane][offset .. offset + 64]);
+            compress(&mut state, chunks);
+        }
+
+        // Transpose the state back into a hash for each lane.
+        let mut words = [[0u32; 8]; 8];
+        for (i, vector) in state.iter().enumerate() {
+            _mm256_storeu_si256(words[i].as_mut_ptr() as *mut __m256i, *vector);
+        }
+        let mut out = [[0u8; HASH_OUTPUT_SIZE]; LANES];
+        for (lane, hash) in out.iter_mut().enumerate() {
+            for (i, word) in words.iter().enumerate() {
+                hash[i * 4 .. i * 4 + 4].copy_from_slice(&word[lane].to_be_bytes());
+            }
+        }
+        out
+    }
+
+    /// Run the SHA-256 compression function on a 64-byte chunk of each message.
+    #[target_feature(enable = "avx2")]
+    unsafe fn compress(state: &mut [__m256i; 8], chunks: [&[u8]; LANES]) {
+        let mut w = [_mm256_setzero_si256(); 16];
+        load_words(&mut w[0 .. 8], chunks, 0);
+        load_words(&mut w[8 .. 16], chunks, 32);
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for (t, &k) in K.iter().enumerate() {
+            if t >= 16 {
+                // w[t] = σ1(w[t-2]) + w[t-7] + σ0(w[t-15]) + w[t-16], in a ring of 16.
+                let w2 = w[(t - 2) % 16];
+                let w15 = w[(t - 15) % 16];
+                let s0 = xor3(rotr(w15, 7), rotr(w15, 18), _mm256_srli_epi32(w15, 3));
+                let s1 = xor3(rotr(w2, 17), rotr(w2, 19), _mm256_srli_epi32(w2, 10));
+                w[t % 16] = add4(s1, w[(t - 7) % 16], s0, w[t % 16]);
+            }
+            let s1 = xor3(rotr(e, 6), rotr(e, 11), rotr(e, 25));
+            let ch = _mm256_xor_si256(_mm256_and_si256(e, f), _mm256_andnot_si256(e, g));
+            let t1 = _mm256_add_epi32(
+                add4(h, s1, ch, _mm256_set1_epi32(k as i32)),
+                w[t % 16]);
+            let s0 = xor3(rotr(a, 2), rotr(a, 13), rotr(a, 22));
+            let maj = _mm256_xor_si256(
+                _mm256_and_si256(a, b),
+                _mm256_and_si256(c, _mm256_xor_si256(a, b)));
+            let t2 = _mm256_add_epi32(s0, maj);
+            h = g;
+            g = f;
+            f = e;
+            e = _mm256_add_epi32(d, t1);
+            d = c;
+            c = b;
+            b = a;
+            a = _mm256_add_epi32(t1, t2);
+        }
+        for (s, x) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = _mm256_add_epi32(*s, x);
+        }
+    }
+
+    /// Load eight big-endian words from each chunk at the given offset, and transpose them so
+    /// that `out[i]` holds word `i` from every chunk.
+    #[target_feature(enable = "avx2")]
+    unsafe fn load_words(out: &mut [__m256i], chunks: [&[u8]; LANES], offset: usize) {
+        let bswap = _mm256_setr_epi8(
+            3, 2, 1, 0, 7, 6, 5, 4, 11, 10, 9, 8, 15, 14, 13, 12,
+            3, 2, 1, 0, 7, 6, 5, 4, 11, 10, 9, 8, 15, 14, 13, 12);
+        let r = chunks.map(|chunk| {
+            let v = _mm256_loadu_si256(chunk[offset .. offset + 32].as_ptr() as *const __m256i);
+            _mm256_shuffle_epi8(v, bswap)
+        });
+
+        // Standard 8x8 transpose of 32-bit elements.
+        let t0 = _mm256_unpacklo_epi32(r[0], r[1]);
+        let t1 = _mm256_unpackhi_epi32(r[0], r[1]);
+        let t2 = _mm256_unpacklo_epi32(r[2], r[3]);
+        let t3 = _mm256_unpackhi_epi32(r[2], r[3]);
+        let t4 = _mm256_unpacklo_epi32(r[4], r[5]);
+        let t5 = _mm256_unpackhi_epi32(r[4], r[5]);
+        let t6 = _mm256_unpacklo_epi32(r[6], r[7]);
+        let t7 = _mm256_unpackhi_epi32(r[6], r[7]);
+        let u0 = _mm256_unpacklo_epi64(t0, t2);
+        let u1 = _mm256_unpackhi_epi64(t0, t2);
+        let u2 = _mm256_unpacklo_epi64(t1, t3);
+        let u3 = _mm256_unpackhi_epi64(t1, t3);
+        let u4 = _mm256_unpacklo_epi64(t4, t6);
+        let u5 = _mm256_unpackhi_epi64(t4, t6);
+        let u6 = _mm256_unpacklo_epi64(t5, t7);
+        let u7 = _mm256_unpackhi_epi64(t5, t7);
+        out[0] = _mm256_permute2x128_si256(u0, u4, 0x20);
+        out[1] = _mm256_permute2x128_si256(u1, u5, 0x20);
+        out[2] = _mm256_permute2x128_si256(u2, u6, 0x20);
+        out[3] = _mm256_permute2x128_si256(u3, u7, 0x20);
+        out[4] = _mm256_permute2x128_si256(u0, u4, 0x31);
+        out[5] = _mm256_permute2x128_si256(u1, u5, 0x31);
+        out[6] = _mm256_permute2x128_si256(u2, u6, 0x31);
+        out[7] = _mm256_permute2x128_si256(u3, u7, 0x31);
+    }
+
+    #[inline(always)]
+    unsafe fn rotr(x: __m256i, n: i32) -> __m256i {
+        _mm256_or_si256(
+            _mm256_srlv_epi32(x, _mm256_set1_epi32(n)),
+            _mm256_sllv_epi32(x, _mm256_set1_epi32(32 - n)))
+    }
+
+    #[inline(always)]
+    unsafe fn xor3(a: __m256i, b: __m256i, c: __m256i) -> __m256i {
+        _mm256_xor_si256(_mm256_xor_si256(a, b), c)
+    }
+
+    #[inline(always)]
+    unsafe fn add4(a: __m256i, b: __m256i, c: __m256i, d: __m256i) -> __m256i {
+        _mm256_add_epi32(_mm256_add_epi32(a, b), _mm256_add_epi32(c, d))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentHasher;
+
+    #[test]
+    fn matches_ring() {
+        // Cover every padding case: tails shorter than, equal to, and past the length field.
+        for &len in &[0, 1, 55, 56, 63, 64, 65, 119, 120, 1000, BLOCK_SIZE] {
+            let data = (0 .. LANES * len).map(|i| (i % 253) as u8).collect::<Vec<u8>>();
+            let mut blocks = data.chunks(len.max(1));
+            let blocks = [(); LANES].map(|()| blocks.next().map_or(&[][..], |b| &b[.. len]));
+            let hashes = hash_blocks(blocks);
+            for (block, hash) in blocks.iter().zip(&hashes) {
+                assert_eq!(digest(&SHA256, block).as_ref(), &hash[..], "len={}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_matches_serial() {
+        for &len in &[0, 5, 8 * BLOCK_SIZE, 9 * BLOCK_SIZE + 1] {
+            let data = (0 .. len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+            assert_eq!(expected, content_hash_from_stream(&data[..]).unwrap(), "len={}", len);
+        }
+    }
+}
@@ -1,41 +1,171 @@
 //! Compute a content hash from a file or other stream, using multiple threads.
 
-use crate::{BLOCK_SIZE, HASH_OUTPUT_SIZE};
-use parallel_reader::read_stream_and_process_chunks_in_parallel;
-use ring::digest::{digest, Context, Digest, SHA256};
-use std::collections::BTreeMap;
+use crate::{block_index, num_blocks, BLOCK_SIZE, HASH_OUTPUT_SIZE, CancelToken, Cancelled, Metrics};
+use crate::direct::{self, AlignedBuffer};
+use crate::read::read_full;
+use crate::sha256::{digest, Context, Digest, SHA256};
+use crate::trace::{debug, debug_span, trace, Span};
+use std::collections::{BTreeMap, VecDeque};
 use std::convert::TryInto;
-use std::io::{self, Read};
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+#[cfg(windows)]
+mod overlapped;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
+
+/// A function which is given each block's number and hash, in order, as they are computed. It is
+/// called from a different thread than the one that started the hashing.
+pub type BlockHashesFn = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+
+/// The ways parallel hashing can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// A block other than the last one in the stream was shorter than [`BLOCK_SIZE`].
+    ShortBlockMidStream {
+        /// The offset in the stream of the short block.
+        offset: u64,
+    },
+
+    /// Reading from the source failed.
+    Read(io::Error),
+
+    /// A worker thread, or a callback given in the [`Options`], panicked.
+    WorkerPanicked,
+
+    /// Hashing was stopped using a [`CancelToken`].
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ShortBlockMidStream { offset } =>
+                write!(f, "incomplete block mid-stream at offset {:#x}", offset),
+            Error::Read(e) => write!(f, "read error: {}", e),
+            Error::WorkerPanicked => f.write_str("a hashing thread panicked"),
+            Error::Cancelled => Cancelled.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Read(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Read(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Read(e) => e,
+            Error::ShortBlockMidStream { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            Error::WorkerPanicked => io::Error::other(e),
+            Error::Cancelled => Cancelled.into(),
+        }
+    }
+}
+
+/// How far along a parallel hash is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of bytes read from the source so far.
+    pub bytes_read: u64,
+
+    /// The number of bytes hashed so far. This lags behind the number of bytes read, by the
+    /// blocks which are waiting for or being processed by a worker thread.
+    pub bytes_hashed: u64,
+}
+
+/// A function which is given the progress of a parallel hash after each block is hashed. It is
+/// called from a different thread than the one that started the hashing.
+pub type ProgressFn = Arc<dyn Fn(Progress) + Send + Sync>;
 
 struct State {
     blocks: BTreeMap<u64, Digest>,
     next_offset: u64,
     overall_hash: Context,
+    /// The length of the stream, if it's known up front.
+    len: Option<u64>,
     incomplete_block_offset: Option<u64>,
+    block_hashes_fn: Option<BlockHashesFn>,
 }
 
-impl Default for State {
-    fn default() -> Self {
+impl State {
+    pub fn new(block_hashes_fn: Option<BlockHashesFn>, len: Option<u64>) -> Self {
         Self {
             blocks: BTreeMap::new(),
             next_offset: 0,
             overall_hash: Context::new(&SHA256),
+            len,
             incomplete_block_offset: None,
+            block_hashes_fn,
         }
     }
-}
 
-impl State {
     /// Add a finished block to the internal hash buffer and update the overall hash if possible.
-    pub fn add_block(&mut self, block_hash: Digest, offset: u64) {
+    ///
+    /// Only the last block in the stream can be smaller than the full block size; if that is
+    /// violated, this returns the offset of the first short block. If the length of the stream is
+    /// known, this returns the offset of any block that isn't the length it should be.
+    pub fn add_block(&mut self, block_hash: Digest, offset: u64, len: usize) -> Result<(), u64> {
+        match self.len {
+            Some(total) => {
+                if len as u64 != total.saturating_sub(offset).min(BLOCK_SIZE as u64) {
+                    return Err(offset);
+                }
+            }
+            None => self.check_short_block(offset, len)?,
+        }
+
         if offset == self.next_offset {
             // shortcut: skip adding to the block map and add it directly
             self.incorporate_next_block(block_hash);
         } else {
+            trace!(offset, waiting_for = self.next_offset, "holding a block until the ones \
+                before it are hashed");
             self.blocks.insert(offset, block_hash);
         }
         self.update_overall_hash();
+        Ok(())
+    }
+
+    /// Without knowing the length of the stream, the last block can't be told apart from a short
+    /// block in the middle until the blocks after it turn up, so keep track of the first short
+    /// block seen, and return the offset of the first one if there turn out to be two, or one
+    /// with blocks after it.
+    fn check_short_block(&mut self, offset: u64, len: usize) -> Result<(), u64> {
+        if let Some(other_offset) = self.incomplete_block_offset {
+            // Check where the other one is; if it's after this, it might be okay because it
+            // might be the last block in the stream.
+            if other_offset < offset {
+                return Err(other_offset);
+            }
+        }
+        if len != BLOCK_SIZE {
+            if let Some(other_offset) = self.incomplete_block_offset {
+                return Err(offset.min(other_offset));
+            }
+            self.incomplete_block_offset = Some(offset);
+        }
+        Ok(())
     }
 
     /// Consume sequential blocks in the internal hash buffer and update the overall hash.
@@ -52,7 +182,9 @@ impl State {
 
     /// Add a block to the overall hash and update the next offset pointer.
     fn incorporate_next_block(&mut self, hash: Digest) {
-        println!("block: {}", crate::hex_string(hash.as_ref()));
+        if let Some(f) = &self.block_hashes_fn {
+            f(block_index(self.next_offset), hash.as_ref());
+        }
         self.overall_hash.update(hash.as_ref());
         self.next_offset += BLOCK_SIZE as u64;
     }
@@ -68,56 +200,1745 @@ impl State {
     }
 }
 
+/// Options for parallel hashing.
+#[derive(Clone)]
+pub struct Options {
+    workers: Workers,
+    queue_depth: Option<usize>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    block_hashes_fn: Option<BlockHashesFn>,
+    progress_fn: Option<ProgressFn>,
+    cancel: Option<CancelToken>,
+    metrics: Option<Arc<dyn Metrics>>,
+    file_backend: Option<FileBackend>,
+}
+
+impl Options {
+    /// Hash using the given number of threads.
+    ///
+    /// By default, up to twice as many blocks as there are threads may be held in memory at once:
+    /// one being hashed by each thread, and one waiting for each thread.
+    pub fn new(num_threads: usize) -> Self {
+        Self {
+            workers: Workers::Threads(num_threads.max(1)),
+            queue_depth: None,
+            buffer_pool: None,
+            block_hashes_fn: None,
+            progress_fn: None,
+            cancel: None,
+            metrics: None,
+            file_backend: None,
+        }
+    }
+
+    /// Hash using one thread per logical CPU, but no more than `max_threads` if given.
+    pub fn auto(max_threads: Option<usize>) -> Self {
+        let num_threads = available_threads();
+        Self::new(max_threads.map_or(num_threads, |max| num_threads.min(max)))
+    }
+
+    /// Limit the number of blocks (of [`BLOCK_SIZE`] each) that may be in memory at once, whether
+    /// being read, waiting to be hashed, or being hashed. This bounds the memory used to
+    /// `queue_depth * BLOCK_SIZE` bytes. Values lower than the number of threads will leave some
+    /// threads idle.
+    ///
+    /// This isn't used with a [`file_backend`](Self::file_backend), where each thread reads its
+    /// own blocks; the io_uring backend has a queue depth of its own.
+    pub fn queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = Some(queue_depth.max(1));
+        self
+    }
+
+    /// Hash blocks as tasks on the given rayon thread pool, or on the global one if `None`,
+    /// instead of on threads dedicated to this hash. The number of threads given to
+    /// [`new`](Self::new) is ignored, and the default queue depth is twice the number of threads
+    /// in the pool.
+    #[cfg(feature = "rayon")]
+    pub fn rayon_pool(mut self, pool: Option<Arc<rayon::ThreadPool>>) -> Self {
+        self.workers = Workers::Rayon(pool);
+        self
+    }
+
+    /// Hash blocks as tasks on the given executor, such as a thread pool shared with the rest of
+    /// the program, instead of on threads dedicated to this hash. The number of threads given to
+    /// [`new`](Self::new) is ignored, and the default queue depth is twice the executor's
+    /// [`num_threads`](Spawn::num_threads).
+    pub fn spawner(mut self, spawner: Arc<dyn Spawn>) -> Self {
+        self.workers = Workers::Spawner(spawner);
+        self
+    }
+
+    fn effective_queue_depth(&self) -> usize {
+        self.queue_depth.unwrap_or_else(|| 2 * self.workers.num_threads())
+    }
+
+    /// Take block buffers from the given pool, and return them to it when done, instead of using
+    /// a new pool for each call. This lets a program hashing many streams reuse the same buffers
+    /// for all of them.
+    ///
+    /// This isn't used with a [`file_backend`](Self::file_backend), which reads into buffers of
+    /// its own, aligned for files opened with [`direct::open`].
+    pub fn buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Feed block hashes to the given function, in block order.
+    pub fn block_hashes_fn(mut self, f: BlockHashesFn) -> Self {
+        self.block_hashes_fn = Some(f);
+        self
+    }
+
+    /// Report progress to the given function after each block is hashed. With a
+    /// [`file_backend`](Self::file_backend), blocks are read and hashed out of order, but the
+    /// totals still only go up.
+    pub fn progress_fn(mut self, f: ProgressFn) -> Self {
+        self.progress_fn = Some(f);
+        self
+    }
+
+    /// Check the given token before reading each block, and stop with an [`Error::Cancelled`] if
+    /// it has been cancelled.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Report the bytes read, each block hashed, and the time taken by each hash to the given
+    /// metrics. Blocks are reported from the worker threads, and with a
+    /// [`file_backend`](Self::file_backend), so are the bytes read.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Have [`content_hash_from_file_with_options`] read the file's blocks in parallel using the
+    /// given backend, as [`content_hash_from_file_with_backend`] does, instead of reading it from
+    /// start to end. The cancel token is checked before reading each block, progress and metrics
+    /// are reported as each block is read and hashed, and the block hashes function is still
+    /// called in block order, once all of the blocks have been hashed. The queue depth and buffer
+    /// pool aren't used. Files without a length to go by, like pipes, are read as a stream
+    /// instead, as [`content_hash_from_path`] describes.
+    pub fn file_backend(mut self, backend: FileBackend) -> Self {
+        self.file_backend = Some(backend);
+        self
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("workers", &self.workers)
+            .field("queue_depth", &self.queue_depth)
+            .field("buffer_pool", &self.buffer_pool)
+            .field("block_hashes_fn", &self.block_hashes_fn.as_ref().map(|_| "Fn"))
+            .field("progress_fn", &self.progress_fn.as_ref().map(|_| "Fn"))
+            .field("cancel", &self.cancel)
+            .field("metrics", &self.metrics.as_ref().map(|_| "Metrics"))
+            .field("file_backend", &self.file_backend)
+            .finish()
+    }
+}
+
+/// An executor, such as a thread pool, which can run the tasks that hash each block.
+///
+/// The tasks must be able to run while the thread that started the hash is blocked waiting for
+/// them; for example, they must not be run on that thread, or queued behind a task running there.
+pub trait Spawn: Send + Sync {
+    /// Run the given task at some point, on some thread.
+    fn spawn(&self, task: Box<dyn FnOnce() + Send + 'static>);
+
+    /// How many tasks can run at once.
+    fn num_threads(&self) -> usize;
+}
+
+/// What hashes the blocks.
+#[derive(Clone)]
+enum Workers {
+    /// Threads dedicated to one hash.
+    Threads(usize),
+
+    /// Tasks on a rayon thread pool, or the global pool if `None`.
+    #[cfg(feature = "rayon")]
+    Rayon(Option<Arc<rayon::ThreadPool>>),
+
+    /// Tasks on a caller-provided executor.
+    Spawner(Arc<dyn Spawn>),
+}
+
+impl Workers {
+    fn num_threads(&self) -> usize {
+        match self {
+            Workers::Threads(n) => *n,
+            #[cfg(feature = "rayon")]
+            Workers::Rayon(Some(pool)) => pool.current_num_threads(),
+            #[cfg(feature = "rayon")]
+            Workers::Rayon(None) => rayon::current_num_threads(),
+            Workers::Spawner(spawner) => spawner.num_threads().max(1),
+        }
+    }
+}
+
+impl fmt::Debug for Workers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Workers::Threads(n) => f.debug_tuple("Threads").field(n).finish(),
+            #[cfg(feature = "rayon")]
+            Workers::Rayon(pool) => f.debug_tuple("Rayon").field(pool).finish(),
+            Workers::Spawner(_) => f.write_str("Spawner"),
+        }
+    }
+}
+
+/// A pool of reusable block buffers.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_idle: usize,
+}
+
+impl BufferPool {
+    /// Make an empty pool which keeps up to `max_idle` unused buffers (of [`BLOCK_SIZE`] each)
+    /// around for reuse. Buffers are allocated as needed, and any beyond that many which are
+    /// returned to the pool are freed.
+    pub fn new(max_idle: usize) -> Self {
+        Self {
+            buffers: Mutex::new(vec![]),
+            max_idle,
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop()
+            .unwrap_or_else(|| vec![0u8; BLOCK_SIZE])
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_idle {
+            buffers.push(buf);
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("idle", &self.buffers.lock().unwrap().len())
+            .field("max_idle", &self.max_idle)
+            .finish()
+    }
+}
+
+/// The number of logical CPUs, or 1 if it can't be determined. This is the number of threads
+/// used by [`Options::auto`], and a good choice for the `num_threads` argument of the functions in
+/// this module.
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
 /// Compute a content hash from the given file or other stream, using the specified number of
 /// threads to do the computation in parallel.
+///
+/// The worker threads send their block hashes to a single reducer thread, which puts them in order
+/// and computes the overall hash.
 pub fn content_hash_from_stream(
     source: impl Read,
     num_threads: usize,
-) -> io::Result<[u8; HASH_OUTPUT_SIZE]> {
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    content_hash_from_stream_with_options(source, &Options::new(num_threads))
+}
+
+/// Like [`content_hash_from_stream`], but with more options.
+pub fn content_hash_from_stream_with_options(
+    mut source: impl Read,
+    options: &Options,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    let mut pipeline = Pipeline::new(options, None);
+    let mut read_result = Ok(());
+    while let Some(mut buf) = pipeline.take_slot() {
+        if options.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            debug!("cancelled");
+            pipeline.give_back(buf);
+            read_result = Err(Error::Cancelled);
+            break;
+        }
+        match read_full(&mut source, &mut buf) {
+            Ok(0) => {
+                pipeline.give_back(buf);
+                break;
+            }
+            Ok(len) => {
+                if !pipeline.submit(buf, len) || len < BLOCK_SIZE {
+                    break;
+                }
+            }
+            Err(e) => {
+                pipeline.give_back(buf);
+                read_result = Err(Error::Read(e));
+                break;
+            }
+        }
+    }
+
+    let digest = pipeline.finish()?;
+    read_result.map(|()| digest.as_ref().try_into().expect("hash output is of wrong size"))
+}
 
-    let state = Arc::new(Mutex::new(State::default()));
-    let thread_state = state.clone();
-    match read_stream_and_process_chunks_in_parallel(source, BLOCK_SIZE, num_threads,
-        Arc::new(move |offset, data: &[u8]| -> Result<(), u64> {
-            let block_hash = digest(&SHA256, data);
-            let mut state = thread_state.lock().unwrap();
+/// Compute the content hash of the file at the given path, reading it from start to end and
+/// hashing its blocks on the workers given in the options.
+///
+/// Unlike [`content_hash_from_stream_with_options`], this knows the length of the file before it
+/// starts, and so how long each block should be. The length is taken from the file's metadata at
+/// the start; if the file is truncated while it is being read, an [`Error::Read`] of kind
+/// `UnexpectedEof` is returned, and anything added to the end is ignored.
+///
+/// Only regular files and block devices have a length to go by, and files in `/proc` and the like
+/// say they're empty when they aren't, so anything else, or a file which says it's empty, is read
+/// with [`content_hash_from_stream_with_options`] instead, whatever backend the options give.
+pub fn content_hash_from_path(
+    path: impl AsRef<Path>,
+    options: &Options,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    content_hash_from_file_with_options(&File::open(path)?, options)
+}
 
-            // Only the last block in the stream can be smaller than the full block size.
-            if let Some(other_offset) = state.incomplete_block_offset {
-                // Check where the other one is; if it's after this, it might be okay because it
-                // might be the last block in the stream.
-                if other_offset < offset {
-                    return Err(other_offset);
+/// Like [`content_hash_from_path`], for a file which is already open. It is read from the start,
+/// wherever its cursor is, unless it's a pipe or the like, which is read from where it is.
+pub fn content_hash_from_file_with_options(
+    file: &File,
+    options: &Options,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    let mut file = file;
+    let len = match known_len(file)? {
+        Some(len) => len,
+        None => {
+            debug!("length unknown; reading it as a stream");
+            if file.metadata()?.is_file() {
+                file.seek(SeekFrom::Start(0))?;
+            }
+            return content_hash_from_stream_with_options(file, options);
+        }
+    };
+    if let Some(backend) = options.file_backend {
+        return backend_content_hash(file, len, backend, options);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let mut pipeline = Pipeline::new(options, Some(len));
+    let mut read_result = Ok(());
+    let mut offset = 0;
+    while offset < len {
+        let mut buf = match pipeline.take_slot() {
+            Some(buf) => buf,
+            None => break,
+        };
+        if options.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            debug!("cancelled");
+            pipeline.give_back(buf);
+            read_result = Err(Error::Cancelled);
+            break;
+        }
+        let block_len = (len - offset).min(BLOCK_SIZE as u64) as usize;
+        match read_full(&mut file, &mut buf[.. block_len]) {
+            Ok(n) if n == block_len => {
+                offset += n as u64;
+                if !pipeline.submit(buf, n) {
+                    break;
                 }
             }
-            if data.len() != BLOCK_SIZE {
-                if let Some(other_offset) = state.incomplete_block_offset {
-                    return Err(offset.min(other_offset));
+            Ok(n) => {
+                pipeline.give_back(buf);
+                read_result = Err(Error::Read(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("file ended unexpectedly at offset {:#x}", offset + n as u64))));
+                break;
+            }
+            Err(e) => {
+                pipeline.give_back(buf);
+                read_result = Err(Error::Read(e));
+                break;
+            }
+        }
+    }
+
+    let digest = pipeline.finish()?;
+    read_result.map(|()| digest.as_ref().try_into().expect("hash output is of wrong size"))
+}
+
+/// A context for multi-step Content Hash calculation, which hashes blocks on a pool of worker
+/// threads.
+///
+/// This has the same interface as [`ContentHasher`](crate::ContentHasher), so it can be fed data
+/// as it arrives. Each block is copied into a buffer, which is handed to a worker thread once it is
+/// full. If the number of blocks in memory reaches the queue depth given in the options, calls to
+/// `update` wait for the workers to catch up.
+pub struct ParallelContentHasher {
+    pipeline: Pipeline,
+    buf: Option<Vec<u8>>,
+    partial: usize,
+}
+
+impl ParallelContentHasher {
+    /// Create a new, empty, hasher using the given number of threads.
+    pub fn new(num_threads: usize) -> Self {
+        Self::with_options(&Options::new(num_threads))
+    }
+
+    /// Create a new, empty, hasher with the given options.
+    pub fn with_options(options: &Options) -> Self {
+        Self {
+            pipeline: Pipeline::new(options, None),
+            buf: None,
+            partial: 0,
+        }
+    }
+
+    /// Update the content hash with some data.
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let buf = match self.buf {
+                Some(ref mut buf) => buf,
+                None => match self.pipeline.take_slot() {
+                    Some(buf) => self.buf.insert(buf),
+                    None => return, // the workers have stopped; finish() will say why
+                },
+            };
+            let n = (BLOCK_SIZE - self.partial).min(bytes.len());
+            buf[self.partial .. self.partial + n].copy_from_slice(&bytes[.. n]);
+            self.partial += n;
+            bytes = &bytes[n ..];
+            if self.partial == BLOCK_SIZE {
+                self.pipeline.submit(self.buf.take().unwrap(), BLOCK_SIZE);
+                self.partial = 0;
+            }
+        }
+    }
+
+    /// Wait for all blocks to be hashed, and return the content hash bytes.
+    pub fn finish(mut self) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+        if let Some(buf) = self.buf.take() {
+            if self.partial != 0 {
+                self.pipeline.submit(buf, self.partial);
+            } else {
+                self.pipeline.give_back(buf);
+            }
+        }
+        let digest = self.pipeline.finish()?;
+        Ok(digest.as_ref().try_into().expect("hash output is of wrong size"))
+    }
+
+    /// Wait for all blocks to be hashed, and return the content hash as a hexadecimal string.
+    pub fn finish_str(self) -> Result<String, Error> {
+        self.finish().map(|hash| crate::hex_string(&hash))
+    }
+}
+
+/// A block of data to be hashed, and its offset in the stream.
+struct Job {
+    offset: u64,
+    buf: Vec<u8>,
+    len: usize,
+}
+
+/// What a worker needs to hash a block and pass the result along.
+#[derive(Clone)]
+struct Worker {
+    pool: Arc<BufferPool>,
+    slot_tx: mpsc::SyncSender<()>,
+    // Block hashes are None if the worker panicked while computing it.
+    block_tx: mpsc::Sender<(u64, usize, Option<Digest>)>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl Worker {
+    /// Hash a block, return its buffer and slot, and send the hash to the reducer. Returns false
+    /// if the reducer has stopped.
+    fn hash(&self, job: Job) -> bool {
+        let block_hash = panic::catch_unwind(AssertUnwindSafe(|| {
+            digest(&SHA256, &job.buf[.. job.len])
+        })).ok();
+        if block_hash.is_none() {
+            debug!(offset = job.offset, "a worker panicked while hashing a block");
+        }
+        trace!(offset = job.offset, len = job.len, "hashed a block");
+        if let (Some(metrics), Some(_)) = (&self.metrics, &block_hash) {
+            metrics.block_hashed(job.len);
+        }
+        self.pool.put(job.buf);
+        let _ = self.slot_tx.send(());
+        // If this fails, the reducer has found a problem and stopped, and it has the details.
+        self.block_tx.send((job.offset, job.len, block_hash)).is_ok()
+    }
+}
+
+/// How blocks get to the workers.
+enum Dispatch {
+    Threads {
+        job_tx: mpsc::Sender<Job>,
+        handles: Vec<thread::JoinHandle<()>>,
+    },
+    /// A task is spawned for each block; the Workers are anything but Threads.
+    Tasks {
+        workers: Workers,
+        worker: Worker,
+    },
+}
+
+/// Workers that hash blocks, and a reducer thread which combines their hashes.
+///
+/// The number of blocks in memory at once is limited by a fixed number of slots, which must be
+/// taken before reading each block, and which are given back by the workers, along with the
+/// block's buffer, when they finish hashing it.
+struct Pipeline {
+    dispatch: Dispatch,
+    slot_rx: mpsc::Receiver<()>,
+    pool: Arc<BufferPool>,
+    bytes_read: Arc<AtomicU64>,
+    reducer: thread::JoinHandle<Result<Digest, Error>>,
+    next_offset: u64,
+    span: Span,
+    metrics: Option<Arc<dyn Metrics>>,
+    start: Instant,
+}
+
+impl Pipeline {
+    /// Start the workers and the reducer, for a stream of the given length if it's known.
+    pub fn new(options: &Options, len: Option<u64>) -> Self {
+        let queue_depth = options.effective_queue_depth();
+        let span = debug_span!("parallel_hash", workers = ?options.workers, queue_depth);
+        let _entered = span.clone().entered();
+        debug!("starting the pipeline");
+        let (slot_tx, slot_rx) = mpsc::sync_channel(queue_depth);
+        for _ in 0 .. queue_depth {
+            slot_tx.send(()).unwrap();
+        }
+        let (block_tx, block_rx) = mpsc::channel();
+        let pool = options.buffer_pool.clone()
+            .unwrap_or_else(|| Arc::new(BufferPool::new(queue_depth)));
+        let worker = Worker {
+            pool: Arc::clone(&pool),
+            slot_tx,
+            block_tx,
+            metrics: options.metrics.clone(),
+        };
+
+        let dispatch = match &options.workers {
+            Workers::Threads(num_threads) => {
+                let (job_tx, job_rx) = mpsc::channel::<Job>();
+                let job_rx = Arc::new(Mutex::new(job_rx));
+                let handles = (0 .. *num_threads)
+                    .map(|_| {
+                        let job_rx = Arc::clone(&job_rx);
+                        let worker = worker.clone();
+                        let span = span.clone();
+                        thread::spawn(move || span.in_scope(|| loop {
+                            let job = match job_rx.lock().unwrap().recv() {
+                                Ok(job) => job,
+                                Err(_) => break, // no more blocks
+                            };
+                            if !worker.hash(job) {
+                                break;
+                            }
+                        }))
+                    })
+                    .collect();
+                Dispatch::Threads { job_tx, handles }
+            }
+            workers => Dispatch::Tasks { workers: workers.clone(), worker },
+        };
+
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let reducer_bytes_read = Arc::clone(&bytes_read);
+        let block_hashes_fn = options.block_hashes_fn.clone();
+        let progress_fn = options.progress_fn.clone();
+        let reducer_span = span.clone();
+        let reducer = thread::spawn(move || reducer_span.in_scope(|| -> Result<Digest, Error> {
+            let mut state = State::new(block_hashes_fn, len);
+            let mut bytes_hashed = 0;
+            for (offset, len, block_hash) in block_rx {
+                let block_hash = block_hash.ok_or(Error::WorkerPanicked)?;
+                state.add_block(block_hash, offset, len)
+                    .map_err(|offset| Error::ShortBlockMidStream { offset })?;
+                bytes_hashed += len as u64;
+                if let Some(f) = &progress_fn {
+                    f(Progress {
+                        bytes_read: reducer_bytes_read.load(Ordering::Relaxed),
+                        bytes_hashed,
+                    });
+                }
+            }
+            debug!(bytes = bytes_hashed, "combined the block hashes");
+            Ok(state.finish())
+        }));
+
+        Self {
+            dispatch,
+            slot_rx,
+            pool,
+            bytes_read,
+            reducer,
+            next_offset: 0,
+            span,
+            metrics: options.metrics.clone(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Wait for a free slot, and return a buffer to read the next block into. Returns `None` if
+    /// the workers have stopped because of an error.
+    pub fn take_slot(&self) -> Option<Vec<u8>> {
+        #[cfg(feature = "rayon")]
+        if let Dispatch::Tasks { workers: Workers::Rayon(_), .. } = self.dispatch {
+            // If this is running on a rayon thread, the tasks which would free up a slot might be
+            // queued up behind this one, so run them while waiting.
+            loop {
+                match self.slot_rx.try_recv() {
+                    Ok(()) => return Some(self.pool.take()),
+                    Err(mpsc::TryRecvError::Disconnected) => return None,
+                    Err(mpsc::TryRecvError::Empty) => (),
+                }
+                if rayon::yield_now() != Some(rayon::Yield::Executed) {
+                    break;
                 }
-                state.incomplete_block_offset = Some(offset);
             }
+        }
+        self.slot_rx.recv().ok().map(|()| self.pool.take())
+    }
 
-            state.add_block(block_hash, offset);
+    /// Return an unused buffer to the pool. This is only for when no more blocks will be read, as
+    /// its slot is not given back.
+    pub fn give_back(&self, buf: Vec<u8>) {
+        self.pool.put(buf);
+    }
 
-            Ok(())
-        }),
-    ) {
-        Ok(()) => (),
-        Err(parallel_reader::Error::Read(io_err)) => return Err(io_err),
-        Err(parallel_reader::Error::Process { chunk_offset: _, error: bad_offset }) => {
-            return Err(io::Error::new(
+    /// Send the next block, which is the first `len` bytes of the buffer, off to be hashed.
+    /// Returns false if the workers have stopped because of an error.
+    pub fn submit(&mut self, buf: Vec<u8>, len: usize) -> bool {
+        let offset = self.next_offset;
+        self.next_offset += len as u64;
+        self.bytes_read.store(self.next_offset, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_read(len as u64);
+        }
+        let job = Job { offset, buf, len };
+        match &self.dispatch {
+            Dispatch::Threads { job_tx, .. } => job_tx.send(job).is_ok(),
+            Dispatch::Tasks { workers, worker } => {
+                let worker = worker.clone();
+                let task = move || {
+                    worker.hash(job);
+                };
+                match workers {
+                    Workers::Threads(_) => unreachable!(),
+                    #[cfg(feature = "rayon")]
+                    Workers::Rayon(Some(pool)) => pool.spawn(task),
+                    #[cfg(feature = "rayon")]
+                    Workers::Rayon(None) => rayon::spawn(task),
+                    Workers::Spawner(spawner) => spawner.spawn(Box::new(task)),
+                }
+                // The reducer only stops early if there's a problem.
+                !self.reducer.is_finished()
+            }
+        }
+    }
+
+    /// Wait for all blocks to be hashed, and return the overall hash.
+    pub fn finish(self) -> Result<Digest, Error> {
+        let Pipeline { dispatch, slot_rx, reducer, span, metrics, start, .. } = self;
+        let _entered = span.entered();
+        debug!("waiting for the workers to finish");
+        drop(slot_rx);
+        #[cfg(feature = "rayon")]
+        let on_rayon = matches!(dispatch, Dispatch::Tasks { workers: Workers::Rayon(_), .. });
+        let mut result = Ok(());
+        match dispatch {
+            Dispatch::Threads { job_tx, handles } => {
+                drop(job_tx);
+                for handle in handles {
+                    if handle.join().is_err() {
+                        result = Err(Error::WorkerPanicked);
+                    }
+                }
+            }
+            Dispatch::Tasks { worker, .. } => drop(worker),
+        }
+        #[cfg(feature = "rayon")]
+        if on_rayon {
+            // As in take_slot(), run any tasks queued up behind this one.
+            while !reducer.is_finished() {
+                if rayon::yield_now() != Some(rayon::Yield::Executed) {
+                    break;
+                }
+            }
+        }
+        let digest = match reducer.join().map_err(|_| Error::WorkerPanicked)? {
+            Ok(digest) => digest,
+            Err(e) => {
+                debug!(error = %e, "the pipeline stopped early");
+                return Err(e);
+            }
+        };
+        result?;
+        if let Some(metrics) = metrics {
+            metrics.finished(start.elapsed());
+        }
+        Ok(digest)
+    }
+}
+
+/// How [`content_hash_from_file_with_backend`] reads blocks from the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileBackend {
+    /// Every worker thread reads its own blocks using blocking positioned reads.
+    Pread,
+
+    /// A single thread keeps up to `queue_depth` block reads in flight using io_uring, and hands
+    /// them to the worker threads to be hashed as they complete.
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    Uring {
+        /// The maximum number of block reads in flight at once.
+        queue_depth: usize,
+    },
+
+    /// The file is mapped into memory, and every worker thread hashes its own blocks straight
+    /// from the mapping.
+    ///
+    /// This avoids copying the data into buffers, but if the file is truncated while it is being
+    /// hashed, the process will crash (with `SIGBUS` on Unix) instead of getting an error.
+    #[cfg(feature = "mmap")]
+    Mmap,
+
+    /// On Windows, the file is opened again without the file cache (using
+    /// `FILE_FLAG_NO_BUFFERING`), and a single thread keeps up to `queue_depth` block reads in
+    /// flight using overlapped I/O, into buffers aligned to the volume's sector size, and hands
+    /// them to the worker threads to be hashed as they complete.
+    #[cfg(windows)]
+    Overlapped {
+        /// The maximum number of block reads in flight at once.
+        queue_depth: usize,
+    },
+}
+
+/// Compute a content hash from the given file, using the specified number of threads, each of
+/// which reads its own blocks from the file using positioned reads.
+///
+/// Unlike [`content_hash_from_stream`], there is no single reader feeding the worker threads, so
+/// on storage that can service many reads at once (striped RAID, NVMe) this can go considerably
+/// faster. The file's length is taken from its metadata at the start; if the file is truncated
+/// while it is being read, an [`Error::Read`] of kind `UnexpectedEof` is returned. A file without
+/// a length to go by is read as a stream instead, as [`content_hash_from_path`] describes.
+///
+/// The file may have been opened with [`direct::open`] to bypass the page cache.
+pub fn content_hash_from_file(
+    file: &File,
+    num_threads: usize,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    content_hash_from_file_with_backend(file, num_threads, FileBackend::Pread)
+}
+
+/// Like [`content_hash_from_file`], but using the given method of reading the file.
+///
+/// To use other [`Options`] too, give the backend to [`Options::file_backend`] and use
+/// [`content_hash_from_file_with_options`].
+pub fn content_hash_from_file_with_backend(
+    file: &File,
+    num_threads: usize,
+    backend: FileBackend,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    content_hash_from_file_with_options(file, &Options::new(num_threads).file_backend(backend))
+}
+
+/// Hash every block of a file of the given length using a backend, then combine them, feeding
+/// them to the block hashes function in the options.
+fn backend_content_hash(
+    file: &File,
+    len: u64,
+    backend: FileBackend,
+    options: &Options,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    let num_threads = options.workers.num_threads();
+    let tracker = Tracker::new(options);
+    let start = Instant::now();
+    let _span = debug_span!("parallel_file_hash", threads = num_threads, ?backend, len).entered();
+    let block_hashes = match backend {
+        FileBackend::Pread => pread_block_hashes(file, len, num_threads, &tracker)?,
+        #[cfg(feature = "mmap")]
+        FileBackend::Mmap => mmap_block_hashes(file, len, num_threads, &tracker)?,
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        FileBackend::Uring { queue_depth } =>
+            uring::block_hashes(file, len, num_threads, queue_depth, &tracker)?,
+        #[cfg(windows)]
+        FileBackend::Overlapped { queue_depth } =>
+            overlapped::block_hashes(file, len, num_threads, queue_depth, &tracker)?,
+    };
+
+    let overall_hash = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut overall_hash = Context::new(&SHA256);
+        for (block, block_hash) in block_hashes.iter().enumerate() {
+            if let Some(f) = &options.block_hashes_fn {
+                f(block as u64, block_hash.as_ref());
+            }
+            overall_hash.update(block_hash.as_ref());
+        }
+        overall_hash.finish()
+    })).map_err(|_| Error::WorkerPanicked)?;
+    if let Some(metrics) = &options.metrics {
+        metrics.finished(start.elapsed());
+    }
+    Ok(overall_hash.as_ref().try_into().expect("hash output is of wrong size"))
+}
+
+/// Hash every block of the file, returning the block hashes in order.
+fn pread_block_hashes(
+    file: &File,
+    len: u64,
+    num_threads: usize,
+    tracker: &Tracker<'_>,
+) -> Result<Vec<Digest>, Error> {
+    strided_block_hashes(len, num_threads, tracker, || {
+        let mut buf = AlignedBuffer::new(BLOCK_SIZE);
+        move |offset, block_len| {
+            read_block_at(file, &mut buf, block_len, offset)?;
+            tracker.read(block_len);
+            Ok(digest(&SHA256, &buf[.. block_len]))
+        }
+    })
+}
+
+/// Hash every block of the file from a memory mapping of it, returning the block hashes in order.
+#[cfg(feature = "mmap")]
+fn mmap_block_hashes(
+    file: &File,
+    len: u64,
+    num_threads: usize,
+    tracker: &Tracker<'_>,
+) -> Result<Vec<Digest>, Error> {
+    if len == 0 {
+        return Ok(vec![]);
+    }
+    // SAFETY: the file is only read, and its length is fixed at the start. See the documentation
+    // of FileBackend::Mmap for what happens if the file is truncated anyway.
+    let map = unsafe { memmap2::MmapOptions::new().len(len as usize).map(file)? };
+    strided_block_hashes(len, num_threads, tracker, || {
+        let map = &map;
+        move |offset, block_len| {
+            let offset = offset as usize;
+            // Read as it's hashed, when the pages are faulted in.
+            tracker.read(block_len);
+            Ok(digest(&SHA256, &map[offset .. offset + block_len]))
+        }
+    })
+}
+
+/// Hash every block of a file of the given length on `num_threads` threads, using a function made
+/// by `make_hasher` on each thread, which takes the offset and length of a block and returns its
+/// hash. Thread N hashes blocks N, N + num_threads, N + 2 * num_threads, etc. Each thread checks
+/// the cancel token before each of its blocks, and tells the tracker when each one is hashed (the
+/// function tells it when each one is read).
+///
+/// Returns the block hashes in order.
+fn strided_block_hashes<F, H>(
+    len: u64,
+    num_threads: usize,
+    tracker: &Tracker<'_>,
+    make_hasher: F,
+) -> Result<Vec<Digest>, Error>
+    where F: Fn() -> H + Sync,
+          H: FnMut(u64, usize) -> io::Result<Digest>,
+{
+    let num_blocks = num_blocks(len);
+    let num_threads = (num_threads.max(1) as u64).min(num_blocks.max(1));
+
+    let span = Span::current();
+    let mut per_thread_hashes = thread::scope(|scope| {
+        let make_hasher = &make_hasher;
+        let span = &span;
+        let handles = (0 .. num_threads)
+            .map(|first_block| scope.spawn(move || span.in_scope(|| -> Result<_, Error> {
+                let mut hasher = make_hasher();
+                let mut hashes = vec![];
+                let mut block = first_block;
+                while block < num_blocks {
+                    if tracker.cancelled() {
+                        debug!("cancelled");
+                        return Err(Error::Cancelled);
+                    }
+                    let offset = block * BLOCK_SIZE as u64;
+                    let block_len = (len - offset).min(BLOCK_SIZE as u64) as usize;
+                    hashes.push(hasher(offset, block_len)?);
+                    trace!(offset, len = block_len, "hashed a block");
+                    tracker.hashed(block_len);
+                    block += num_threads;
+                }
+                Ok(hashes)
+            })))
+            .collect::<Vec<_>>();
+        handles.into_iter()
+            .map(|handle| match handle.join() {
+                Ok(result) => result.map(Vec::into_iter),
+                Err(_) => Err(Error::WorkerPanicked),
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+
+    Ok((0 .. num_blocks)
+        .map(|block| per_thread_hashes[(block % num_threads) as usize].next().unwrap())
+        .collect())
+}
+
+/// Compute the content hashes of many files, hashing the blocks of all of them on one set of
+/// threads, and return the results in the same order as the paths.
+///
+/// Each thread takes the next block to be hashed from whichever files are being worked through,
+/// and opens the next file once all of their blocks have been taken, so a mix of big and small
+/// files keeps all the threads busy. Blocks are read using positioned reads, as with
+/// [`content_hash_from_file`]. Files which can only be read from start to end (such as pipes),
+/// and regular files which claim to be empty (as ones in `/proc` do, whatever they hold), are
+/// each hashed on a single thread.
+///
+/// The number of threads is the one in the options; they are started just for this, even if the
+/// options say to use a rayon pool or a spawner. The cancel token, progress function, and metrics
+/// cover all of the files together, and the block hashes function and queue depth aren't used.
+/// Files which weren't finished when the hash was cancelled get [`Error::Cancelled`], and files
+/// whose hashing panicked, in a callback or otherwise, get [`Error::WorkerPanicked`].
+pub fn content_hashes_from_paths<P: AsRef<Path> + Sync>(
+    paths: &[P],
+    options: &Options,
+) -> Vec<Result<[u8; HASH_OUTPUT_SIZE], Error>> {
+    let num_threads = options.workers.num_threads();
+    let _span = debug_span!("parallel_many_hash", threads = num_threads, files = paths.len())
+        .entered();
+    let results = paths.iter().map(|_| Mutex::new(ManyResult::default())).collect::<Vec<_>>();
+    let schedule = Schedule::new(paths.len());
+    let hasher = ManyHasher { tracker: Tracker::new(options) };
+    let start = Instant::now();
+    let span = Span::current();
+    thread::scope(|scope| {
+        for _ in 0 .. num_threads.min(paths.len()) {
+            scope.spawn(|| span.in_scope(|| {
+                let mut buf = AlignedBuffer::new(BLOCK_SIZE);
+                while let Some(job) = schedule.next_job(options.cancel.as_ref()) {
+                    match job {
+                        ManyJob::Block { index, file, block, len } => {
+                            let hash = panic::catch_unwind(AssertUnwindSafe(|| {
+                                hasher.hash_block(&file, &mut buf, block, len)
+                            })).unwrap_or(Err(Error::WorkerPanicked));
+                            results[index].lock().unwrap().add_block(block, hash);
+                        }
+                        ManyJob::Open { index } => {
+                            // Opened outside of the schedule's lock, which would hold up the
+                            // other threads while waiting on a slow filesystem.
+                            let mut stream = None;
+                            let opened = panic::catch_unwind(AssertUnwindSafe(|| {
+                                match open_for_blocks(paths[index].as_ref()) {
+                                    Ok((file, Some(len))) => {
+                                        results[index].lock().unwrap().start(num_blocks(len));
+                                        let file = Arc::new(file);
+                                        Ok(Some(CurrentFile { index, file, len, next_block: 0 }))
+                                    }
+                                    Ok((file, None)) => {
+                                        stream = Some(file);
+                                        Ok(None)
+                                    }
+                                    Err(e) => Err(Error::Read(e)),
+                                }
+                            })).unwrap_or(Err(Error::WorkerPanicked));
+                            match opened {
+                                Ok(file) => schedule.opened(file),
+                                Err(e) => {
+                                    results[index].lock().unwrap().result = Some(Err(e));
+                                    schedule.opened(None);
+                                }
+                            }
+                            if let Some(file) = stream {
+                                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                                    hasher.hash_stream(&file, &mut buf)
+                                })).unwrap_or(Err(Error::WorkerPanicked));
+                                results[index].lock().unwrap().result = Some(result);
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+    });
+    if let Some(metrics) = &options.metrics {
+        metrics.finished(start.elapsed());
+    }
+    results.into_iter()
+        // Files are only left unfinished if the hash was cancelled.
+        .map(|result| result.into_inner().unwrap().result.unwrap_or(Err(Error::Cancelled)))
+        .collect()
+}
+
+/// Open a file for [`content_hashes_from_paths`], and get its length if its blocks can be read in
+/// any order.
+fn open_for_blocks(path: &Path) -> io::Result<(File, Option<u64>)> {
+    let file = File::open(path)?;
+    let len = known_len(&file)?;
+    Ok((file, len))
+}
+
+/// The length of a regular file or block device, unless it says it's empty; files in `/proc` and
+/// the like do even though they aren't, so they have to be read to find out. Anything else, like
+/// a pipe, has no length to go by.
+fn known_len(file: &File) -> io::Result<Option<u64>> {
+    let meta = file.metadata()?;
+    if meta.is_file() || crate::file::is_block_device(&meta) {
+        Ok(Some(crate::file::len(file)?).filter(|&len| len != 0))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Which block [`content_hashes_from_paths`] hashes next.
+struct Schedule {
+    state: Mutex<ScheduleState>,
+    /// Signalled when a file has been opened, or failed to open.
+    opened: Condvar,
+}
+
+struct ScheduleState {
+    next_path: usize,
+    num_paths: usize,
+    /// The files whose blocks are being handed out, in order.
+    files: VecDeque<CurrentFile>,
+    /// The number of files being opened, which might have more blocks to hand out.
+    opening: usize,
+}
+
+struct CurrentFile {
+    index: usize,
+    file: Arc<File>,
+    len: u64,
+    next_block: u64,
+}
+
+enum ManyJob {
+    Block { index: usize, file: Arc<File>, block: u64, len: usize },
+    Open { index: usize },
+}
+
+impl Schedule {
+    fn new(num_paths: usize) -> Self {
+        Self {
+            state: Mutex::new(ScheduleState {
+                next_path: 0,
+                num_paths,
+                files: VecDeque::new(),
+                opening: 0,
+            }),
+            opened: Condvar::new(),
+        }
+    }
+
+    /// Take the next block of the files being worked through, or the next file to open. Waits
+    /// for files being opened by other threads before saying there's nothing left to do, and
+    /// stops early if the hash is cancelled.
+    fn next_job(&self, cancel: Option<&CancelToken>) -> Option<ManyJob> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                debug!("cancelled");
+                return None;
+            }
+            if let Some(current) = state.files.front_mut() {
+                let offset = current.next_block * BLOCK_SIZE as u64;
+                let job = ManyJob::Block {
+                    index: current.index,
+                    file: Arc::clone(&current.file),
+                    block: current.next_block,
+                    len: (current.len - offset).min(BLOCK_SIZE as u64) as usize,
+                };
+                current.next_block += 1;
+                if current.next_block == num_blocks(current.len) {
+                    state.files.pop_front();
+                }
+                return Some(job);
+            }
+            if state.next_path < state.num_paths {
+                let index = state.next_path;
+                state.next_path += 1;
+                state.opening += 1;
+                return Some(ManyJob::Open { index });
+            }
+            if state.opening == 0 {
+                return None;
+            }
+            state = self.opened.wait(state).unwrap();
+        }
+    }
+
+    /// Finish opening a file, with its blocks to be handed out if it's to be hashed that way.
+    fn opened(&self, file: Option<CurrentFile>) {
+        let mut state = self.state.lock().unwrap();
+        state.opening -= 1;
+        state.files.extend(file);
+        self.opened.notify_all();
+    }
+}
+
+/// Hashes blocks for [`content_hashes_from_paths`], keeping track of the progress of all of the
+/// files.
+struct ManyHasher<'a> {
+    tracker: Tracker<'a>,
+}
+
+impl ManyHasher<'_> {
+    /// Read a block of a file at its offset, and hash it.
+    fn hash_block(
+        &self,
+        file: &File,
+        buf: &mut [u8],
+        block: u64,
+        len: usize,
+    ) -> Result<Digest, Error> {
+        let offset = block * BLOCK_SIZE as u64;
+        read_block_at(file, buf, len, offset)?;
+        self.tracker.read(len);
+        let hash = digest(&SHA256, &buf[.. len]);
+        trace!(offset, len, "hashed a block");
+        self.tracker.hashed(len);
+        Ok(hash)
+    }
+
+    /// Hash a file by reading it from start to end.
+    fn hash_stream(
+        &self,
+        mut file: &File,
+        buf: &mut [u8],
+    ) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+        let mut overall_hash = Context::new(&SHA256);
+        loop {
+            if self.tracker.cancelled() {
+                return Err(Error::Cancelled);
+            }
+            let len = read_full(&mut file, &mut buf[.. BLOCK_SIZE])?;
+            if len == 0 {
+                break;
+            }
+            self.tracker.read(len);
+            overall_hash.update(digest(&SHA256, &buf[.. len]).as_ref());
+            self.tracker.hashed(len);
+            if len < BLOCK_SIZE {
+                break;
+            }
+        }
+        Ok(overall_hash.finish().as_ref().try_into().expect("hash output is of wrong size"))
+    }
+}
+
+/// Adds up the bytes read and hashed by the threads of a hash which doesn't go through the
+/// [`Pipeline`], and reports them to the progress function and metrics in the options.
+struct Tracker<'a> {
+    options: &'a Options,
+    bytes_read: AtomicU64,
+    bytes_hashed: AtomicU64,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(options: &'a Options) -> Self {
+        Self { options, bytes_read: AtomicU64::new(0), bytes_hashed: AtomicU64::new(0) }
+    }
+
+    /// Whether the options' cancel token has been cancelled.
+    fn cancelled(&self) -> bool {
+        self.options.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    /// Some bytes were read.
+    fn read(&self, len: usize) {
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(metrics) = &self.options.metrics {
+            metrics.bytes_read(len as u64);
+        }
+    }
+
+    /// A block of the given length was hashed.
+    fn hashed(&self, len: usize) {
+        let bytes_hashed = self.bytes_hashed.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+        if let Some(metrics) = &self.options.metrics {
+            metrics.block_hashed(len);
+        }
+        if let Some(f) = &self.options.progress_fn {
+            f(Progress { bytes_read: self.bytes_read.load(Ordering::Relaxed), bytes_hashed });
+        }
+    }
+}
+
+/// The block hashes of one file for [`content_hashes_from_paths`], and then its content hash.
+#[derive(Default)]
+struct ManyResult {
+    blocks: Vec<Option<Digest>>,
+    remaining: u64,
+    error: Option<Error>,
+    result: Option<Result<[u8; HASH_OUTPUT_SIZE], Error>>,
+}
+
+impl ManyResult {
+    fn start(&mut self, num_blocks: u64) {
+        self.blocks = vec![None; num_blocks as usize];
+        self.remaining = num_blocks;
+    }
+
+    /// Record a block's hash, or why it couldn't be hashed, and once all the blocks are in, the
+    /// result.
+    fn add_block(&mut self, block: u64, hash: Result<Digest, Error>) {
+        match hash {
+            Ok(hash) => self.blocks[block as usize] = Some(hash),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.result = Some(match self.error.take() {
+                Some(e) => Err(e),
+                None => {
+                    let mut overall_hash = Context::new(&SHA256);
+                    for hash in self.blocks.drain(..) {
+                        overall_hash.update(hash.expect("all blocks are hashed").as_ref());
+                    }
+                    Ok(overall_hash.finish().as_ref().try_into()
+                        .expect("hash output is of wrong size"))
+                }
+            });
+        }
+    }
+}
+
+/// Read `len` bytes from the file at the given offset into the start of the buffer, without using
+/// or moving the file cursor.
+///
+/// The read size is rounded up to the alignment required by files opened with [`direct::open`],
+/// so the buffer must be big enough to hold that; any excess is read only if the read extends
+/// past `len` bytes before the end of the file.
+fn read_block_at(file: &File, buf: &mut [u8], len: usize, offset: u64) -> io::Result<()> {
+    let buf = &mut buf[.. direct::align_up(len)];
+    let mut filled = 0;
+    while filled < len {
+        let pos = offset + filled as u64;
+        match read_at(file, &mut buf[filled ..], pos) {
+            Ok(0) => return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
-                format!("incomplete block mid-stream at offset {:#x}", bad_offset)));
+                format!("file ended unexpectedly at offset {:#x}", pos))),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
+}
+
+/// Positioned reads aren't stable on WASI.
+#[cfg(not(any(unix, windows)))]
+fn read_at(_file: &File, _buf: &mut [u8], _offset: u64) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+        "positioned reads are not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentHasher;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// The file backends in this build.
+    fn test_backends() -> Vec<FileBackend> {
+        vec![
+            FileBackend::Pread,
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            FileBackend::Uring { queue_depth: 2 },
+            #[cfg(feature = "mmap")]
+            FileBackend::Mmap,
+            #[cfg(windows)]
+            FileBackend::Overlapped { queue_depth: 2 },
+        ]
+    }
+
+    #[test]
+    fn stream_matches_serial() {
+        let pool = Arc::new(BufferPool::new(4));
+        for &len in &[0, 5, BLOCK_SIZE, 2 * BLOCK_SIZE + 1, 5 * BLOCK_SIZE - 1] {
+            let data = (0 .. len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+            for &threads in &[1, 2, 3, 8] {
+                assert_eq!(expected, content_hash_from_stream(&data[..], threads).unwrap(),
+                    "len={} threads={}", len, threads);
+                let options = Options::new(threads).queue_depth(1);
+                assert_eq!(expected,
+                    content_hash_from_stream_with_options(&data[..], &options).unwrap(),
+                    "len={} threads={} queue_depth=1", len, threads);
+                let options = Options::new(threads).buffer_pool(Arc::clone(&pool));
+                assert_eq!(expected,
+                    content_hash_from_stream_with_options(&data[..], &options).unwrap(),
+                    "len={} threads={} shared pool", len, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn auto_threads() {
+        assert!(available_threads() >= 1);
+        assert_eq!(Options::auto(None).workers.num_threads(), available_threads());
+        assert_eq!(Options::auto(Some(1)).workers.num_threads(), 1);
+        let data = vec![1u8; BLOCK_SIZE + 1];
+        assert_eq!(ContentHasher::from_stream(&data[..]).unwrap().finish(),
+            content_hash_from_stream_with_options(&data[..], &Options::auto(Some(4))).unwrap());
+    }
+
+    /// A reader that returns at most 1000 bytes per read, like a pipe might.
+    struct ShortReads<'a>(&'a [u8]);
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[.. n].copy_from_slice(&self.0[.. n]);
+            self.0 = &self.0[n ..];
+            Ok(n)
+        }
+    }
+
+    /// Returns reads of the given sizes, over and over.
+    struct SizedReads<'a> {
+        data: &'a [u8],
+        sizes: std::iter::Cycle<std::slice::Iter<'a, usize>>,
+    }
+
+    impl Read for SizedReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.data.len()).min(*self.sizes.next().unwrap());
+            buf[.. n].copy_from_slice(&self.data[.. n]);
+            self.data = &self.data[n ..];
+            Ok(n)
+        }
+    }
+
+    /// Stream lengths up to a few blocks, with exact multiples of the block size and one byte
+    /// either side of them as likely as any other length.
+    fn stream_len() -> impl Strategy<Value = usize> {
+        prop_oneof![
+            0 ..= 3 * BLOCK_SIZE + 1,
+            (0 ..= 3usize, 0 ..= 2usize)
+                .prop_map(|(blocks, offset)| (blocks * BLOCK_SIZE + offset).saturating_sub(1)),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn serial_matches_parallel(
+            len in stream_len(),
+            read_sizes in vec(prop_oneof![1 .. 1000usize, 1 .. 2 * BLOCK_SIZE], 1 .. 8),
+            threads in 1 ..= 4usize,
+            seed: u8,
+        ) {
+            let data = (0 .. len).map(|i| (i % 251) as u8 ^ seed).collect::<Vec<u8>>();
+            let reads = || SizedReads { data: &data, sizes: read_sizes.iter().cycle() };
+            let mut ctx = ContentHasher::new();
+            ctx.read_stream(reads()).unwrap();
+            let expected = ctx.finish();
+
+            let options = Options::new(threads);
+            prop_assert_eq!(expected,
+                content_hash_from_stream_with_options(reads(), &options).unwrap());
+
+            let mut ctx = ParallelContentHasher::with_options(&options);
+            let mut rest = &data[..];
+            for &size in read_sizes.iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, after) = rest.split_at(size.min(rest.len()));
+                ctx.update(chunk);
+                rest = after;
+            }
+            prop_assert_eq!(expected, ctx.finish().unwrap());
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn rayon() {
+        let data = (0 .. 5 * BLOCK_SIZE + 1).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        let global = Options::new(1).rayon_pool(None);
+        assert_eq!(expected, content_hash_from_stream_with_options(&data[..], &global).unwrap());
+
+        // Hashing from inside a pool with only one thread must not deadlock.
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let options = Options::new(1).rayon_pool(Some(Arc::clone(&pool)));
+        let hash = pool.install(|| content_hash_from_stream_with_options(&data[..], &options));
+        assert_eq!(expected, hash.unwrap());
+    }
+
+    /// Runs each task on a new thread.
+    struct ThreadPerTask;
+
+    impl Spawn for ThreadPerTask {
+        fn spawn(&self, task: Box<dyn FnOnce() + Send + 'static>) {
+            thread::spawn(task);
+        }
+
+        fn num_threads(&self) -> usize {
+            2
+        }
+    }
+
+    #[test]
+    fn spawner() {
+        let data = (0 .. 5 * BLOCK_SIZE + 1).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        let options = Options::new(1).spawner(Arc::new(ThreadPerTask));
+        assert_eq!(expected, content_hash_from_stream_with_options(&data[..], &options).unwrap());
+    }
+
+    #[test]
+    fn stream_short_reads() {
+        let data = (0 .. 2 * BLOCK_SIZE + 1).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        assert_eq!(expected, content_hash_from_stream(ShortReads(&data), 3).unwrap());
+    }
+
+    #[test]
+    fn incremental_matches_serial() {
+        let data = (0 .. 3 * BLOCK_SIZE + 7).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+        for &chunk_size in &[1000, BLOCK_SIZE / 3, BLOCK_SIZE, BLOCK_SIZE + 1, data.len()] {
+            let mut ctx = ParallelContentHasher::new(3);
+            for chunk in data.chunks(chunk_size) {
+                ctx.update(chunk);
+            }
+            assert_eq!(expected, ctx.finish().unwrap(), "chunk_size={}", chunk_size);
+        }
+        assert_eq!(ContentHasher::new().finish(), ParallelContentHasher::new(2).finish().unwrap());
+    }
+
+    #[test]
+    fn block_hashes_in_order() {
+        let data = (0 .. 5 * BLOCK_SIZE + 7).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let expected = data.chunks(BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, block)| (i as u64, digest(&SHA256, block).as_ref().to_vec()))
+            .collect::<Vec<_>>();
+        let block_hashes = Arc::new(Mutex::new(vec![]));
+        let f_block_hashes = Arc::clone(&block_hashes);
+        let options = Options::new(4).block_hashes_fn(Arc::new(move |block_num, hash| {
+            f_block_hashes.lock().unwrap().push((block_num, hash.to_vec()));
+        }));
+        content_hash_from_stream_with_options(&data[..], &options).unwrap();
+        assert_eq!(expected, *block_hashes.lock().unwrap());
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+        for backend in test_backends() {
+            block_hashes.lock().unwrap().clear();
+            let options = options.clone().file_backend(backend);
+            content_hash_from_file_with_options(&file, &options).unwrap();
+            assert_eq!(expected, *block_hashes.lock().unwrap(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let data = vec![30u8; 8 * BLOCK_SIZE];
+        let token = CancelToken::new();
+        let f_token = token.clone();
+        let options = Options::new(2)
+            .cancel_token(token)
+            .progress_fn(Arc::new(move |_| f_token.cancel()));
+        let err = content_hash_from_stream_with_options(&data[..], &options).unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+
+        // The file backends check it too.
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+        for backend in test_backends() {
+            let token = CancelToken::new();
+            token.cancel();
+            let options = Options::new(2).file_backend(backend).cancel_token(token);
+            let err = content_hash_from_file_with_options(&file, &options).unwrap_err();
+            assert!(matches!(err, Error::Cancelled), "{:?}: {:?}", backend, err);
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        let data = vec![30u8; 3 * BLOCK_SIZE + 7];
+        let counters = Arc::new(crate::metrics::Counters::new());
+        let options = Options::new(2).metrics(counters.clone());
+        content_hash_from_stream_with_options(&data[..], &options).unwrap();
+        assert_eq!(data.len() as u64, counters.total_bytes_read());
+        assert_eq!(4, counters.total_blocks_hashed());
+        assert_eq!(data.len() as u64, counters.total_bytes_hashed());
+        assert_eq!(1, counters.hashes_finished());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics");
+        std::fs::write(&path, &data).unwrap();
+        for backend in test_backends() {
+            let counters = Arc::new(crate::metrics::Counters::new());
+            let options = Options::new(2).metrics(counters.clone()).file_backend(backend);
+            let file = File::open(&path).unwrap();
+            content_hash_from_file_with_options(&file, &options).unwrap();
+            assert_eq!(data.len() as u64, counters.total_bytes_read(), "{:?}", backend);
+            assert_eq!(4, counters.total_blocks_hashed(), "{:?}", backend);
+            assert_eq!(data.len() as u64, counters.total_bytes_hashed(), "{:?}", backend);
+            assert_eq!(1, counters.hashes_finished(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn callback_panic() {
+        let data = vec![30u8; 4 * BLOCK_SIZE];
+        let options = Options::new(2)
+            .queue_depth(1)
+            .block_hashes_fn(Arc::new(|_, _| panic!("oh no")));
+        let err = content_hash_from_stream_with_options(&data[..], &options).unwrap_err();
+        assert!(matches!(err, Error::WorkerPanicked), "{:?}", err);
+    }
+
+    #[test]
+    fn progress() {
+        let data = vec![30u8; 3 * BLOCK_SIZE + 7];
+        let progress = Arc::new(Mutex::new(vec![]));
+        let f_progress = Arc::clone(&progress);
+        let options = Options::new(2).progress_fn(Arc::new(move |p| {
+            f_progress.lock().unwrap().push(p);
+        }));
+        content_hash_from_stream_with_options(&data[..], &options).unwrap();
+        let reported = progress.lock().unwrap().clone();
+        assert_eq!(4, reported.len());
+        for pair in reported.windows(2) {
+            assert!(pair[0].bytes_hashed < pair[1].bytes_hashed);
+            assert!(pair[1].bytes_read >= pair[1].bytes_hashed);
+        }
+        assert_eq!(Progress { bytes_read: data.len() as u64, bytes_hashed: data.len() as u64 },
+            reported[3]);
+
+        // The file backends report it too.
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("progress");
+        fs::write(&path, &data).unwrap();
+        let file = File::open(&path).unwrap();
+        for backend in test_backends() {
+            progress.lock().unwrap().clear();
+            content_hash_from_file_with_options(&file, &options.clone().file_backend(backend))
+                .unwrap();
+            let mut reported = progress.lock().unwrap().clone();
+            reported.sort_unstable_by_key(|p| p.bytes_hashed);
+            assert_eq!(4, reported.len(), "{:?}", backend);
+            assert!(reported.iter().all(|p| p.bytes_read >= p.bytes_hashed), "{:?}", backend);
+            assert_eq!(data.len() as u64, reported[3].bytes_hashed, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn file_matches_serial() {
+        let dir = TempDir::new().unwrap();
+        for &len in &[0, 5, BLOCK_SIZE, 2 * BLOCK_SIZE + 1, 5 * BLOCK_SIZE - 1] {
+            let data = (0 .. len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let path = dir.path().join(format!("pread-{}", len));
+            fs::write(&path, &data).unwrap();
+            let file = File::open(&path).unwrap();
+            let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+            for &threads in &[1, 2, 3, 8] {
+                assert_eq!(expected, content_hash_from_file(&file, threads).unwrap(),
+                    "len={} threads={}", len, threads);
+                #[cfg(all(target_os = "linux", feature = "uring"))]
+                for &queue_depth in &[1, 4] {
+                    let backend = FileBackend::Uring { queue_depth };
+                    assert_eq!(expected,
+                        content_hash_from_file_with_backend(&file, threads, backend).unwrap(),
+                        "len={} threads={} uring queue_depth={}", len, threads, queue_depth);
+                }
+                #[cfg(windows)]
+                for &queue_depth in &[1, 4] {
+                    let backend = FileBackend::Overlapped { queue_depth };
+                    assert_eq!(expected,
+                        content_hash_from_file_with_backend(&file, threads, backend).unwrap(),
+                        "len={} threads={} overlapped queue_depth={}", len, threads,
+                        queue_depth);
+                }
+                #[cfg(feature = "mmap")]
+                assert_eq!(expected,
+                    content_hash_from_file_with_backend(&file, threads, FileBackend::Mmap).unwrap(),
+                    "len={} threads={} mmap", len, threads);
+            }
+            drop(file);
 
-    // No other thread can have a copy of the Arc now, so extract the State out of the Arc and
-    // Mutex so we can call finish().
-    let state = Arc::try_unwrap(state)
-        .map_err(|_| "unable to unpack state").unwrap()  // can't expect() because it's not Debug.
-        .into_inner().unwrap();
+            #[cfg(target_os = "linux")]
+            {
+                // tmpfs doesn't support O_DIRECT, so only check this where it works.
+                if let Ok(file) = direct::open(&path) {
+                    assert_eq!(expected, content_hash_from_file(&file, 2).unwrap(),
+                        "len={} direct", len);
+                    let reader = direct::DirectReader::new(file);
+                    assert_eq!(expected, ContentHasher::from_stream(reader).unwrap().finish(),
+                        "len={} direct serial", len);
+                }
+            }
+        }
+    }
 
-    let digest = state.finish();
+    #[test]
+    fn path_matches_serial() {
+        let dir = TempDir::new().unwrap();
+        for &len in &[0, 5, BLOCK_SIZE, 2 * BLOCK_SIZE + 1] {
+            let data = (0 .. len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let path = dir.path().join(format!("path-{}", len));
+            fs::write(&path, &data).unwrap();
+            let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+            for &threads in &[1, 3] {
+                let options = Options::new(threads).queue_depth(1);
+                assert_eq!(expected, content_hash_from_path(&path, &options).unwrap(),
+                    "len={} threads={}", len, threads);
+            }
+            let mut file = File::open(&path).unwrap();
+            io::copy(&mut (&mut file).take(3), &mut io::sink()).unwrap();
+            assert_eq!(expected,
+                content_hash_from_file_with_options(&file, &Options::new(2)).unwrap(),
+                "len={} after reading some", len);
+        }
+    }
 
-    Ok(digest.as_ref().try_into().expect("hash output is of wrong size"))
+    #[test]
+    fn unknown_len() {
+        let mut files = vec![];
+        // A pipe has no length.
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+            use std::os::fd::OwnedFd;
+            let data = vec![30u8; 2 * BLOCK_SIZE + 1];
+            let expected = ContentHasher::from_stream(&data[..]).unwrap().finish();
+            let (reader, mut writer) = io::pipe().unwrap();
+            thread::spawn(move || writer.write_all(&data).unwrap());
+            files.push((File::from(OwnedFd::from(reader)), expected));
+        }
+        // This says it's empty, but it isn't.
+        #[cfg(target_os = "linux")]
+        {
+            let path = "/proc/version";
+            let expected = ContentHasher::from_stream(File::open(path).unwrap()).unwrap().finish();
+            assert_eq!(expected, content_hash_from_path(path, &Options::new(2)).unwrap());
+            files.push((File::open(path).unwrap(), expected));
+        }
+        for (file, expected) in files {
+            assert_eq!(expected,
+                content_hash_from_file_with_backend(&file, 2, FileBackend::Pread).unwrap());
+        }
+    }
+
+    #[test]
+    fn many_paths() {
+        let lens = [0, 5, 3 * BLOCK_SIZE + 1, BLOCK_SIZE, 7, 2 * BLOCK_SIZE];
+        let dir = TempDir::new().unwrap();
+        let mut paths = vec![];
+        let mut expected = vec![];
+        for (i, &len) in lens.iter().enumerate() {
+            let data = (0 .. len).map(|j| (i + j) as u8).collect::<Vec<u8>>();
+            let path = dir.path().join(format!("many-{}", i));
+            fs::write(&path, &data).unwrap();
+            paths.push(path);
+            expected.push(ContentHasher::from_stream(&data[..]).unwrap().finish());
+        }
+        paths.insert(2, PathBuf::from("/nonexistent"));
+        for &threads in &[1, 3, 16] {
+            let mut results = content_hashes_from_paths(&paths, &Options::new(threads));
+            assert!(matches!(results.remove(2), Err(Error::Read(e))
+                if e.kind() == io::ErrorKind::NotFound));
+            let results = results.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+            assert_eq!(expected, results, "threads={}", threads);
+        }
+        #[cfg(unix)]
+        assert_eq!(crate::EMPTY_CONTENT_HASH,
+            *content_hashes_from_paths(&["/dev/null"], &Options::new(2))[0].as_ref().unwrap());
+        // This says it's empty, but it isn't.
+        #[cfg(target_os = "linux")]
+        {
+            let path = "/proc/version";
+            let expected = ContentHasher::from_stream(File::open(path).unwrap()).unwrap().finish();
+            let results = content_hashes_from_paths(&[path], &Options::new(2));
+            assert_eq!(expected, *results[0].as_ref().unwrap());
+        }
+    }
+
+    #[test]
+    fn many_paths_options() {
+        let dir = TempDir::new().unwrap();
+        let paths = (0 .. 3)
+            .map(|i| {
+                let path = dir.path().join(format!("many-options-{}", i));
+                fs::write(&path, vec![i; 3 * BLOCK_SIZE + 1]).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+
+        let counters = Arc::new(crate::metrics::Counters::new());
+        let progress = Arc::new(Mutex::new(vec![]));
+        let f_progress = Arc::clone(&progress);
+        let options = Options::new(2)
+            .metrics(counters.clone())
+            .progress_fn(Arc::new(move |p| f_progress.lock().unwrap().push(p.bytes_hashed)));
+        assert!(content_hashes_from_paths(&paths, &options).iter().all(Result::is_ok));
+        let total = 3 * (3 * BLOCK_SIZE as u64 + 1);
+        assert_eq!(total, counters.total_bytes_read());
+        assert_eq!(12, counters.total_blocks_hashed());
+        assert_eq!(1, counters.hashes_finished());
+        let mut progress = progress.lock().unwrap().clone();
+        progress.sort_unstable();
+        assert_eq!(12, progress.len());
+        assert_eq!(total, progress[11]);
+
+        let token = CancelToken::new();
+        let f_token = token.clone();
+        let options = Options::new(1)
+            .cancel_token(token)
+            .progress_fn(Arc::new(move |_| f_token.cancel()));
+        for result in content_hashes_from_paths(&paths, &options) {
+            assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+        }
+
+        let options = Options::new(2).progress_fn(Arc::new(|_| panic!("oh no")));
+        for result in content_hashes_from_paths(&paths, &options) {
+            assert!(matches!(result, Err(Error::WorkerPanicked)), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn known_len() {
+        let hash = digest(&SHA256, b"");
+        let b = BLOCK_SIZE as u64;
+        // A short block in the middle is only found out once a block after it turns up...
+        let mut state = State::new(None, None);
+        assert_eq!(Ok(()), state.add_block(hash, b, 1));
+        assert_eq!(Err(b), state.add_block(hash, 2 * b, 1));
+        // ...unless the length is known.
+        let mut state = State::new(None, Some(2 * b + 1));
+        assert_eq!(Err(b), state.add_block(hash, b, 1));
+        assert_eq!(Err(2 * b), state.add_block(hash, 2 * b, 2));
+        assert_eq!(Ok(()), state.add_block(hash, 2 * b, 1));
+        assert_eq!(Ok(()), state.add_block(hash, 0, BLOCK_SIZE));
+    }
 }
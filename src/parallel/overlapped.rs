@@ -0,0 +1,391 @@
+//! Read a file using overlapped I/O on Windows, bypassing the file cache and keeping many block
+//! reads in flight at once, while worker threads hash the blocks as they arrive.
+
+use super::{Error, Tracker};
+use crate::{num_blocks, BLOCK_SIZE};
+use crate::trace::debug;
+use crate::direct::{self, AlignedBuffer};
+use crate::sha256::{digest, Digest, SHA256};
+use std::cell::UnsafeCell;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use windows_sys::Win32::Foundation::{ERROR_HANDLE_EOF, ERROR_IO_PENDING, GENERIC_READ, HANDLE,
+    INVALID_HANDLE_VALUE};
+use windows_sys::Win32::Storage::FileSystem::{ReOpenFile, ReadFile, FILE_FLAG_NO_BUFFERING,
+    FILE_FLAG_OVERLAPPED, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+use windows_sys::Win32::System::IO::{CancelIoEx, CreateIoCompletionPort,
+    GetQueuedCompletionStatus, OVERLAPPED, OVERLAPPED_0, OVERLAPPED_0_0};
+use windows_sys::Win32::System::Threading::INFINITE;
+
+/// A block buffer, and the state of the read being done into it.
+struct Slot {
+    buf: AlignedBuffer,
+    block: u64,
+    filled: usize,
+    wanted: usize,
+}
+
+/// A read that finished, identified by the `OVERLAPPED` it was started with.
+struct Completion {
+    overlapped: *mut OVERLAPPED,
+    result: io::Result<usize>,
+}
+
+/// Hash every block of the file, returning the block hashes in order. The cancel token is
+/// checked before starting to read each block, and the tracker is told as each block is read and
+/// hashed.
+///
+/// The file is opened again for overlapped reads without the file cache, so it doesn't need to
+/// have been opened with [`direct::open`].
+pub fn block_hashes(
+    file: &File,
+    len: u64,
+    num_threads: usize,
+    queue_depth: usize,
+    tracker: &Tracker<'_>,
+) -> Result<Vec<Digest>, Error> {
+    block_hashes_with(file, len, num_threads, queue_depth, tracker, wait_for_read)
+}
+
+/// [`block_hashes`], with a function to wait for a read to complete, so tests can make it fail.
+fn block_hashes_with(
+    file: &File,
+    len: u64,
+    num_threads: usize,
+    queue_depth: usize,
+    tracker: &Tracker<'_>,
+    mut wait: impl FnMut(&OwnedHandle) -> io::Result<Completion>,
+) -> Result<Vec<Digest>, Error> {
+    let num_blocks = num_blocks(len);
+    let queue_depth = queue_depth.max(1);
+    let file = reopen(file)?;
+    let sector_size = direct::sector_size(&file)?;
+    if !sector_size.is_power_of_two() || sector_size > BLOCK_SIZE {
+        return Err(Error::Read(io::Error::other(
+            format!("unsupported sector size of {} bytes", sector_size))));
+    }
+    let port = completion_port(&file)?;
+    let handle = file.as_raw_handle();
+    // These are only written to while their reads aren't in flight, and Windows writes to them
+    // while they are, so they're only ever touched through raw pointers.
+    let requests = (0 .. queue_depth)
+        .map(|_| UnsafeCell::new(overlapped_at(0)))
+        .collect::<Box<[_]>>();
+
+    let (work_tx, work_rx) = mpsc::channel::<Slot>();
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    // Block hashes are None if the worker panicked while computing it.
+    let (done_tx, done_rx) = mpsc::channel::<(Slot, Option<Digest>)>();
+
+    thread::scope(|scope| {
+        for _ in 0 .. num_threads.max(1) {
+            let work_rx = Arc::clone(&work_rx);
+            let done_tx = done_tx.clone();
+            scope.spawn(move || loop {
+                let slot = match work_rx.lock().unwrap().recv() {
+                    Ok(slot) => slot,
+                    Err(_) => break, // reader is done
+                };
+                let hash = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let hash = digest(&SHA256, &slot.buf[.. slot.wanted]);
+                    tracker.hashed(slot.wanted);
+                    hash
+                })).ok();
+                if done_tx.send((slot, hash)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut slots = (0 .. queue_depth)
+            .map(|_| Some(Slot {
+                buf: AlignedBuffer::with_alignment(BLOCK_SIZE, sector_size),
+                block: 0,
+                filled: 0,
+                wanted: 0,
+            }))
+            .collect::<Vec<_>>();
+        let mut hashes: Vec<Option<Digest>> = vec![None; num_blocks as usize];
+        let mut next_block = 0;
+        let mut in_flight = 0;
+        let mut hashed = 0;
+        let mut result = Ok(());
+
+        while hashed < num_blocks {
+            // Put every free buffer to work reading the next block.
+            for (index, slot) in slots.iter_mut().enumerate() {
+                if result.is_err() || next_block == num_blocks {
+                    break;
+                }
+                if tracker.cancelled() {
+                    debug!("cancelled");
+                    // The reads in flight are still waited for, below.
+                    result = Err(Error::Cancelled);
+                    break;
+                }
+                if let Some(slot) = slot.as_mut().filter(|slot| slot.wanted == 0) {
+                    let offset = next_block * BLOCK_SIZE as u64;
+                    slot.block = next_block;
+                    slot.filled = 0;
+                    slot.wanted = (len - offset).min(BLOCK_SIZE as u64) as usize;
+                    next_block += 1;
+                    match submit_read(handle, requests[index].get(), slot, sector_size) {
+                        Ok(()) => in_flight += 1,
+                        Err(e) => result = Err(e),
+                    }
+                }
+            }
+
+            if in_flight > 0 {
+                let completion = match wait(&port) {
+                    Ok(completion) => completion,
+                    Err(e) => {
+                        // There's no way to know when Windows is done with the buffers now, so
+                        // the reads are cancelled, and the buffers have to be leaked.
+                        // SAFETY: the handle is open.
+                        unsafe { CancelIoEx(handle, ptr::null()) };
+                        mem::forget(slots);
+                        mem::forget(requests);
+                        // Let the hashing threads finish, or the scope would wait for them
+                        // forever.
+                        drop(work_tx);
+                        drop(done_rx);
+                        return Err(Error::Read(e));
+                    }
+                };
+                in_flight -= 1;
+                let index = requests.iter()
+                    .position(|request| request.get() == completion.overlapped)
+                    .expect("completion of a read that wasn't started");
+                let slot = slots[index].as_mut().expect("slot with a read in flight is empty");
+                match completion.result {
+                    // Keep going until every read in flight is finished, because Windows is still
+                    // writing into those buffers.
+                    _ if result.is_err() => (),
+                    Ok(0) => result = Err(ended_early(slot)),
+                    Err(e) if e.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) =>
+                        result = Err(ended_early(slot)),
+                    Err(e) => result = Err(Error::Read(e)),
+                    Ok(n) => {
+                        slot.filled += n;
+                        if slot.filled >= slot.wanted {
+                            tracker.read(slot.wanted);
+                            let slot = slots[index].take().unwrap();
+                            work_tx.send(slot).expect("hashing threads exited early");
+                        } else if !slot.filled.is_multiple_of(sector_size) {
+                            // Only the read at the end of the file can come up short of a whole
+                            // sector, so it must have been truncated.
+                            result = Err(ended_early(slot));
+                        } else {
+                            // Short read; go back for the rest.
+                            match submit_read(handle, requests[index].get(), slot, sector_size) {
+                                Ok(()) => in_flight += 1,
+                                Err(e) => result = Err(e),
+                            }
+                        }
+                    }
+                }
+            }
+
+            if result.is_err() {
+                if in_flight == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            // Collect finished hashes and return their buffers to the pool. If there are no
+            // reads in flight, the only thing left to do is to wait for them.
+            let mut wait = in_flight == 0;
+            loop {
+                let (mut slot, hash) = if wait {
+                    match done_rx.recv() {
+                        Ok(done) => done,
+                        Err(_) => break,
+                    }
+                } else {
+                    match done_rx.try_recv() {
+                        Ok(done) => done,
+                        Err(_) => break,
+                    }
+                };
+                wait = false;
+                match hash {
+                    Some(hash) => hashes[slot.block as usize] = Some(hash),
+                    None if result.is_ok() => result = Err(Error::WorkerPanicked),
+                    None => (),
+                }
+                hashed += 1;
+                let index = slots.iter().position(Option::is_none).unwrap();
+                slot.wanted = 0;
+                slots[index] = Some(slot);
+            }
+        }
+        drop(work_tx);
+
+        result.map(|()| hashes.into_iter()
+            .map(|hash| hash.expect("missing block hash"))
+            .collect())
+    })
+}
+
+/// Open the file again, for overlapped reads which bypass the file cache.
+fn reopen(file: &File) -> io::Result<File> {
+    // SAFETY: the handle is valid for as long as the file is.
+    let handle = unsafe {
+        ReOpenFile(file.as_raw_handle(), GENERIC_READ,
+            FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            FILE_FLAG_OVERLAPPED | FILE_FLAG_NO_BUFFERING)
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the handle was just opened, and nothing else owns it.
+    Ok(unsafe { File::from_raw_handle(handle) })
+}
+
+/// Create an I/O completion port which the file's reads complete on.
+fn completion_port(file: &File) -> io::Result<OwnedHandle> {
+    // SAFETY: the handle is valid for as long as the file is, and the port outlives the reads.
+    let port = unsafe { CreateIoCompletionPort(file.as_raw_handle(), ptr::null_mut(), 0, 1) };
+    if port.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the port was just created, and nothing else owns it.
+    Ok(unsafe { OwnedHandle::from_raw_handle(port) })
+}
+
+/// An `OVERLAPPED` for a read at the given offset.
+fn overlapped_at(offset: u64) -> OVERLAPPED {
+    OVERLAPPED {
+        Internal: 0,
+        InternalHigh: 0,
+        Anonymous: OVERLAPPED_0 {
+            Anonymous: OVERLAPPED_0_0 {
+                Offset: offset as u32,
+                OffsetHigh: (offset >> 32) as u32,
+            },
+        },
+        hEvent: ptr::null_mut(),
+    }
+}
+
+/// Start a read of the remainder of the slot's block, which completes on the file's completion
+/// port, with the given `OVERLAPPED`.
+///
+/// The read size is rounded up to the sector size, as reads which bypass the file cache have to
+/// be.
+fn submit_read(
+    handle: HANDLE,
+    overlapped: *mut OVERLAPPED,
+    slot: &mut Slot,
+    sector_size: usize,
+) -> Result<(), Error> {
+    let offset = slot.block * BLOCK_SIZE as u64 + slot.filled as u64;
+    let buf = &mut slot.buf[slot.filled .. slot.wanted.div_ceil(sector_size) * sector_size];
+    // SAFETY: the OVERLAPPED and the buffer stay alive and untouched until the read completes, as
+    // slots with reads in flight are never moved out of the slot list or dropped before being
+    // waited on, and neither are the OVERLAPPEDs.
+    let ok = unsafe {
+        overlapped.write(overlapped_at(offset));
+        ReadFile(handle, buf.as_mut_ptr(), buf.len() as u32, ptr::null_mut(), overlapped)
+    };
+    if ok != 0 {
+        // It finished already, but it still completes on the port.
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(code) if code == ERROR_IO_PENDING as i32 => Ok(()),
+        Some(code) if code == ERROR_HANDLE_EOF as i32 => Err(ended_early(slot)),
+        _ => Err(Error::Read(e)),
+    }
+}
+
+/// Wait for a read started on the completion port to finish.
+fn wait_for_read(port: &OwnedHandle) -> io::Result<Completion> {
+    let mut bytes = 0;
+    let mut key = 0;
+    let mut overlapped = ptr::null_mut();
+    // SAFETY: the port is open, and the pointers are to locals of the right types.
+    let ok = unsafe {
+        GetQueuedCompletionStatus(port.as_raw_handle(), &mut bytes, &mut key, &mut overlapped,
+            INFINITE)
+    };
+    if ok != 0 {
+        Ok(Completion { overlapped, result: Ok(bytes as usize) })
+    } else if !overlapped.is_null() {
+        // The read failed, not the wait.
+        Ok(Completion { overlapped, result: Err(io::Error::last_os_error()) })
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// The error for a file which ended before the slot's block was read.
+fn ended_early(slot: &Slot) -> Error {
+    Error::Read(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("file ended unexpectedly at offset {:#x}",
+            slot.block * BLOCK_SIZE as u64 + slot.filled as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel::Options;
+    use crate::CancelToken;
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// A file of the given number of blocks, in a directory that's deleted when it's dropped.
+    fn test_file(blocks: usize) -> (TempDir, File) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks");
+        fs::write(&path, vec![30u8; blocks * BLOCK_SIZE]).unwrap();
+        let file = File::open(&path).unwrap();
+        (dir, file)
+    }
+
+    #[test]
+    fn wait_fails() {
+        let (_dir, file) = test_file(3);
+        let options = Options::new(2);
+        let tracker = Tracker::new(&options);
+        let err = block_hashes_with(&file, 3 * BLOCK_SIZE as u64, 2, 2, &tracker, |_| {
+            Err(io::Error::from_raw_os_error(6)) // ERROR_INVALID_HANDLE
+        }).unwrap_err();
+        assert!(matches!(&err, Error::Read(e) if e.raw_os_error() == Some(6)), "{:?}", err);
+    }
+
+    #[test]
+    fn cancelled_midway() {
+        let (_dir, file) = test_file(5);
+        let token = CancelToken::new();
+        let options = Options::new(2).cancel_token(token.clone());
+        let tracker = Tracker::new(&options);
+        // The first reads are already started when it's cancelled.
+        let err = block_hashes_with(&file, 5 * BLOCK_SIZE as u64, 2, 2, &tracker, |port| {
+            token.cancel();
+            wait_for_read(port)
+        }).unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+    }
+
+    #[test]
+    fn truncated() {
+        let (_dir, file) = test_file(2);
+        let options = Options::new(2);
+        let tracker = Tracker::new(&options);
+        let err = block_hashes(&file, 3 * BLOCK_SIZE as u64, 2, 4, &tracker).unwrap_err();
+        assert!(matches!(&err, Error::Read(e) if e.kind() == io::ErrorKind::UnexpectedEof),
+            "{:?}", err);
+    }
+}
@@ -0,0 +1,262 @@
+//! Read a file using io_uring, keeping many block reads in flight at once, while worker threads
+//! hash the blocks as they arrive.
+
+use super::{Error, Tracker};
+use crate::{num_blocks, BLOCK_SIZE};
+use crate::trace::debug;
+use crate::direct::{self, AlignedBuffer};
+use crate::sha256::{digest, Digest, SHA256};
+use io_uring::{opcode, types, IoUring};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// A block buffer, and the state of the read being done into it.
+struct Slot {
+    buf: AlignedBuffer,
+    block: u64,
+    filled: usize,
+    wanted: usize,
+}
+
+/// Hash every block of the file, returning the block hashes in order. The cancel token is
+/// checked before starting to read each block, and the tracker is told as each block is read and
+/// hashed.
+pub fn block_hashes(
+    file: &File,
+    len: u64,
+    num_threads: usize,
+    queue_depth: usize,
+    tracker: &Tracker<'_>,
+) -> Result<Vec<Digest>, Error> {
+    block_hashes_with(file, len, num_threads, queue_depth, tracker, |ring| ring.submit_and_wait(1))
+}
+
+/// [`block_hashes`], with a function to submit the queued reads and wait for at least one to
+/// complete, so tests can make it fail.
+fn block_hashes_with(
+    file: &File,
+    len: u64,
+    num_threads: usize,
+    queue_depth: usize,
+    tracker: &Tracker<'_>,
+    mut submit_and_wait: impl FnMut(&IoUring) -> io::Result<usize>,
+) -> Result<Vec<Digest>, Error> {
+    let num_blocks = num_blocks(len);
+    let queue_depth = queue_depth.max(1);
+    let mut ring = IoUring::new(queue_depth as u32)?;
+    let fd = types::Fd(file.as_raw_fd());
+
+    let (work_tx, work_rx) = mpsc::channel::<Slot>();
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    // Block hashes are None if the worker panicked while computing it.
+    let (done_tx, done_rx) = mpsc::channel::<(Slot, Option<Digest>)>();
+
+    thread::scope(|scope| {
+        for _ in 0 .. num_threads.max(1) {
+            let work_rx = Arc::clone(&work_rx);
+            let done_tx = done_tx.clone();
+            scope.spawn(move || loop {
+                let slot = match work_rx.lock().unwrap().recv() {
+                    Ok(slot) => slot,
+                    Err(_) => break, // reader is done
+                };
+                let hash = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let hash = digest(&SHA256, &slot.buf[.. slot.wanted]);
+                    tracker.hashed(slot.wanted);
+                    hash
+                })).ok();
+                if done_tx.send((slot, hash)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut slots = (0 .. queue_depth)
+            .map(|_| Some(Slot {
+                buf: AlignedBuffer::new(BLOCK_SIZE),
+                block: 0,
+                filled: 0,
+                wanted: 0,
+            }))
+            .collect::<Vec<_>>();
+        let mut hashes: Vec<Option<Digest>> = vec![None; num_blocks as usize];
+        let mut next_block = 0;
+        let mut in_ring = 0;
+        let mut hashed = 0;
+        let mut result = Ok(());
+
+        while hashed < num_blocks {
+            // Put every free buffer to work reading the next block.
+            for (index, slot) in slots.iter_mut().enumerate() {
+                if result.is_err() || next_block == num_blocks {
+                    break;
+                }
+                if tracker.cancelled() {
+                    debug!("cancelled");
+                    // The reads in flight are still waited for, below.
+                    result = Err(Error::Cancelled);
+                    break;
+                }
+                if let Some(slot) = slot.as_mut().filter(|slot| slot.wanted == 0) {
+                    let offset = next_block * BLOCK_SIZE as u64;
+                    slot.block = next_block;
+                    slot.filled = 0;
+                    slot.wanted = (len - offset).min(BLOCK_SIZE as u64) as usize;
+                    submit_read(&mut ring, fd, index, slot);
+                    in_ring += 1;
+                    next_block += 1;
+                }
+            }
+
+            if in_ring > 0 {
+                match submit_and_wait(&ring) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        // There's no way to know when the kernel is done with the buffers now,
+                        // so they have to be leaked.
+                        std::mem::forget(slots);
+                        // Let the hashing threads finish, or the scope would wait for them
+                        // forever.
+                        drop(work_tx);
+                        drop(done_rx);
+                        return Err(Error::Read(e));
+                    }
+                }
+                let completions = ring.completion()
+                    .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+                    .collect::<Vec<_>>();
+                for (index, res) in completions {
+                    in_ring -= 1;
+                    let slot = slots[index].as_mut().expect("slot with a read in flight is empty");
+                    if res < 0 || result.is_err() {
+                        // Keep going until every read in flight is finished, because the kernel
+                        // is still writing into those buffers.
+                        if res < 0 && result.is_ok() {
+                            result = Err(Error::Read(io::Error::from_raw_os_error(-res)));
+                        }
+                        continue;
+                    }
+                    if res == 0 {
+                        if result.is_ok() {
+                            result = Err(Error::Read(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                format!("file ended unexpectedly at offset {:#x}",
+                                    slot.block * BLOCK_SIZE as u64 + slot.filled as u64))));
+                        }
+                        continue;
+                    }
+                    slot.filled += res as usize;
+                    if slot.filled < slot.wanted {
+                        // Short read; go back for the rest.
+                        submit_read(&mut ring, fd, index, slot);
+                        in_ring += 1;
+                    } else {
+                        tracker.read(slot.wanted);
+                        let slot = slots[index].take().unwrap();
+                        work_tx.send(slot).expect("hashing threads exited early");
+                    }
+                }
+            }
+
+            if result.is_err() {
+                if in_ring == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            // Collect finished hashes and return their buffers to the pool. If there are no
+            // reads in flight, the only thing left to do is to wait for them.
+            let mut wait = in_ring == 0;
+            loop {
+                let (mut slot, hash) = if wait {
+                    match done_rx.recv() {
+                        Ok(done) => done,
+                        Err(_) => break,
+                    }
+                } else {
+                    match done_rx.try_recv() {
+                        Ok(done) => done,
+                        Err(_) => break,
+                    }
+                };
+                wait = false;
+                match hash {
+                    Some(hash) => hashes[slot.block as usize] = Some(hash),
+                    None if result.is_ok() => result = Err(Error::WorkerPanicked),
+                    None => (),
+                }
+                hashed += 1;
+                let index = slots.iter().position(Option::is_none).unwrap();
+                slot.wanted = 0;
+                slots[index] = Some(slot);
+            }
+        }
+        drop(work_tx);
+
+        result.map(|()| hashes.into_iter()
+            .map(|hash| hash.expect("missing block hash"))
+            .collect())
+    })
+}
+
+/// Queue up a read of the remainder of the slot's block. It gets submitted on the next call to
+/// `submit_and_wait`.
+///
+/// The read size is rounded up to the alignment required by files opened with [`direct::open`].
+fn submit_read(ring: &mut IoUring, fd: types::Fd, index: usize, slot: &mut Slot) {
+    let offset = slot.block * BLOCK_SIZE as u64 + slot.filled as u64;
+    let buf = &mut slot.buf[slot.filled .. direct::align_up(slot.wanted)];
+    let entry = opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+        .offset(offset)
+        .build()
+        .user_data(index as u64);
+    // SAFETY: the buffer stays alive and untouched until the read completes, as slots with reads
+    // in flight are never moved out of the slot list or dropped before being waited on.
+    unsafe {
+        ring.submission().push(&entry).expect("submission queue is full");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel::Options;
+    use crate::CancelToken;
+    use std::io::Write;
+    use tempfile::tempfile;
+
+    #[test]
+    fn submit_fails() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&vec![30u8; 3 * BLOCK_SIZE]).unwrap();
+        let options = Options::new(2);
+        let tracker = Tracker::new(&options);
+        let err = block_hashes_with(&file, 3 * BLOCK_SIZE as u64, 2, 2, &tracker, |_| {
+            Err(io::Error::from_raw_os_error(libc::EBADF))
+        }).unwrap_err();
+        assert!(matches!(&err, Error::Read(e) if e.raw_os_error() == Some(libc::EBADF)),
+            "{:?}", err);
+    }
+
+    #[test]
+    fn cancelled_midway() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&vec![30u8; 5 * BLOCK_SIZE]).unwrap();
+        let token = CancelToken::new();
+        let options = Options::new(2).cancel_token(token.clone());
+        let tracker = Tracker::new(&options);
+        // The first reads are already queued up when it's cancelled.
+        let err = block_hashes_with(&file, 5 * BLOCK_SIZE as u64, 2, 2, &tracker, |ring| {
+            token.cancel();
+            ring.submit_and_wait(1)
+        }).unwrap_err();
+        assert!(matches!(err, Error::Cancelled), "{:?}", err);
+    }
+}
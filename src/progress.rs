@@ -0,0 +1,135 @@
+//! Reporting how much of a stream has been read, for showing progress while it's hashed.
+
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+/// How often [`ProgressRead`] reports progress, unless told otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wraps a reader, calling a function with the number of bytes read so far, and the total length
+/// if it's known, as it's read from.
+///
+/// The function is called at most once per interval ([`DEFAULT_INTERVAL`] unless changed with
+/// [`interval`](Self::interval)), and always when the end of the stream is reached, so the last
+/// call has the final count.
+///
+/// ```
+/// use dropbox_content_hash::ContentHasher;
+/// use dropbox_content_hash::progress::ProgressRead;
+///
+/// let data = vec![0u8; 10_000];
+/// let mut done = 0;
+/// let reader = ProgressRead::new(&data[..], Some(data.len() as u64), |bytes, _total| {
+///     done = bytes;
+/// });
+/// ContentHasher::from_stream(reader).unwrap();
+/// assert_eq!(10_000, done);
+/// ```
+pub struct ProgressRead<R, F> {
+    inner: R,
+    callback: F,
+    position: u64,
+    total: Option<u64>,
+    interval: Duration,
+    last: Instant,
+}
+
+impl<R, F: FnMut(u64, Option<u64>)> ProgressRead<R, F> {
+    /// Wrap a reader whose length, if known, is `total`.
+    pub fn new(inner: R, total: Option<u64>, callback: F) -> Self {
+        Self {
+            inner,
+            callback,
+            position: 0,
+            total,
+            interval: DEFAULT_INTERVAL,
+            last: Instant::now(),
+        }
+    }
+
+    /// Set the least time between calls. With zero, the function is called after every read.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The number of bytes read or skipped so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Record that some bytes were skipped over without being read, such as by seeking.
+    pub fn skip(&mut self, len: u64) {
+        self.position += len;
+        self.report(false);
+    }
+
+    /// A reference to the wrapped reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwrap the reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn report(&mut self, force: bool) {
+        let done = self.total.is_some_and(|total| self.position >= total);
+        if force || done || self.last.elapsed() >= self.interval {
+            self.last = Instant::now();
+            (self.callback)(self.position, self.total);
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(u64, Option<u64>)> Read for ProgressRead<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        self.report(n == 0 && !buf.is_empty());
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let data = [7u8; 1000];
+        let mut calls = vec![];
+        let mut reader = ProgressRead::new(&data[..], None, |done, total| calls.push((done, total)))
+            .interval(Duration::ZERO);
+        reader.skip(100);
+        io::copy(&mut reader.by_ref().take(300), &mut io::sink()).unwrap();
+        assert_eq!(400, reader.position());
+        assert_eq!((100, None), calls[0]);
+        assert_eq!((400, None), *calls.last().unwrap());
+
+        // Throttled, only the end is reported.
+        let mut calls = vec![];
+        let reader = ProgressRead::new(&data[..], None, |done, total| calls.push((done, total)))
+            .interval(Duration::from_secs(3600));
+        io::copy(&mut OneByteReads(reader), &mut io::sink()).unwrap();
+        assert_eq!(vec![(1000, None)], calls);
+
+        // ...or when the total is reached.
+        let mut calls = vec![];
+        let mut reader = ProgressRead::new(&data[..], Some(1000), |done, _| calls.push(done))
+            .interval(Duration::from_secs(3600));
+        reader.read_exact(&mut [0; 1000]).unwrap();
+        assert_eq!(vec![1000], calls);
+    }
+
+    /// Reads a byte at a time.
+    struct OneByteReads<R>(R);
+
+    impl<R: Read> Read for OneByteReads<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[.. len])
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Helpers for reading streams, which the command-line tool uses too.
+
+use std::io::{self, Read};
+
+/// Read until the buffer is full or the stream ends, returning the number of bytes read.
+pub fn read_full(source: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled ..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Counts the bytes read through it.
+pub struct CountingReader<R> {
+    pub inner: R,
+    pub count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads at most three bytes at a time, and is interrupted before each.
+    struct Trickle<'a>(&'a [u8], bool);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let n = buf.len().min(3);
+            self.0.read(&mut buf[.. n])
+        }
+    }
+
+    #[test]
+    fn read_full_and_count() {
+        let mut reader = CountingReader::new(Trickle(b"0123456789", false));
+        let mut buf = [0; 8];
+        assert_eq!(8, read_full(&mut reader, &mut buf).unwrap());
+        assert_eq!(b"01234567", &buf);
+        assert_eq!(2, read_full(&mut reader, &mut buf).unwrap());
+        assert_eq!(b"89", &buf[.. 2]);
+        assert_eq!(0, read_full(&mut reader, &mut buf).unwrap());
+        assert_eq!(10, reader.count);
+    }
+}
@@ -0,0 +1,52 @@
+//! Implementations of the RustCrypto `digest` traits for [`ContentHasher`], so it can be used with
+//! code written for any [`Digest`](digest::Digest), such as:
+//!
+//! ```
+//! use digest::Digest;
+//! use dropbox_content_hash::ContentHasher;
+//!
+//! fn verify<D: Digest>(data: &[u8], expected: &[u8]) -> bool {
+//!     D::digest(data).as_slice() == expected
+//! }
+//!
+//! assert!(verify::<ContentHasher>(b"", &dropbox_content_hash::EMPTY_CONTENT_HASH));
+//! ```
+
+use crate::ContentHasher;
+use digest::consts::U32;
+use digest::{FixedOutput, HashMarker, Output, OutputSizeUser, Update};
+
+impl HashMarker for ContentHasher {}
+
+impl OutputSizeUser for ContentHasher {
+    type OutputSize = U32;
+}
+
+impl Update for ContentHasher {
+    fn update(&mut self, data: &[u8]) {
+        ContentHasher::update(self, data);
+    }
+}
+
+impl FixedOutput for ContentHasher {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        out.copy_from_slice(&self.finish());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ContentHasher, BLOCK_SIZE};
+    use digest::Digest;
+
+    #[test]
+    fn digest() {
+        let data = vec![30u8; BLOCK_SIZE + 1];
+        let mut expected = ContentHasher::new();
+        expected.update(&data);
+        let mut ctx = <ContentHasher as Digest>::new();
+        Digest::update(&mut ctx, &data[.. 100]);
+        Digest::update(&mut ctx, &data[100 ..]);
+        assert_eq!(expected.finish(), ctx.finalize().as_slice());
+    }
+}
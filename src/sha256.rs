@@ -0,0 +1,49 @@
+//! SHA-256, from `ring` where it's available, or from the RustCrypto `sha2` crate on WASI, which
+//! `ring` doesn't support. Both are used through the same interface as `ring::digest`.
+
+#[cfg(not(target_os = "wasi"))]
+pub(crate) use ring::digest::{digest, Context, Digest, SHA256};
+
+#[cfg(target_os = "wasi")]
+pub(crate) use self::sha2_backend::{digest, Context, Digest, SHA256};
+
+#[cfg(target_os = "wasi")]
+mod sha2_backend {
+    use crate::HASH_OUTPUT_SIZE;
+    use sha2::{Digest as _, Sha256};
+
+    /// Stands in for `ring::digest::Algorithm`; SHA-256 is the only one there is.
+    pub struct Algorithm(());
+
+    pub static SHA256: Algorithm = Algorithm(());
+
+    #[derive(Clone)]
+    pub struct Context(Sha256);
+
+    impl Context {
+        pub fn new(_algorithm: &'static Algorithm) -> Self {
+            Context(Sha256::new())
+        }
+
+        pub fn update(&mut self, data: &[u8]) {
+            self.0.update(data);
+        }
+
+        pub fn finish(self) -> Digest {
+            Digest(self.0.finalize().into())
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Digest([u8; HASH_OUTPUT_SIZE]);
+
+    impl AsRef<[u8]> for Digest {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    pub fn digest(_algorithm: &'static Algorithm, data: &[u8]) -> Digest {
+        Digest(Sha256::digest(data).into())
+    }
+}
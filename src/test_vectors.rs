@@ -0,0 +1,147 @@
+//! Known inputs and their content hashes, for checking other implementations of the content hash
+//! (or bindings to this one) against the same data.
+//!
+//! The inputs are described rather than stored, so they don't take up megabytes of space; use
+//! [`Input::data`] to get the bytes. The content hashes were computed independently of this crate,
+//! from the description of the content hash in the Dropbox documentation.
+//!
+//! [`DROPBOX_EXAMPLE`] is the example Dropbox publishes, which has to be downloaded to be hashed.
+
+use crate::BLOCK_SIZE;
+
+/// An input to hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// These exact bytes.
+    Bytes(&'static [u8]),
+    /// The given byte, repeated to the given length.
+    Repeat {
+        /// The byte.
+        byte: u8,
+        /// How many of it.
+        len: usize,
+    },
+    /// The bytes 0, 1, 2, ... 255, 0, 1, ..., up to the given length.
+    Counting {
+        /// The length.
+        len: usize,
+    },
+}
+
+impl Input {
+    /// The length of the input.
+    pub fn len(&self) -> usize {
+        match *self {
+            Input::Bytes(bytes) => bytes.len(),
+            Input::Repeat { len, .. } | Input::Counting { len } => len,
+        }
+    }
+
+    /// Whether the input is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes of the input.
+    pub fn data(&self) -> Vec<u8> {
+        match *self {
+            Input::Bytes(bytes) => bytes.to_vec(),
+            Input::Repeat { byte, len } => vec![byte; len],
+            Input::Counting { len } => (0 .. len).map(|i| i as u8).collect(),
+        }
+    }
+}
+
+/// An input and its content hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestVector {
+    /// A short description of the input.
+    pub name: &'static str,
+    /// The input.
+    pub input: Input,
+    /// The content hash of the input, in lowercase hexadecimal.
+    pub content_hash: &'static str,
+}
+
+/// A published file and its content hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExternalVector {
+    /// The name of the file.
+    pub name: &'static str,
+    /// Where to download it from.
+    pub url: &'static str,
+    /// The content hash of the file, in lowercase hexadecimal.
+    pub content_hash: &'static str,
+}
+
+/// The example file from the Dropbox content hash documentation.
+pub const DROPBOX_EXAMPLE: ExternalVector = ExternalVector {
+    name: "milky-way-nasa.jpg",
+    url: "https://www.dropbox.com/static/images/developers/milky-way-nasa.jpg",
+    content_hash: "485291fa0ee50c016982abbfa943957bcd231aae0492ccbaa22c58e3997b35e0",
+};
+
+/// Inputs around the edges of blocks, and their content hashes.
+pub const VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "empty",
+        input: Input::Bytes(b""),
+        content_hash: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+    },
+    TestVector {
+        name: "less than one block",
+        input: Input::Bytes(b"hello"),
+        content_hash: "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50",
+    },
+    TestVector {
+        name: "one byte less than one block",
+        input: Input::Repeat { byte: 30, len: BLOCK_SIZE - 1 },
+        content_hash: "c8bd7c264a7f1d63a7b40fcfb0757f53067c1ba23760b9e9194715570e8211e3",
+    },
+    TestVector {
+        name: "exactly one block",
+        input: Input::Repeat { byte: 30, len: BLOCK_SIZE },
+        content_hash: "1114501b241325c24970e0cd0b6416d80284085151e2980747ccecc4e0c156e6",
+    },
+    TestVector {
+        name: "one byte more than one block",
+        input: Input::Repeat { byte: 30, len: BLOCK_SIZE + 1 },
+        content_hash: "5b1d15f99119b9138a887c27d1b246cf6c584621fc75c42edd27c3d962835d4f",
+    },
+    TestVector {
+        name: "exactly two blocks",
+        input: Input::Repeat { byte: 30, len: 2 * BLOCK_SIZE },
+        content_hash: "aa562efb265c604214e4626717330e15be16f2daaabfe5d7d2c22f3e88cbc268",
+    },
+    TestVector {
+        name: "three blocks of zeros",
+        input: Input::Repeat { byte: 0, len: 3 * BLOCK_SIZE },
+        content_hash: "822a8043666c5be998c321b6748845f9686322b64dbbb9df9268dc3f235d15b7",
+    },
+    TestVector {
+        name: "two blocks and a little bit more, all different",
+        input: Input::Counting { len: 2 * BLOCK_SIZE + 5 },
+        content_hash: "018567bdaf529cd20daf871aa5aa657daa1b6009781b4ddfac3142de17e8f3e3",
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hex_string, ContentHasher};
+
+    #[test]
+    fn vectors() {
+        for vector in VECTORS {
+            let data = vector.input.data();
+            assert_eq!(vector.input.len(), data.len());
+            let mut ctx = ContentHasher::new();
+            // Odd-sized updates, so they don't line up with the blocks.
+            for chunk in data.chunks(1_000_003) {
+                ctx.update(chunk);
+            }
+            assert_eq!(vector.content_hash, ctx.finish_str(), "{}", vector.name);
+        }
+        assert_eq!(VECTORS[0].content_hash, hex_string(&crate::EMPTY_CONTENT_HASH));
+    }
+}
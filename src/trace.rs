@@ -0,0 +1,104 @@
+//! Instrumentation with `tracing`, which compiles to nothing without the "tracing" feature.
+//!
+//! The macros here are used the same way as `tracing`'s, so the places they're used don't need
+//! `#[cfg]`s of their own. The command-line tool uses this module too, for its own logging;
+//! without the feature, it still prints warnings, unless told not to with `set_warnings`.
+
+#[cfg(feature = "tracing")]
+pub use tracing::{debug, debug_span, info, info_span, trace, warn, Span};
+
+#[cfg(not(feature = "tracing"))]
+pub use self::disabled::{debug, debug_span, info, info_span, set_warnings, trace, warn, warnings,
+    Span};
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __trace_debug {
+        ($($arg:tt)*) => {{}};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __trace_info {
+        ($($arg:tt)*) => {{}};
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __trace_trace {
+        ($($arg:tt)*) => {{}};
+    }
+
+    /// Prints the message to standard error, followed by any `name = %value` fields.
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __trace_warn {
+        ($($name:ident = %$value:expr),+, $($arg:tt)+) => {
+            if $crate::trace::warnings() {
+                eprintln!("{}{}", format_args!($($arg)+),
+                    [$(format!(" {}={}", stringify!($name), $value)),+].concat());
+            }
+        };
+        ($($arg:tt)+) => {
+            if $crate::trace::warnings() {
+                eprintln!($($arg)+);
+            }
+        };
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __trace_debug_span {
+        ($($arg:tt)*) => {
+            $crate::trace::Span
+        };
+    }
+
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! __trace_info_span {
+        ($($arg:tt)*) => {
+            $crate::trace::Span
+        };
+    }
+
+    // Exported from the crate root, as `macro_export` macros are, and re-exported from here under
+    // `tracing`'s names.
+    pub use crate::{__trace_debug as debug, __trace_debug_span as debug_span,
+        __trace_info as info, __trace_info_span as info_span, __trace_trace as trace,
+        __trace_warn as warn};
+
+    static WARNINGS: AtomicBool = AtomicBool::new(true);
+
+    /// Whether [`warn!`] prints anything.
+    pub fn warnings() -> bool {
+        WARNINGS.load(Ordering::Relaxed)
+    }
+
+    /// Turn [`warn!`] on or off.
+    pub fn set_warnings(enabled: bool) {
+        WARNINGS.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Stands in for `tracing::Span`.
+    #[derive(Debug, Clone)]
+    pub struct Span;
+
+    impl Span {
+        pub fn current() -> Self {
+            Span
+        }
+
+        pub fn entered(self) -> Self {
+            self
+        }
+
+        pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+            f()
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Store content hashes in files' extended attributes, so they don't need to be computed again
+//! until the file changes.
+//!
+//! The hash is stored along with the file's size and modification time when it was computed; if
+//! either is different now, the stored hash is out of date. If they are the same but the contents
+//! no longer match the stored hash, the file has been corrupted, which [`verify`] reports.
+
+use crate::file::{self, content_hash_file};
+use crate::parallel::Error;
+use crate::{hex_string, parse_hex, HASH_OUTPUT_SIZE};
+use std::fs::Metadata;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the extended attribute the hash is stored in.
+pub const ATTR_NAME: &str = "user.dropbox.content_hash";
+
+/// A content hash stored on a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredHash {
+    /// The content hash.
+    pub hash: [u8; HASH_OUTPUT_SIZE],
+    /// The file's size when the hash was computed.
+    pub size: u64,
+    /// The file's modification time when the hash was computed.
+    pub mtime: SystemTime,
+}
+
+impl StoredHash {
+    /// Whether the file, with the given metadata, has the same size and modification time as when
+    /// the hash was computed.
+    pub fn is_current(&self, meta: &Metadata) -> bool {
+        meta.len() == self.size && meta.modified().ok() == Some(self.mtime)
+    }
+
+    fn to_attr(self) -> String {
+        let mtime = self.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!("{} {} {}.{:09}",
+            hex_string(&self.hash), self.size, mtime.as_secs(), mtime.subsec_nanos())
+    }
+
+    fn from_attr(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?;
+        let mut fields = value.split(' ');
+        let hex = fields.next()?;
+        let size = fields.next()?.parse().ok()?;
+        let (secs, nanos) = fields.next()?.split_once('.')?;
+        if fields.next().is_some() || nanos.len() != 9 {
+            return None;
+        }
+        let hash = parse_hex(hex).ok()?;
+        let mtime = UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+        Some(Self { hash, size, mtime })
+    }
+}
+
+/// Read the hash stored on the file, whether it is current or not.
+///
+/// Returns an error of kind `InvalidData` if the attribute is there but can't be parsed.
+pub fn read(path: impl AsRef<Path>) -> io::Result<Option<StoredHash>> {
+    match ::xattr::get(path, ATTR_NAME)? {
+        None => Ok(None),
+        Some(value) => StoredHash::from_attr(&value)
+            .map(Some)
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed {} attribute", ATTR_NAME))),
+    }
+}
+
+/// Store a hash on the file, along with its size and modification time from the given metadata.
+///
+/// The metadata should be read before computing the hash, so that if the file is modified while
+/// it's being hashed, the stored hash is recognized as out of date.
+pub fn write(
+    path: impl AsRef<Path>,
+    hash: &[u8; HASH_OUTPUT_SIZE],
+    meta: &Metadata,
+) -> io::Result<()> {
+    let stored = StoredHash {
+        hash: *hash,
+        size: meta.len(),
+        mtime: meta.modified()?,
+    };
+    ::xattr::set(path, ATTR_NAME, stored.to_attr().as_bytes())
+}
+
+/// Remove any hash stored on the file.
+pub fn remove(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    if ::xattr::get(path, ATTR_NAME)?.is_some() {
+        ::xattr::remove(path, ATTR_NAME)?;
+    }
+    Ok(())
+}
+
+/// Get the file's content hash from its extended attributes if it's current, or else compute it
+/// and store it there.
+pub fn content_hash_cached(
+    path: impl AsRef<Path>,
+    options: &file::Options,
+) -> Result<[u8; HASH_OUTPUT_SIZE], Error> {
+    let path = path.as_ref();
+    let meta = path.metadata()?;
+    if let Ok(Some(stored)) = read(path) {
+        if stored.is_current(&meta) {
+            return Ok(stored.hash);
+        }
+    }
+    let (hash, _stats) = content_hash_file(path, options)?;
+    write(path, &hash, &meta)?;
+    Ok(hash)
+}
+
+/// The result of [`verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// There was no hash stored, or it couldn't be parsed. The new hash has been stored.
+    New,
+    /// The file has been modified since the stored hash was computed. The new hash has been
+    /// stored in its place.
+    Updated,
+    /// The file hasn't been modified, and still matches the stored hash.
+    Ok,
+    /// The file hasn't been modified, judging by its size and modification time, but doesn't
+    /// match the stored hash any more, so it has probably been corrupted. The stored hash is left
+    /// alone.
+    Corrupt {
+        /// The hash that was stored.
+        stored: [u8; HASH_OUTPUT_SIZE],
+    },
+}
+
+/// Compute the file's content hash, and compare it with the one stored in its extended
+/// attributes, storing the new hash unless the file appears to be corrupt.
+pub fn verify(
+    path: impl AsRef<Path>,
+    options: &file::Options,
+) -> Result<([u8; HASH_OUTPUT_SIZE], Verification), Error> {
+    let path = path.as_ref();
+    let meta = path.metadata()?;
+    let stored = read(path).ok().flatten();
+    let (hash, _stats) = content_hash_file(path, options)?;
+    let verification = match stored {
+        None => Verification::New,
+        Some(stored) if !stored.is_current(&meta) => Verification::Updated,
+        Some(stored) if stored.hash == hash => Verification::Ok,
+        Some(stored) => return Ok((hash, Verification::Corrupt { stored: stored.hash })),
+    };
+    if verification != Verification::Ok {
+        write(path, &hash, &meta)?;
+    }
+    Ok((hash, verification))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ContentHasher;
+    use tempfile::TempDir;
+
+    #[test]
+    fn attr_format() {
+        let stored = StoredHash {
+            hash: [0xab; HASH_OUTPUT_SIZE],
+            size: 1234,
+            mtime: UNIX_EPOCH + Duration::new(1_600_000_000, 5),
+        };
+        let attr = stored.to_attr();
+        assert_eq!(format!("{} 1234 1600000000.000000005", "ab".repeat(32)), attr);
+        assert_eq!(Some(stored), StoredHash::from_attr(attr.as_bytes()));
+        assert_eq!(None, StoredHash::from_attr(b"abcd 1234 1600000000.000000005"));
+        assert_eq!(None, StoredHash::from_attr(format!("{} extra", attr).as_bytes()));
+    }
+
+    #[test]
+    fn store_and_verify() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"hello").unwrap();
+        if let Err(e) = ::xattr::set(&path, ATTR_NAME, b"") {
+            // The temp dir may be on a filesystem without user xattrs.
+            eprintln!("skipping: {}", e);
+            return;
+        }
+        remove(&path).unwrap();
+        remove(&path).unwrap();
+        let expected = ContentHasher::from_stream(&b"hello"[..]).unwrap().finish();
+        let options = file::Options::new();
+
+        assert_eq!(None, read(&path).unwrap());
+        assert_eq!(expected, content_hash_cached(&path, &options).unwrap());
+        assert_eq!(expected, read(&path).unwrap().unwrap().hash);
+        assert_eq!((expected, Verification::Ok), verify(&path, &options).unwrap());
+
+        // Change the contents but not the size or mtime.
+        let mtime = path.metadata().unwrap().modified().unwrap();
+        std::fs::write(&path, b"jello").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        let (hash, verification) = verify(&path, &options).unwrap();
+        assert_eq!(Verification::Corrupt { stored: expected }, verification);
+        assert_eq!(expected, read(&path).unwrap().unwrap().hash);
+
+        // A real modification.
+        std::fs::write(&path, b"hello, world").unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(mtime + Duration::from_secs(1)).unwrap();
+        let (new_hash, verification) = verify(&path, &options).unwrap();
+        assert_ne!(hash, new_hash);
+        assert_eq!(Verification::Updated, verification);
+        assert_eq!(new_hash, content_hash_cached(&path, &options).unwrap());
+    }
+}